@@ -0,0 +1,102 @@
+use super::error::Error;
+use super::hal::Backend;
+
+use std::sync::Arc;
+
+/// Weight of a new sample in the filtered voltage, filters out short spikes under load
+const FILTER: f64 = 0.1;
+
+/// Create a battery monitor for a 2S LiPo pack
+///
+/// Warns below 7.0V (3.5V per cell) and is critical below 6.6V (3.3V per cell)
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend with the ADC
+pub fn new(hal: Arc<dyn Backend>) -> Battery {
+	Battery {
+		hal,
+		nominal: 7.4,
+		warning: 7.0,
+		critical: 6.6,
+		voltage: None,
+		jack: None,
+	}
+}
+
+/// Charge level of the battery
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+	#[default]
+	Ok,
+	Warning,
+	Critical,
+}
+
+/// Monitors the voltage of the battery pack and the DC jack
+pub struct Battery {
+	hal: Arc<dyn Backend>,
+	nominal: f64,
+	warning: f64,
+	critical: f64,
+	voltage: Option<f64>,
+	jack: Option<f64>,
+}
+
+impl Battery {
+	/// Set the thresholds for the battery level
+	///
+	/// # Arguments
+	///
+	/// * `nominal` - Voltage in V the motor duties are compensated to
+	/// * `warning` - Below this voltage in V the battery level is a warning
+	/// * `critical` - Below this voltage in V the battery level is critical
+	pub fn set_thresholds(&mut self, nominal: f64, warning: f64, critical: f64) {
+		self.nominal = nominal;
+		self.warning = warning;
+		self.critical = critical;
+	}
+
+	/// Start measuring the voltages
+	pub fn start(&mut self) -> Result<(), Error> {
+		self.hal.init_adc()
+	}
+
+	/// Measure the voltages and return the new level
+	pub fn update(&mut self) -> Result<Level, Error> {
+		let battery = self.hal.battery_voltage()?;
+		self.voltage = Some(match self.voltage {
+			Some(voltage) => voltage + FILTER * (battery - voltage),
+			None => battery,
+		});
+		self.jack = self.hal.jack_voltage().ok();
+		Ok(self.level())
+	}
+
+	/// The filtered voltage of the battery pack in V, None until the first update
+	pub fn voltage(&self) -> Option<f64> {
+		self.voltage
+	}
+
+	/// The voltage on the DC jack in V, None until the first update or if it can not be measured
+	pub fn jack_voltage(&self) -> Option<f64> {
+		self.jack
+	}
+
+	/// The level of the battery based on the last update
+	pub fn level(&self) -> Level {
+		match self.voltage {
+			Some(voltage) if voltage < self.critical => Level::Critical,
+			Some(voltage) if voltage < self.warning => Level::Warning,
+			_ => Level::Ok,
+		}
+	}
+
+	/// Factor to multiply a motor duty with, so the motor gets the same voltage as on a nominal battery
+	pub fn compensation(&self) -> f64 {
+		match self.voltage {
+			Some(voltage) if voltage > 0.0 => self.nominal / voltage,
+			_ => 1.0,
+		}
+	}
+}
@@ -0,0 +1,238 @@
+use super::error::Error;
+use super::hal::{Backend, Button};
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// A button held at least this long is a long press
+const LONG_PRESS: Duration = Duration::from_millis(1000);
+
+/// A second press within this time after releasing the button is a double press
+const DOUBLE_PRESS: Duration = Duration::from_millis(300);
+
+/// The backends call plain C functions without any context, so the edges are sent through this channel
+static EDGES: Mutex<Option<Sender<Edge>>> = Mutex::new(None);
+
+/// A button was pressed (true) or released (false) at the given time
+type Edge = (Button, bool, Instant);
+
+/// Send an edge of a button from the callback to the `Buttons` instance
+fn edge(button: Button, pressed: bool) {
+	let now = Instant::now();
+	if let Some(sender) = EDGES.lock().unwrap_or_else(|err| err.into_inner()).as_ref() {
+		let _ = sender.send((button, pressed, now));
+	}
+}
+
+extern "C" fn pause_pressed() { edge(Button::Pause, true); }
+extern "C" fn pause_released() { edge(Button::Pause, false); }
+extern "C" fn mode_pressed() { edge(Button::Mode, true); }
+extern "C" fn mode_released() { edge(Button::Mode, false); }
+
+/// Kind of a button press
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Press {
+	Short,
+	Long,
+	Double,
+}
+
+/// A button press, the time is when the (first) press started
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+	pub button: Button,
+	pub press: Press,
+	pub time: Instant,
+}
+
+/// Where a button is in detecting the kind of press
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+	/// Pressed since the time, with the start of the first press if it is the second one
+	Pressed(Instant, Option<Instant>),
+	/// Released after a short press which started at the first time, waiting for a second press
+	Released(Instant, Instant),
+	/// Long press was already sent, waiting for the release
+	Held,
+}
+
+/// Create the button event detection on the given backend
+///
+/// Only one instance receives the events, creating a new one disconnects the previous one
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the buttons are attached to
+pub fn new(hal: Arc<dyn Backend>) -> Buttons {
+	let (sender, receiver) = channel();
+	*EDGES.lock().unwrap_or_else(|err| err.into_inner()) = Some(sender);
+	Buttons {
+		hal,
+		edges: receiver,
+		states: HashMap::new(),
+		events: VecDeque::new(),
+	}
+}
+
+/// Turns presses and releases of the Pause and Mode button into short, long and double presses
+///
+/// `poll` has to be called regularly, it never blocks.
+pub struct Buttons {
+	hal: Arc<dyn Backend>,
+	edges: Receiver<Edge>,
+	states: HashMap<Button, State>,
+	events: VecDeque<Event>,
+}
+
+impl Buttons {
+	/// Initialize the buttons and register the callbacks
+	pub fn start(&mut self) -> Result<(), Error> {
+		self.hal.init_button(Button::Pause)?;
+		self.hal.init_button(Button::Mode)?;
+		self.hal.register_button_callbacks(Button::Pause, Some(pause_pressed), Some(pause_released))?;
+		self.hal.register_button_callbacks(Button::Mode, Some(mode_pressed), Some(mode_released))
+	}
+
+	/// Get the next button event, None if there is none at the moment
+	///
+	/// A short press is only delivered once no double press is possible anymore
+	pub fn poll(&mut self) -> Option<Event> {
+		while let Ok((button, pressed, time)) = self.edges.try_recv() {
+			self.expire(time);
+			self.update(button, pressed, time);
+		}
+		self.expire(Instant::now());
+		self.events.pop_front()
+	}
+
+	/// Apply an edge of a button
+	fn update(&mut self, button: Button, pressed: bool, time: Instant) {
+		let state = self.states.get(&button).copied();
+		let (state, press) = match (state, pressed) {
+			(None, true) => (Some(State::Pressed(time, None)), None),
+			(Some(State::Released(first, _)), true) => (Some(State::Pressed(time, Some(first))), None),
+			(Some(State::Pressed(since, None)), false) => (Some(State::Released(since, time)), None),
+			(Some(State::Pressed(_, Some(first))), false) => (None, Some((Press::Double, first))),
+			(Some(State::Held), false) => (None, None),
+			// Missed edges, e.g. a press while pressed, are ignored
+			(state, _) => (state, None),
+		};
+		self.set(button, state, press);
+	}
+
+	/// Detect the long presses and the short presses without a second one up to the given time
+	fn expire(&mut self, now: Instant) {
+		let states: Vec<(Button, State)> = self.states.iter().map(|(button, state)| (*button, *state)).collect();
+		for (button, state) in states {
+			match state {
+				State::Pressed(since, first) if now.duration_since(since) >= LONG_PRESS => {
+					if let Some(first) = first {
+						self.events.push_back(Event { button, press: Press::Short, time: first });
+					}
+					self.set(button, Some(State::Held), Some((Press::Long, since)));
+				},
+				State::Released(first, released) if now.duration_since(released) > DOUBLE_PRESS => {
+					self.set(button, None, Some((Press::Short, first)));
+				},
+				_ => {},
+			}
+		}
+	}
+
+	/// Set the new state of a button and queue an event
+	fn set(&mut self, button: Button, state: Option<State>, press: Option<(Press, Instant)>) {
+		match state {
+			Some(state) => self.states.insert(button, state),
+			None => self.states.remove(&button),
+		};
+		if let Some((press, time)) = press {
+			self.events.push_back(Event { button, press, time });
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::simulated::Simulated;
+
+	use std::thread;
+
+	/// Buttons which only get the edges given to `apply`, the global channel is left to `delivers_through_the_channel`
+	fn buttons() -> Buttons {
+		Buttons {
+			hal: Arc::new(Simulated::default()),
+			edges: channel().1,
+			states: HashMap::new(),
+			events: VecDeque::new(),
+		}
+	}
+
+	/// Apply an edge of the Pause button in the same order as `poll`
+	fn apply(buttons: &mut Buttons, pressed: bool, time: Instant) {
+		buttons.expire(time);
+		buttons.update(Button::Pause, pressed, time);
+	}
+
+	/// All events detected up to the time
+	fn events(buttons: &mut Buttons, now: Instant) -> Vec<(Press, Instant)> {
+		buttons.expire(now);
+		buttons.events.drain(..).map(|event| (event.press, event.time)).collect()
+	}
+
+	#[test]
+	fn detects_a_long_press_at_the_threshold() {
+		let mut buttons = buttons();
+		let start = Instant::now();
+		apply(&mut buttons, true, start);
+		assert_eq!(events(&mut buttons, start + LONG_PRESS - Duration::from_millis(1)), vec![]);
+		assert_eq!(events(&mut buttons, start + LONG_PRESS), vec![(Press::Long, start)]);
+
+		// The release after a long press is not another press
+		apply(&mut buttons, false, start + Duration::from_millis(1500));
+		assert_eq!(events(&mut buttons, start + Duration::from_secs(5)), vec![]);
+		assert!(buttons.states.is_empty());
+	}
+
+	#[test]
+	fn detects_a_double_press_within_the_window() {
+		let mut buttons = buttons();
+		let start = Instant::now();
+		apply(&mut buttons, true, start);
+		apply(&mut buttons, false, start + Duration::from_millis(100));
+		// The second press exactly at the end of the window still counts
+		apply(&mut buttons, true, start + Duration::from_millis(100) + DOUBLE_PRESS);
+		apply(&mut buttons, false, start + Duration::from_millis(500));
+		assert_eq!(events(&mut buttons, start + Duration::from_secs(5)), vec![(Press::Double, start)]);
+	}
+
+	#[test]
+	fn splits_presses_across_the_window_boundary() {
+		let mut buttons = buttons();
+		let start = Instant::now();
+		let second = start + Duration::from_millis(101) + DOUBLE_PRESS;
+		apply(&mut buttons, true, start);
+		apply(&mut buttons, false, start + Duration::from_millis(100));
+		assert_eq!(events(&mut buttons, start + Duration::from_millis(100) + DOUBLE_PRESS), vec![]);
+		apply(&mut buttons, true, second);
+		apply(&mut buttons, false, second + Duration::from_millis(100));
+		assert_eq!(events(&mut buttons, start + Duration::from_secs(5)), vec![(Press::Short, start), (Press::Short, second)]);
+	}
+
+	#[test]
+	fn delivers_through_the_channel() {
+		let hal = Arc::new(Simulated::default());
+		let mut buttons = new(hal.clone());
+		buttons.start().unwrap();
+		hal.press_button(Button::Mode);
+		hal.release_button(Button::Mode);
+		// The short press is only delivered once the window of a double press is over
+		assert_eq!(buttons.poll(), None);
+		thread::sleep(DOUBLE_PRESS + Duration::from_millis(50));
+		let event = buttons.poll().unwrap();
+		assert_eq!((event.button, event.press), (Button::Mode, Press::Short));
+		assert_eq!(buttons.poll(), None);
+	}
+}
@@ -0,0 +1,275 @@
+use super::config;
+use super::diff_drive::DifferentialDrive as DifferentialDrive;
+use super::error::Error;
+use super::units::Duty;
+
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Number of steps of the duty staircase
+const STEPS: u32 = 5;
+
+/// Interval between two samples of the wheel velocities
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Number of samples before and after a sample which are used to calculate its velocity and acceleration
+const SPAN: usize = 5;
+
+/// Samples slower than this number of mm/s are within the deadband of the motor and not used for the fit
+const MIN_VELOCITY: f64 = 5.0;
+
+/// The duty, the velocity in mm/s and the acceleration in mm/s² of a wheel
+pub type Sample = (f64, f64, f64);
+
+/// Feedforward gains of the velocity control: duty = ks * sign(v) + kv * v + ka * a
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct Feedforward {
+	/// Duty to overcome the static friction and the deadband of the motor driver
+	pub ks: f64,
+	/// Duty per mm/s of velocity
+	pub kv: f64,
+	/// Duty per mm/s² of acceleration
+	pub ka: f64,
+}
+
+impl Feedforward {
+	/// Fit the motor model to samples by least squares
+	///
+	/// # Arguments
+	///
+	/// * `samples` - Samples of the wheel over different velocities and accelerations
+	pub fn fit(samples: &[Sample]) -> Result<Self, Error> {
+		// Normal equations of duty = [sign(v), v, a] * [ks, kv, ka]
+		let mut matrix = [[0.0; 3]; 3];
+		let mut vector = [0.0; 3];
+		let mut count = 0;
+		for (duty, velocity, acceleration) in samples.iter().filter(|sample| sample.1.abs() >= MIN_VELOCITY) {
+			let row = [velocity.signum(), *velocity, *acceleration];
+			for i in 0..3 {
+				for j in 0..3 {
+					matrix[i][j] += row[i] * row[j];
+				}
+				vector[i] += row[i] * duty;
+			}
+			count += 1;
+		}
+		if count < 3 {
+			return Err(Error::Calibration(String::from("Not enough samples with a turning wheel")));
+		}
+
+		let [ks, kv, ka] = solve(matrix, vector)
+			.ok_or_else(|| Error::Calibration(String::from("The samples do not determine the motor model")))?;
+		Ok(Self { ks, kv, ka })
+	}
+}
+
+/// Solve the linear equations by Gaussian elimination, None if they are singular
+fn solve(mut matrix: [[f64; 3]; 3], mut vector: [f64; 3]) -> Option<[f64; 3]> {
+	for column in 0..3 {
+		let pivot = (column..3).max_by(|a, b| matrix[*a][column].abs().total_cmp(&matrix[*b][column].abs()))?;
+		if matrix[pivot][column].abs() <= f64::EPSILON {
+			return None;
+		}
+		matrix.swap(column, pivot);
+		vector.swap(column, pivot);
+
+		for row in column + 1..3 {
+			let factor = matrix[row][column] / matrix[column][column];
+			let pivot_row = matrix[column];
+			for (value, pivot) in matrix[row].iter_mut().zip(pivot_row).skip(column) {
+				*value -= factor * pivot;
+			}
+			vector[row] -= factor * vector[column];
+		}
+	}
+
+	let mut result = [0.0; 3];
+	for row in (0..3).rev() {
+		let sum: f64 = (row + 1..3).map(|i| matrix[row][i] * result[i]).sum();
+		result[row] = (vector[row] - sum) / matrix[row][row];
+	}
+	Some(result)
+}
+
+/// Fitted feedforward gains of the left and right wheel
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct Characterization {
+	pub left: Feedforward,
+	pub right: Feedforward,
+}
+
+impl Characterization {
+	/// Load a characterization from a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - File the characterization was saved to
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+		let config = config::load(path)?;
+		let wheel = |name: &str| Feedforward {
+			ks: config.get_or(&format!("motor.{}.ks", name), 0.0),
+			kv: config.get_or(&format!("motor.{}.kv", name), 0.0),
+			ka: config.get_or(&format!("motor.{}.ka", name), 0.0),
+		};
+		Ok(Self {
+			left: wheel("left"),
+			right: wheel("right"),
+		})
+	}
+
+	/// Save the characterization into a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - File to save the characterization to
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let mut config = config::Config::default();
+		for (feedforward, name) in [(self.left, "left"), (self.right, "right")] {
+			config.set(&format!("motor.{}.ks", name), feedforward.ks);
+			config.set(&format!("motor.{}.kv", name), feedforward.kv);
+			config.set(&format!("motor.{}.ka", name), feedforward.ka);
+		}
+		config.save(path)
+	}
+}
+
+/// Spin the robot in place through a duty staircase and a ramp and fit the motor model of each wheel
+///
+/// The duties are taken before the battery compensation and the wheels are measured by the encoders alone.
+///
+/// # Arguments
+///
+/// * `robot` - The robot to spin, it must not be running
+/// * `max_duty` - Duty of the last step of the staircase and the end of the ramp [0.0 - 1.0]
+/// * `step` - How long each step of the staircase is held, the ramp takes as long as the whole staircase
+pub fn characterize(robot: &mut DifferentialDrive, max_duty: f64, step: Duration) -> Result<Characterization, Error> {
+	let mut samples = (vec!(), vec!());
+	// The slip detection would correct the distances of the wheels with the IMU
+	let imu = robot.remove_imu();
+
+	let mut run = || {
+		for i in 1..=STEPS {
+			let duty = max_duty * i as f64 / STEPS as f64;
+			sample(robot, step, |_| duty, &mut samples)?;
+		}
+
+		// Start the ramp from a standing robot
+		robot.pause()?;
+		sleep(step);
+		let ramp = step * STEPS;
+		sample(robot, ramp, |elapsed| max_duty * elapsed.as_secs_f64() / ramp.as_secs_f64(), &mut samples)
+	};
+	let result = run();
+	let halted = robot.halt();
+	if let Some((imu, weight)) = imu {
+		robot.add_imu(imu, weight);
+	}
+	result.and(halted)?;
+
+	Ok(Characterization {
+		left: Feedforward::fit(&samples.0)?,
+		right: Feedforward::fit(&samples.1)?,
+	})
+}
+
+/// Spin the robot with a duty which depends on the elapsed time and sample the wheels
+fn sample<F: Fn(Duration) -> f64>(robot: &mut DifferentialDrive, length: Duration, duty: F, samples: &mut (Vec<Sample>, Vec<Sample>)) -> Result<(), Error> {
+	let start = Instant::now();
+	let mut recorded = vec!();
+	while start.elapsed() < length {
+		// Called on every sample, so the motors can ramp up with a slew-rate limit
+		let speed = duty(start.elapsed());
+		robot.set_speed(Duty::new(-speed), Duty::new(speed))?;
+		robot.update_odometry()?;
+		// The feedforward is applied before the battery compensation, so it is fitted to the duty before it
+		recorded.push((start.elapsed().as_secs_f64(), robot.commanded_duties(), robot.wheel_distances()));
+		sleep(SAMPLE_INTERVAL);
+	}
+
+	// Central differences of the distances over some samples, so the velocity and acceleration are not delayed
+	// like the filtered velocity of the wheels and the steps of the encoders are averaged out
+	for window in recorded.windows(2 * SPAN + 1) {
+		let (before, (time, duties, distances), after) = (window[0], window[SPAN], window[2 * SPAN]);
+		let (dt_before, dt_after) = (time - before.0, after.0 - time);
+		for (samples, duty, distance, before, after) in [
+			(&mut samples.0, duties.0.get(), distances.0.as_mm(), before.2.0.as_mm(), after.2.0.as_mm()),
+			(&mut samples.1, duties.1.get(), distances.1.as_mm(), before.2.1.as_mm(), after.2.1.as_mm()),
+		] {
+			let velocity = (after - before) / (dt_before + dt_after);
+			let acceleration = ((after - distance) / dt_after - (distance - before) / dt_before) / ((dt_before + dt_after) / 2.0);
+			samples.push((duty, velocity, acceleration));
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::{battery, diff_drive, hal, simulator};
+	use super::super::units::Length;
+	use super::super::wheel::Wheel;
+
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	#[test]
+	fn fits_the_motor_model() {
+		let model = Feedforward { ks: 0.05, kv: 0.0008, ka: 0.0001 };
+		let mut samples: Vec<Sample> = (-30..=30).map(|i| {
+			let (velocity, acceleration) = (i as f64 * 10.0, (i % 7) as f64 * 100.0);
+			(model.ks * velocity.signum() + model.kv * velocity + model.ka * acceleration, velocity, acceleration)
+		}).collect();
+		// Standing in the deadband, the duty does not turn the wheel
+		samples.push((0.04, 0.0, 0.0));
+		samples.push((-0.04, 2.0, 0.0));
+
+		let fitted = Feedforward::fit(&samples).unwrap();
+		assert!((fitted.ks - model.ks).abs() < 1e-9, "ks {}", fitted.ks);
+		assert!((fitted.kv - model.kv).abs() < 1e-12, "kv {}", fitted.kv);
+		assert!((fitted.ka - model.ka).abs() < 1e-12, "ka {}", fitted.ka);
+	}
+
+	#[test]
+	fn fits_on_a_sagging_battery() {
+		// The motors are given for a full pack and the duty is compensated to it, the pack has sagged by a fifth
+		let model = simulator::MotorModel { voltage: 8.4, ..simulator::MotorModel::default() };
+		let mut sim = simulator::new(Length::mm(150.0));
+		let hal = sim.backend();
+		hal.init_encoders().and_then(|_| hal.init_motors()).unwrap();
+		let mut robot = diff_drive::new(hal.clone(), Length::mm(150.0), Length::mm(163.0));
+		for wheel in [
+			Wheel::left(Length::mm(40.0), hal::Encoder::ENCODER3, hal::Motor::MOTOR3, 3441.0 / 104.0, 32.0),
+			Wheel::right(Length::mm(40.0), hal::Encoder::ENCODER2, hal::Motor::MOTOR2, 3441.0 / 104.0, 32.0),
+		] {
+			sim.add_wheel(&wheel, false, model);
+			robot.add_wheel(wheel, false);
+		}
+		sim.set_battery_voltage(6.7);
+		let mut pack = battery::new(hal.clone());
+		pack.set_thresholds(8.4, 7.0, 6.6);
+		pack.update().unwrap();
+		robot.add_battery(pack, true);
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let sim = sim.spawn(stop.clone());
+		let result = characterize(&mut robot, 0.5, Duration::from_millis(200));
+		stop.store(true, Ordering::Relaxed);
+		sim.join().unwrap();
+
+		// The velocity constant of the motor on a wheel of 40mm, the measured duties would make it 25% larger
+		let kv = (model.stall_torque / model.no_load_speed + model.viscous_friction) / model.stall_torque / 40.0;
+		let result = result.unwrap();
+		for fitted in [result.left, result.right] {
+			assert!((fitted.kv / kv - 1.0).abs() < 0.08, "kv {} instead of {}", fitted.kv, kv);
+		}
+	}
+
+	#[test]
+	fn rejects_too_few_samples() {
+		assert!(Feedforward::fit(&[(0.1, 50.0, 0.0), (0.2, 100.0, 0.0), (0.0, 1.0, 0.0)]).is_err());
+		// Without any acceleration the acceleration constant is undetermined
+		assert!(Feedforward::fit(&[(0.1, 50.0, 0.0), (0.2, 100.0, 0.0), (-0.2, -100.0, 0.0), (0.3, 150.0, 0.0)]).is_err());
+	}
+}
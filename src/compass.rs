@@ -0,0 +1,238 @@
+use super::config;
+use super::diff_drive::DifferentialDrive as DifferentialDrive;
+use super::error::Error;
+use super::hal::Backend;
+use super::units::Duty;
+
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Create a new compass based on the magnetometer of the IMU
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the MPU is attached to
+pub fn new(hal: Arc<dyn Backend>) -> Compass {
+	Compass {
+		hal,
+		calibration: Calibration::default(),
+		offset: 0.0,
+	}
+}
+
+/// Hard- and soft-iron correction for the magnetometer
+///
+/// The hard-iron offset moves the center of the measured ellipsoid into the origin,
+/// the soft-iron scale stretches its axes to a sphere.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Calibration {
+	pub offset: [f64; 3],
+	pub scale: [f64; 3],
+}
+impl Default for Calibration {
+	fn default() -> Self {
+		Self {
+			offset: [0.0; 3],
+			scale: [1.0; 3],
+		}
+	}
+}
+
+impl Calibration {
+	/// Fit the correction to magnetometer samples taken while the robot turned at least once around
+	///
+	/// # Arguments
+	///
+	/// * `samples` - Magnetometer samples in µT
+	pub fn fit(samples: &[[f64; 3]]) -> Result<Self, Error> {
+		if samples.len() < 2 {
+			return Err(Error::Calibration(String::from("Not enough magnetometer samples")));
+		}
+
+		let mut min = [f64::MAX; 3];
+		let mut max = [f64::MIN; 3];
+		for sample in samples {
+			for axis in 0..3 {
+				min[axis] = min[axis].min(sample[axis]);
+				max[axis] = max[axis].max(sample[axis]);
+			}
+		}
+
+		// The robot only turns around Z, so only X and Y are corrected for soft-iron
+		let radius = [(max[0] - min[0]) / 2.0, (max[1] - min[1]) / 2.0];
+		if radius.iter().any(|r| *r <= f64::EPSILON) {
+			return Err(Error::Calibration(String::from("The magnetometer did not see a full turn")));
+		}
+		let average = (radius[0] + radius[1]) / 2.0;
+
+		Ok(Self {
+			offset: [(max[0] + min[0]) / 2.0, (max[1] + min[1]) / 2.0, (max[2] + min[2]) / 2.0],
+			scale: [average / radius[0], average / radius[1], 1.0],
+		})
+	}
+
+	/// Apply the correction to a magnetometer sample
+	pub fn apply(&self, sample: [f64; 3]) -> [f64; 3] {
+		[
+			(sample[0] - self.offset[0]) * self.scale[0],
+			(sample[1] - self.offset[1]) * self.scale[1],
+			(sample[2] - self.offset[2]) * self.scale[2],
+		]
+	}
+
+	/// Load a calibration from a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - File the calibration was saved to
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+		let config = config::load(path)?;
+		let default = Self::default();
+		let axis = |name: &str, default: [f64; 3]| [
+			config.get_or(&format!("{}.x", name), default[0]),
+			config.get_or(&format!("{}.y", name), default[1]),
+			config.get_or(&format!("{}.z", name), default[2]),
+		];
+		Ok(Self {
+			offset: axis("compass.offset", default.offset),
+			scale: axis("compass.scale", default.scale),
+		})
+	}
+
+	/// Save the calibration into a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - File to save the calibration to
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let mut config = config::Config::default();
+		for (axis, name) in ["x", "y", "z"].iter().enumerate() {
+			config.set(&format!("compass.offset.{}", name), self.offset[axis]);
+			config.set(&format!("compass.scale.{}", name), self.scale[axis]);
+		}
+		config.save(path)
+	}
+}
+
+/// Spin the robot in place and fit the magnetometer calibration
+///
+/// # Arguments
+///
+/// * `robot` - The robot to spin, it must not be running
+/// * `compass` - The compass to calibrate, the IMU must be started
+/// * `speed` - Speed of the wheels while spinning
+/// * `duration` - How long to spin, the robot should turn at least twice
+pub fn calibrate(robot: &mut DifferentialDrive, compass: &mut Compass, speed: Duty, duration: Duration) -> Result<Calibration, Error> {
+	let mut samples = vec!();
+	let start = Instant::now();
+
+	while start.elapsed() < duration {
+		// Called on every sample, so the motors can ramp up with a slew-rate limit
+		match robot.set_speed(-speed, speed).and_then(|_| compass.hal.read_imu()) {
+			Ok(data) => samples.push(data.mag),
+			Err(err) => {
+				let _ = robot.halt();
+				return Err(err);
+			},
+		}
+		sleep(Duration::from_millis(10));
+	}
+	robot.halt()?;
+
+	let calibration = Calibration::fit(&samples)?;
+	compass.set_calibration(calibration);
+	Ok(calibration)
+}
+
+/// Absolute heading based on the magnetometer
+pub struct Compass {
+	hal: Arc<dyn Backend>,
+	calibration: Calibration,
+	offset: f64,
+}
+
+impl Compass {
+	/// Set the hard- and soft-iron correction
+	pub fn set_calibration(&mut self, calibration: Calibration) {
+		self.calibration = calibration;
+	}
+
+	/// Align the compass to the world, so the current magnetic heading becomes the given orientation
+	///
+	/// # Arguments
+	///
+	/// * `phi` - The robots current alignment in rad
+	pub fn align(&mut self, phi: f64) -> Result<(), Error> {
+		self.offset = 0.0;
+		self.offset = phi - self.heading()?;
+		Ok(())
+	}
+
+	/// Get the heading in rad from -PI up to PI in the aligned world, counterclockwise is positive
+	pub fn heading(&self) -> Result<f64, Error> {
+		let mag = self.calibration.apply(self.hal.read_imu()?.mag);
+		let heading = (-mag[1]).atan2(mag[0]) + self.offset;
+		Ok((heading + PI).rem_euclid(2.0 * PI) - PI)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::ImuData;
+	use super::super::hal::simulated::Simulated;
+
+	use std::f64::consts::FRAC_PI_4;
+
+	/// Horizontal and vertical earth field in µT
+	const FIELD: (f64, f64) = (21.0, -43.0);
+
+	/// Hard-iron offset and soft-iron scale of the simulated magnetometer
+	const OFFSET: [f64; 3] = [12.0, -7.0, 30.0];
+	const SCALE: [f64; 3] = [1.3, 0.8, 1.0];
+
+	/// What the distorted magnetometer measures at the heading in rad
+	fn measure(phi: f64) -> [f64; 3] {
+		let field = [FIELD.0 * phi.cos(), -FIELD.0 * phi.sin(), FIELD.1];
+		[0, 1, 2].map(|axis| OFFSET[axis] + SCALE[axis] * field[axis])
+	}
+
+	#[test]
+	fn recovers_the_hard_and_soft_iron_calibration() {
+		let samples: Vec<[f64; 3]> = (0..720).map(|deg| measure((deg as f64).to_radians())).collect();
+		let calibration = Calibration::fit(&samples).unwrap();
+		for (offset, expected) in calibration.offset.iter().zip(OFFSET).take(2) {
+			assert!((offset - expected).abs() < 1e-9, "offset {:?}", calibration.offset);
+		}
+		// Turning only around Z the vertical field can not be told apart from the offset
+		assert!((calibration.offset[2] - OFFSET[2] - FIELD.1).abs() < 1e-9, "offset {:?}", calibration.offset);
+		// Both axes are stretched to the average of their radii
+		let average = (SCALE[0] + SCALE[1]) / 2.0;
+		for (scale, distortion) in calibration.scale.iter().zip(SCALE).take(2) {
+			assert!((scale - average / distortion).abs() < 1e-9, "scale {:?}", calibration.scale);
+		}
+		assert_eq!(calibration.scale[2], 1.0);
+		for sample in &samples {
+			let corrected = calibration.apply(*sample);
+			assert!((corrected[0].hypot(corrected[1]) - FIELD.0 * average).abs() < 1e-9);
+		}
+
+		let hal = Arc::new(Simulated::default());
+		let mut compass = new(hal.clone());
+		compass.set_calibration(calibration);
+		for deg in [-170.0, -135.0, -60.0, 0.0, 10.0, 45.0, 100.0, 179.0] {
+			let phi = f64::to_radians(deg);
+			hal.set_imu(ImuData { mag: measure(phi), ..Default::default() });
+			let heading = compass.heading().unwrap();
+			assert!((heading - phi).abs() < 1e-9, "heading {} at {}", heading, phi);
+		}
+
+		// Uncorrected the soft-iron bends the headings in between the axes
+		compass.set_calibration(Calibration { offset: OFFSET, ..Calibration::default() });
+		hal.set_imu(ImuData { mag: measure(FRAC_PI_4), ..Default::default() });
+		assert!((compass.heading().unwrap() - FRAC_PI_4).abs() > 0.1);
+	}
+}
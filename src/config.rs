@@ -0,0 +1,64 @@
+use super::error::Error;
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Load a configuration file
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+	match fs::read_to_string(&path) {
+		Ok(content) => Ok(Config::parse(&content)),
+		Err(err) => Err(Error::Io(format!("Unable to read {}: {}", path.as_ref().display(), err))),
+	}
+}
+
+/// Configuration with one `key = value` pair per line, a `#` starts a comment
+#[derive(Default, Clone, Debug)]
+pub struct Config {
+	values: BTreeMap<String, String>,
+}
+
+impl Config {
+	/// Parse the content of a configuration file, lines which are not a `key = value` pair are ignored
+	pub fn parse(content: &str) -> Self {
+		let values = content.lines()
+			.map(|line| line.split('#').next().unwrap_or_default())
+			.filter_map(|line| line.split_once('='))
+			.map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+			.collect();
+		Self { values }
+	}
+
+	/// Get a value, None if it does not exist or can not be parsed
+	pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+		self.values.get(key).and_then(|value| value.parse().ok())
+	}
+
+	/// Get a value or the default if it does not exist or can not be parsed
+	pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> T {
+		self.get(key).unwrap_or(default)
+	}
+
+	/// Set a value, an existing one is replaced
+	pub fn set<T: Display>(&mut self, key: &str, value: T) {
+		self.values.insert(key.to_string(), value.to_string());
+	}
+
+	/// Save the configuration into a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the file, an existing file is overwritten
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let content: String = self.values.iter()
+			.map(|(key, value)| format!("{} = {}\n", key, value))
+			.collect();
+		fs::write(&path, content).map_err(|err| Error::Io(format!("Unable to write {}: {}", path.as_ref().display(), err)))
+	}
+}
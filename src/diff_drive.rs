@@ -1,97 +1,420 @@
-use super::distance::Ultrasonic as Ultrasonic;
+use super::battery::Battery as Battery;
+use super::battery::Level as Level;
+use super::characterization::Feedforward as Feedforward;
+use super::compass::Compass as Compass;
+use super::distance::Sensor as Sensor;
+use super::error::Error;
+use super::imu::Imu as Imu;
 use super::planner::Planner as Planner;
 use super::motor::Motor as Motor;
+use super::motor::FaultDetection as FaultDetection;
+use super::motor::VelocityControl as VelocityControl;
 use super::wheel::Wheel as Wheel;
 use super::wheel::Orientation as Orientation;
+use super::position;
+use super::position::Covariance as Covariance;
+use super::position::Integration as Integration;
+use super::position::OdometryError as OdometryError;
 use super::position::Position as Position;
+use super::profiler::Profiler as Profiler;
+use super::slip::{Slip, SlipDetector};
+use super::status;
+use super::status::Status as Status;
+use super::status::StatusLed as StatusLed;
+use super::units::{Angle, AngularVelocity, Duty, Length, Velocity};
+use super::watchdog::Watchdog as Watchdog;
 
-use std::time::Instant;
+use super::hal::Backend;
+use super::hal::StopMode;
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// An obstacle closer than this number of cm is shown on the status LEDs
+const OBSTACLE_DISTANCE: f64 = 10.0;
+
+/// Interval to measure the battery voltage
+const BATTERY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the distances of the wheels on one side are combined for the odometry
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SideOdometry {
+	/// The mean of all wheels of the side
+	#[default]
+	Average,
+	/// The median of all wheels of the side, a single slipping or faulty wheel of three or more is outvoted
+	Median,
+}
+
+impl SideOdometry {
+	/// Combine the distances of the wheels of a side, zero for a side without wheels
+	fn combine(self, mut distances: Vec<Length>) -> Length {
+		if distances.is_empty() {
+			return Length::ZERO;
+		}
+		match self {
+			SideOdometry::Average => distances.iter().copied().sum::<Length>() / distances.len() as f64,
+			SideOdometry::Median => {
+				distances.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+				let middle = distances.len() / 2;
+				match distances.len() % 2 {
+					0 => (distances[middle - 1] + distances[middle]) / 2.0,
+					_ => distances[middle],
+				}
+			},
+		}
+	}
+}
+
+/// How the difference of the sides turns the robot
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Kinematics {
+	/// The wheels roll without slipping, the robot turns around the middle between them
+	#[default]
+	Differential,
+	/// The wheels slide sideways while turning, e.g. four wheels on a rigid chassis, so the robot turns slower than
+	/// the wheels suggest. The robot turns as if its wheels were `slip` times further apart [>= 1.0]
+	SkidSteer { slip: f64 },
+}
+
+impl Kinematics {
+	/// The distance of the wheels of a differential drive which turns like this robot
+	fn effective_distance(self, wheel_distance: Length) -> Length {
+		match self {
+			Kinematics::Differential => wheel_distance,
+			Kinematics::SkidSteer { slip } => wheel_distance * slip,
+		}
+	}
+}
 
 // Create a new Differential-Drive Robbot
 //
 // #Arguments
 //
+// * `hal` - The hardware backend the robot runs on
 // * `wheel_distance` - Distance between the wheels (middle of the wheel)
 // * `caster_distance` - Distance from the amin Axle to the caster wheel mounting point
-pub fn new(wheel_distance: f32, caster_distance: f32) -> DifferentialDrive {
+pub fn new(hal: Arc<dyn Backend>, wheel_distance: Length, caster_distance: Length) -> DifferentialDrive {
+	let now = hal.now();
 	DifferentialDrive {
+		status: status::new(hal.clone()),
+		hal,
 		wheel_distance,
 		caster_distance,
-		left: Motor::default(),
-		right: Motor::default(),
+		left: vec!(),
+		right: vec!(),
+		slew_rate: None,
+		velocity_control: (None, None),
+		fault_detection: None,
+		side_odometry: SideOdometry::Average,
+		kinematics: Kinematics::Differential,
+		profiler: None,
+		stopping: false,
+		slip_detector: None,
 		position: Position::default(),
 		planner: None,
 		running: false,
 		loop_run: false,
-		last_step: Instant::now(),
+		last_step: now,
 		distances: vec!(),
+		imu: None,
+		compass: None,
+		battery: None,
+		last_battery: now,
+		fault: None,
+		stop_mode: StopMode::Brake,
+		watchdog: None,
 	}
 }
 
 /// This is the main Robot
 pub struct DifferentialDrive {
-	wheel_distance: f32,
-	caster_distance: f32,
-	left: Motor,
-	right: Motor,
+	hal: Arc<dyn Backend>,
+	wheel_distance: Length,
+	#[allow(dead_code)]
+	caster_distance: Length,
+	left: Vec<Motor>,
+	right: Vec<Motor>,
+	/// Settings of the motors, they are applied to wheels added later on too
+	slew_rate: Option<f64>,
+	velocity_control: (Option<VelocityControl>, Option<VelocityControl>),
+	fault_detection: Option<FaultDetection>,
+	side_odometry: SideOdometry,
+	kinematics: Kinematics,
+	profiler: Option<Profiler>,
+	/// The goal was reached and the profiler brings the robot to rest, even if it drifts out of the goal area meanwhile
+	stopping: bool,
+	slip_detector: Option<SlipDetector>,
 	position: Position,
 	planner: Option<Planner>,
 	running: bool,
 	loop_run: bool,
 	last_step: Instant,
-	distances: Vec<Ultrasonic>,
+	distances: Vec<Box<dyn Sensor>>,
+	imu: Option<Imu>,
+	compass: Option<(Compass, f64)>,
+	status: StatusLed,
+	battery: Option<(Battery, bool)>,
+	last_battery: Instant,
+	fault: Option<Error>,
+	stop_mode: StopMode,
+	watchdog: Option<Watchdog>,
 }
 impl DifferentialDrive {
-	/// Add a motorized wheel
+	/// Add a motorized wheel, a side may have any number of wheels which all get the speed of the side
 	///
 	/// # Arguments
 	///
 	/// * `wheel` - A Wheel-Instance
 	/// * `reversed` - If the motor and encoder is reversed (rotated 180°, even the left or right is)
 	pub fn add_wheel(&mut self, wheel: Wheel, reversed: bool) {
-		match wheel.orientation {
-			Orientation::LEFT => self.left = Motor {
-				wheel,
-				angle: 0.0,
-				rotations: 0,
-				reversed,
-				last_encoder: 0,
-			},
-			Orientation::RIGHT => self.right = Motor {
-				wheel,
-				angle: 0.0,
-				rotations: 0,
-				reversed,
-				last_encoder: 0,
-			},
-			_ => {},
+		let (side, velocity_control) = match wheel.orientation {
+			Orientation::LEFT => (&mut self.left, self.velocity_control.0),
+			Orientation::RIGHT => (&mut self.right, self.velocity_control.1),
+			Orientation::UNDEFINED => return,
+		};
+		side.push(Motor {
+			wheel,
+			angle: Angle::ZERO,
+			rotations: 0,
+			reversed,
+			last_encoder: 0,
+			slew_rate: self.slew_rate,
+			velocity_control,
+			fault_detection: self.fault_detection,
+			..Motor::default()
+		});
+	}
+
+	/// Set how the distances of several wheels on a side are combined for the odometry, the default is the average
+	pub fn set_side_odometry(&mut self, side_odometry: SideOdometry) {
+		self.side_odometry = side_odometry;
+	}
+
+	/// Set how the robot turns, e.g. a skid-steer chassis with four wheels, the default is a differential drive
+	pub fn set_kinematics(&mut self, kinematics: Kinematics) {
+		self.kinematics = kinematics;
+	}
+
+	/// Limit how fast the duty of the motors may change, so the wheels do not slip on sudden changes
+	///
+	/// # Arguments
+	///
+	/// * `rate` - Maximum change of the duty per second, None for no limit
+	pub fn set_slew_rate(&mut self, rate: Option<f64>) {
+		self.slew_rate = rate;
+		for motor in self.left.iter_mut().chain(self.right.iter_mut()) {
+			motor.slew_rate = rate;
+		}
+	}
+
+	/// Control the velocity of the wheels in a closed loop instead of sending the speeds as duty,
+	/// so both wheels drive equally fast with the same speed
+	///
+	/// # Arguments
+	///
+	/// * `control` - Gains of the velocity control, None for the open loop
+	pub fn set_velocity_control(&mut self, control: Option<VelocityControl>) {
+		self.velocity_control = (control, control);
+		for motor in self.left.iter_mut().chain(self.right.iter_mut()) {
+			motor.velocity_control = control;
+		}
+	}
+
+	/// Stop the robot with a fault if a wheel is stalled, turns against its duty or its encoder jumps
+	///
+	/// # Arguments
+	///
+	/// * `detection` - Limits of the fault detection, None to not check the wheels
+	pub fn set_fault_detection(&mut self, detection: Option<FaultDetection>) {
+		self.fault_detection = detection;
+		for motor in self.left.iter_mut().chain(self.right.iter_mut()) {
+			motor.fault_detection = detection;
 		}
 	}
 
-	/// Start the robot
+	/// Replace the feedforward gains of the velocity control, e.g. with the ones of a motor characterization
+	///
+	/// The velocity control has to be set first, the gains are kept when it is set again
+	///
+	/// # Arguments
+	///
+	/// * `left` - Feedforward of the left wheel
+	/// * `right` - Feedforward of the right wheel
+	pub fn set_feedforward(&mut self, left: Feedforward, right: Feedforward) {
+		for (control, motors, feedforward) in [
+			(&mut self.velocity_control.0, &mut self.left, left),
+			(&mut self.velocity_control.1, &mut self.right, right),
+		] {
+			if let Some(control) = control.as_mut() {
+				control.ks = feedforward.ks;
+				control.kv = feedforward.kv;
+				control.ka = feedforward.ka;
+				for motor in motors.iter_mut() {
+					motor.velocity_control = Some(*control);
+				}
+			}
+		}
+	}
+
+	/// The measured velocities of the (left, right) side, the average of its wheels
+	pub fn wheel_velocities(&self) -> (Velocity, Velocity) {
+		let velocity = |motors: &[Motor]| match motors.len() {
+			0 => Velocity::ZERO,
+			count => motors.iter().map(|motor| motor.velocity).sum::<Velocity>() / count as f64,
+		};
+		(velocity(&self.left), velocity(&self.right))
+	}
+
+	/// The total distances the (left, right) side drove, combined like the odometry
+	pub fn wheel_distances(&self) -> (Length, Length) {
+		let distance = |motors: &[Motor]| self.side_odometry.combine(motors.iter().map(|motor| motor.total_distance()).collect());
+		(distance(&self.left), distance(&self.right))
+	}
+
+	/// The duties the motors of the (left, right) side were last set to, after the slew-rate limit
+	pub fn wheel_duties(&self) -> (Duty, Duty) {
+		let duty = |motors: &[Motor]| match motors.len() {
+			0 => Duty::ZERO,
+			count => Duty::new(motors.iter().map(|motor| motor.duty.get()).sum::<f64>() / count as f64),
+		};
+		(duty(&self.left), duty(&self.right))
+	}
+
+	/// The duties of the (left, right) side before the battery compensation, the ones the velocity control works with
+	pub fn commanded_duties(&self) -> (Duty, Duty) {
+		let (left, right) = self.wheel_duties();
+		let compensation = self.compensation();
+		(Duty::new(left.get() / compensation), Duty::new(right.get() / compensation))
+	}
+
+	/// Set how the motors are stopped when the robot halts or reaches the goal, the default is to brake
+	pub fn set_stop_mode(&mut self, mode: StopMode) {
+		self.stop_mode = mode;
+	}
+
+	/// Start the robot, it does not start on a critical battery or a tripped watchdog
+	///
+	/// A previous fault is cleared
 	pub fn start(&mut self, restart_on_end: bool) {
+		// The battery is only measured while stepping, so a critical pack would not be refused before the first step
+		if let Some((battery, _)) = self.battery.as_mut() {
+			if let Err(err) = battery.update() {
+				println!("ERROR: {}", err);
+			}
+			self.last_battery = self.hal.now();
+		}
+		if self.battery_level() == Level::Critical {
+			println!("ERROR: Battery is critical, not starting");
+			return;
+		}
+		if self.watchdog.as_ref().is_some_and(|watchdog| watchdog.tripped()) {
+			println!("ERROR: Watchdog tripped, reset it before starting");
+			return;
+		}
+		self.fault = None;
 		self.running = true;
 		self.loop_run = restart_on_end;
-		self.last_step = Instant::now();
+		self.last_step = self.hal.now();
+		if let Some(watchdog) = self.watchdog.as_mut() {
+			watchdog.start();
+		}
 	}
 
 	/// Stop the robot
-	pub fn halt(&mut self) {
+	///
+	/// All motors and sensors are stopped even if one of them fails, the first Error is returned
+	pub fn halt(&mut self) -> Result<(), Error> {
+		self.running = false;
+		let mut result = self.stop_motors();
+
+		for dist in self.distances.iter_mut() {
+			result = result.and(dist.stop());
+		}
+		if let Some(watchdog) = self.watchdog.as_mut() {
+			watchdog.stop();
+		}
+		self.status.off();
+		result
+	}
+
+	/// The error which stopped the robot, None if it is running fine
+	pub fn fault(&self) -> Option<&Error> {
+		self.fault.as_ref()
+	}
+
+	/// Stop the motors but keep the sensors, the position and the path, `resume` continues from here
+	pub fn pause(&mut self) -> Result<(), Error> {
 		self.running = false;
-		self.left.stop();
-		self.right.stop();
+		self.stop_motors()
+	}
+
+	/// Continue after a pause, like `start` it does not continue on a critical battery
+	pub fn resume(&mut self) {
+		self.start(self.loop_run);
+	}
+
+	/// Check if the robot is driving to its goal
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	/// The current position of the robot: (X, Y, ORIENTATION)
+	pub fn pose(&self) -> (Length, Length, Angle) {
+		(self.position.x, self.position.y, self.position.phi)
+	}
+
+	/// Set the current position of the robot, it is taken as exact and the covariance is reset
+	///
+	/// # Arguments
+	///
+	/// * `x` - X-Coordinates
+	/// * `y` - Y-Coordinates
+	/// * `phi` - Orientation
+	pub fn set_pose(&mut self, x: Length, y: Length, phi: Angle) {
+		self.position.set_position(x, y, phi);
+		self.position.set_covariance(Covariance::default());
+	}
+
+	/// The covariance of the current pose: X and Y in mm², the orientation in rad²
+	pub fn covariance(&self) -> Covariance {
+		self.position.covariance
+	}
+
+	/// Set the covariance of the current pose, e.g. the one of a re-localization after `set_pose`
+	pub fn set_covariance(&mut self, covariance: Covariance) {
+		self.position.set_covariance(covariance);
+	}
+
+	/// Standard deviation of the position along the direction it is most uncertain in
+	pub fn uncertainty(&self) -> Length {
+		position::uncertainty(&self.position.covariance)
+	}
+
+	/// Set the error model of the wheels the covariance of the pose grows with
+	///
+	/// # Arguments
+	///
+	/// * `error` - Variance of the distance of each side per mm driven
+	pub fn set_odometry_error(&mut self, error: OdometryError) {
+		self.position.set_odometry_error(error);
+	}
 
-		self.distances.iter_mut().for_each(|dist| { dist.stop(); });
+	/// Check if the path planner asks for a re-localization, the robot pauses until it got one
+	pub fn needs_localization(&self) -> bool {
+		self.planner.as_ref().is_some_and(|planner| planner.needs_localization(&self.position.covariance))
 	}
 
 	/// Set the Coordinates the robot should reach
 	///
 	/// # Arguments
 	///
-	/// * `x` - X-Coordinates in mm
-	/// * `y` - Y-Coordinates in mm
-	pub fn set_goal(&mut self, x: f64, y: f64) {
+	/// * `x` - X-Coordinates
+	/// * `y` - Y-Coordinates
+	pub fn set_goal(&mut self, x: Length, y: Length) {
 		self.position.set_goal(x, y);
+		self.stopping = false;
 	}
 
 	/// Set a path-planner
@@ -103,7 +426,7 @@ impl DifferentialDrive {
 		self.planner = Some(planner);
 		if let Some(plan) = &self.planner {
 			let start = plan.start();
-			self.position.set_position(start.0, start.1, start.2);
+			self.set_pose(Length::mm(start.0), Length::mm(start.1), Angle::rad(start.2));
 		}
 		self.next_goal();
 	}
@@ -112,59 +435,382 @@ impl DifferentialDrive {
 	///
 	/// # Arguments
 	///
-	/// * `sensors` - List of Ultrasonic Sensors
-	pub fn collision_detection(&mut self, sensors: &mut[Ultrasonic]) {
+	/// * `sensors` - List of distance sensors, e.g. Ultrasonic or TimeOfFlight
+	pub fn collision_detection<S: Sensor + Clone + 'static>(&mut self, sensors: &mut[S]) -> Result<(), Error> {
 		for sensor in sensors {
-			sensor.start();
-			self.distances.push(sensor.clone());
+			sensor.start()?;
+			self.distances.push(Box::new(sensor.clone()));
+		}
+		Ok(())
+	}
+
+	/// Brake the motors when `step` is not called in time, it is started with the robot
+	///
+	/// # Arguments
+	///
+	/// * `watchdog` - A watchdog on the same backend
+	pub fn add_watchdog(&mut self, watchdog: Watchdog) {
+		self.watchdog = Some(watchdog);
+	}
+
+	/// Clear a tripped watchdog and its fault, the robot can be started again afterwards
+	pub fn reset_watchdog(&mut self) {
+		if let Some(watchdog) = self.watchdog.as_mut() {
+			if watchdog.tripped() {
+				watchdog.reset();
+				self.fault = None;
+			}
 		}
 	}
 
+	/// Set how the motion of each step is integrated into the position, the default is the exact arc
+	pub fn set_integration(&mut self, integration: Integration) {
+		self.position.set_integration(integration);
+	}
+
+	/// Limit the velocity, acceleration and jerk on the way to the goal and come to rest smoothly at the last one
+	///
+	/// # Arguments
+	///
+	/// * `profiler` - A velocity profiler with the limits of the robot
+	pub fn add_profiler(&mut self, profiler: Profiler) {
+		self.profiler = Some(profiler);
+	}
+
+	/// Use the gyro of an IMU to calculate the orientation of the robot
+	///
+	/// # Arguments
+	///
+	/// * `imu` - A started IMU
+	/// * `weight` - How much the gyro is trusted compared to the encoders, from 0.0 up to 1.0
+	pub fn add_imu(&mut self, imu: Imu, weight: f64) {
+		self.imu = Some(imu);
+		self.position.set_gyro_weight(weight);
+	}
+
+	/// Take the IMU away, so the odometry and a calibration rely on the encoders alone
+	///
+	/// # Returns
+	///
+	/// The IMU and its weight to add it again with `add_imu`, None without an IMU
+	pub fn remove_imu(&mut self) -> Option<(Imu, f64)> {
+		self.imu.take().map(|imu| (imu, self.position.gyro_weight))
+	}
+
+	/// Compare the motion of the wheels with the IMU, so a slip of the wheels does not move the position
+	///
+	/// Needs the IMU of `add_imu`, without it no slip is detected
+	///
+	/// # Arguments
+	///
+	/// * `detector` - A slip detector with its thresholds
+	pub fn add_slip_detection(&mut self, detector: SlipDetector) {
+		self.slip_detector = Some(detector);
+	}
+
+	/// The current slip of the wheels, None if they grip or the slip is not detected
+	pub fn slip(&self) -> Option<Slip> {
+		self.slip_detector.as_ref().and_then(|detector| detector.slip())
+	}
+
+	/// Number of slips of the wheels since the slip detection was added
+	pub fn slip_events(&self) -> u32 {
+		self.slip_detector.as_ref().map_or(0, |detector| detector.events())
+	}
+
+	/// Use a compass to correct the orientation of the robot, so the heading error stays bounded
+	///
+	/// The compass is aligned to the current orientation of the robot, so set the position first
+	///
+	/// # Arguments
+	///
+	/// * `compass` - A calibrated compass
+	/// * `weight` - How much of the heading error is corrected on each step [0.0 - 1.0]
+	pub fn add_compass(&mut self, mut compass: Compass, weight: f64) -> Result<(), Error> {
+		compass.align(self.position.phi.as_rad())?;
+		self.compass = Some((compass, weight));
+		Ok(())
+	}
+
+	/// Monitor the battery, the robot brakes the motors if it gets critical
+	///
+	/// # Arguments
+	///
+	/// * `battery` - A started battery monitor
+	/// * `compensate` - Compensate the motor duties for the voltage sag of the battery
+	pub fn add_battery(&mut self, battery: Battery, compensate: bool) {
+		self.battery = Some((battery, compensate));
+	}
+
+	/// The level of the battery, Ok if it is not monitored
+	pub fn battery_level(&self) -> Level {
+		self.battery.as_ref().map_or(Level::Ok, |(battery, _)| battery.level())
+	}
+
+	/// Drive the wheels directly with the given speeds, the robot must not be running
+	///
+	/// The speeds are sent as duty without the velocity control.
+	/// With a slew-rate limit it has to be called repeatedly until the speeds are reached
+	///
+	/// # Arguments
+	///
+	/// * `left` - The speed of the left wheel
+	/// * `right` - The speed of the right wheel
+	pub fn set_speed(&mut self, left: Duty, right: Duty) -> Result<(), Error> {
+		match self.running {
+			true => Ok(()),
+			false => self.drive(left, right, false),
+		}
+	}
+
+	/// Read the encoders and update the position and the wheel velocities, the robot must not be running
+	///
+	/// While the robot is running `step` does this, e.g. use it to measure the wheels driven with `set_speed`
+	pub fn update_odometry(&mut self) -> Result<(), Error> {
+		match self.running {
+			true => Ok(()),
+			false => self.odometry(),
+		}
+	}
+
+	/// Send the speeds to the motors of each side, compensated for the battery voltage if enabled
+	///
+	/// # Arguments
+	///
+	/// * `left` - The speed of the left side
+	/// * `right` - The speed of the right side
+	/// * `control` - Pass the speeds through the velocity control of each wheel, otherwise they are sent as duty
+	fn drive(&mut self, left: Duty, right: Duty, control: bool) -> Result<(), Error> {
+		let compensation = self.compensation();
+		// The traction control cuts the speed before the velocity control, which would wind up against a cut duty
+		let traction = self.slip_detector.as_ref().map_or(1.0, |detector| detector.traction());
+		let hal = self.hal.as_ref();
+		for (motors, speed) in [(&mut self.left, left * traction), (&mut self.right, right * traction)] {
+			for motor in motors.iter_mut() {
+				let duty = match control {
+					true => motor.duty(hal, speed),
+					false => speed,
+				};
+				motor.set_speed(hal, duty * compensation)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Factor the duties are multiplied with for the battery voltage, 1.0 without a compensation
+	fn compensation(&self) -> f64 {
+		match &self.battery {
+			Some((battery, true)) => battery.compensation(),
+			_ => 1.0,
+		}
+	}
+
+	/// Stop all motors even if one of them fails, the first Error is returned
+	fn stop_motors(&mut self) -> Result<(), Error> {
+		if let Some(profiler) = self.profiler.as_mut() {
+			profiler.reset();
+		}
+		let mut result = Ok(());
+		for motor in self.left.iter_mut().chain(self.right.iter_mut()) {
+			result = result.and(motor.stop(self.hal.as_ref(), self.stop_mode));
+		}
+		result
+	}
+
+	/// Feed the watchdog, a tripped one is reported once as fault
+	fn check_watchdog(&mut self) -> Result<(), Error> {
+		match &self.watchdog {
+			Some(watchdog) if watchdog.tripped() => match self.fault {
+				Some(_) => Ok(()),
+				None => Err(Error::Timeout(format!("Control loop stalled for more than {:?}", watchdog.deadline()))),
+			},
+			Some(watchdog) => { watchdog.feed(); Ok(()) },
+			None => Ok(()),
+		}
+	}
+
+	/// Measure the battery regularly and brake the motors if it is critical
+	fn check_battery(&mut self) -> Result<(), Error> {
+		let now = self.hal.now();
+		if now.duration_since(self.last_battery) < BATTERY_INTERVAL {
+			return Ok(());
+		}
+		self.last_battery = now;
+
+		let level = match self.battery.as_mut() {
+			Some((battery, _)) => battery.update()?,
+			None => return Ok(()),
+		};
+		if level == Level::Critical && self.running {
+			println!("ERROR: Battery is critical, stopping");
+			self.halt()?;
+		}
+		Ok(())
+	}
+
 	/// sets the next goal for the Robot based on the PathPlanner
 	fn next_goal(&mut self) {
 		if let Some(planner) = self.planner.as_mut() {
 			if let Ok(goal) = planner.next_goal() {
-				self.set_goal(goal.0, goal.1);
+				self.set_goal(Length::mm(goal.0), Length::mm(goal.1));
 			} else if self.loop_run {
 				planner.restart();
 				let start = planner.start();
-				self.set_goal(start.0, start.1);
+				self.set_goal(Length::mm(start.0), Length::mm(start.1));
 			}
 		}
 	}
 
+	/// The current status of the robot
+	pub fn status(&self) -> Status {
+		let obstacle = self.distances.iter()
+			.filter_map(|sensor| sensor.distance())
+			.any(|distance| distance < OBSTACLE_DISTANCE);
+
+		if self.fault.is_some() {
+			Status::Fault
+		} else if self.battery_level() != Level::Ok {
+			Status::LowBattery
+		} else if obstacle {
+			Status::ObstacleClose
+		} else if !self.running {
+			Status::Idle
+		} else if self.slip().is_some() {
+			Status::Slipping
+		} else if self.position.goal_reached() {
+			Status::GoalReached
+		} else {
+			Status::Running
+		}
+	}
+
 	/// Called on each step, calculates the new position and how to get to the wanted one, etc.
-	pub fn step(&mut self) {
-		if self.running {
-			let now = Instant::now();
-			let duration = now.duration_since(self.last_step).as_nanos();
-
-			// Get the travelling distance
-			let (dist_l, _angle_l) = self.left.step(duration);
-			let (dist_r, _angle_r) = self.right.step(duration);
-
-			// Update the new position of of the robot
-			self.position.calculate_position(dist_l, dist_r, self.wheel_distance);
-			//self.position.debug();
-
-			// If we reached the goal and have a path planner, set the next goal
-			if self.planner.is_some() && self.position.goal_reached() {
-				self.next_goal();
-			}
+	///
+	/// If a sensor or motor fails, the robot is halted and the Error is returned
+	pub fn step(&mut self) -> Result<(), Error> {
+		let result = self.check_watchdog().and_then(|_| self.check_battery()).and_then(|_| match self.running {
+			true => self.control(),
+			false => Ok(()),
+		});
+		// Only the motors stop on a fault, the collision detection keeps measuring for the restart
+		if let Err(err) = &result {
+			self.fault = Some(err.clone());
+			let _ = self.pause();
+		}
+
+		self.status.set(self.status());
+		self.status.step();
+		result
+	}
+
+	/// Calculate the new position and drive the motors towards the goal
+	fn control(&mut self) -> Result<(), Error> {
+		self.odometry()?;
+
+		// The position is too uncertain to drive on
+		if self.needs_localization() {
+			return self.pause();
+		}
+
+		// If we reached the goal and have a path planner, set the next goal
+		if self.planner.is_some() && self.position.goal_reached() {
+			self.next_goal();
+		}
+
+		// Stop if the goal is reached, otherwise get the velocities for the wheels
+		let wheel_distance = self.kinematics.effective_distance(self.wheel_distance);
+		let reached = self.stopping || self.position.goal_reached();
+		let (left, right) = match reached {
+			true => (Duty::ZERO, Duty::ZERO),
+			false => self.position.get_goal_velocities(wheel_distance),
+		};
+		match self.profiler.as_mut() {
+			// The robot comes to rest at the last goal, it drives through the others
+			Some(profiler) => {
+				let last = !self.loop_run && self.planner.as_ref().is_none_or(|planner| planner.is_finished());
+				let (left, right) = profiler.step(self.hal.now(), left, right, wheel_distance, last.then(|| self.position.goal_distance()));
+				self.stopping = reached;
+				match reached && profiler.at_rest() {
+					true => self.stop_motors(),
+					false => self.drive(left, right, true),
+				}
+			},
+			None if reached => self.stop_motors(),
+			None => self.drive(left, right, true),
+		}
+	}
+
+	/// Read the encoders and calculate the new position and the velocities of the wheels
+	fn odometry(&mut self) -> Result<(), Error> {
+		let now = self.hal.now();
+		let duration = now.duration_since(self.last_step);
 
-			// Stop if the goal is reached, otherwise get the velocities for the wheels
-			if !self.position.goal_reached() {
-				let (left, right) = self.position.get_goal_velocities(self.wheel_distance);
-				self.left.set_speed(left);
-				self.right.set_speed(right);
-			} else {
-				self.left.stop();
-				self.right.stop();
+		// Get the travelling distance of each side
+		let mut distances = (vec!(), vec!());
+		for (motors, distances, name) in [(&mut self.left, &mut distances.0, "left"), (&mut self.right, &mut distances.1, "right")] {
+			if motors.is_empty() {
+				return Err(Error::InvalidChannel(format!("No {} wheel added", name)));
 			}
+			for motor in motors.iter_mut() {
+				distances.push(motor.step(self.hal.as_ref(), duration)?.0);
+			}
+		}
+		let (dist_l, dist_r) = (self.side_odometry.combine(distances.0), self.side_odometry.combine(distances.1));
+		self.last_step = now;
 
-			self.last_step = now;
+		// Angle the robot turned since the last step as measured by the gyro
+		let gyro = self.imu.as_ref()
+			.and_then(|imu| imu.yaw_rate().ok())
+			.map(AngularVelocity::rad_per_s);
+		let gyro_angle = gyro.map(|rate| rate * duration);
+
+		// Rely on the IMU instead of the wheels while they slip
+		let wheel_distance = self.kinematics.effective_distance(self.wheel_distance);
+		let (velocity_l, velocity_r) = self.wheel_velocities();
+		let (dist_l, dist_r) = match (self.slip_detector.as_mut(), self.imu.as_ref().map(|imu| imu.acceleration()), gyro) {
+			(Some(detector), Some(Ok(acceleration)), Some(gyro)) => {
+				let yaw_rate = AngularVelocity::rad_per_s((velocity_r - velocity_l).as_mm_per_s() / wheel_distance.as_mm());
+				detector.step((velocity_l + velocity_r) / 2.0, yaw_rate, acceleration, gyro, duration);
+				detector.correct(dist_l, dist_r, wheel_distance, gyro, duration)
+			},
+			_ => (dist_l, dist_r),
+		};
+
+		// Update the new position of of the robot
+		self.position.calculate_position(dist_l, dist_r, wheel_distance, gyro_angle);
+		if let Some((compass, weight)) = &self.compass {
+			if let Ok(heading) = compass.heading() {
+				self.position.correct_heading(Angle::rad(heading), *weight);
+			}
 		}
+		//self.position.debug();
+		Ok(())
 	}
 
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Distances in mm of the wheels of a side
+	fn distances(mm: &[f64]) -> Vec<Length> {
+		mm.iter().map(|mm| Length::mm(*mm)).collect()
+	}
+
+	#[test]
+	fn outvotes_a_slipping_or_glitching_wheel() {
+		// A spinning wheel drives too far, a glitching encoder jumps backwards
+		for outlier in [25.0, -40.0] {
+			let median = SideOdometry::Median.combine(distances(&[10.0, outlier, 10.2]));
+			assert!((10.0..=10.2).contains(&median.as_mm()), "median {}", median);
+			let average = SideOdometry::Average.combine(distances(&[10.0, outlier, 10.2]));
+			assert!((average.as_mm() - 10.1).abs() > 4.0, "average {}", average);
+		}
+
+		// With an even number of wheels the two in the middle are averaged
+		let median = SideOdometry::Median.combine(distances(&[10.0, 25.0, 9.8, 10.2]));
+		assert!((median.as_mm() - 10.1).abs() < 1e-9, "median {}", median);
+		assert_eq!(SideOdometry::Median.combine(vec![]), Length::ZERO);
+	}
+}
@@ -1,46 +1,115 @@
+use super::error::Error;
 use super::hal;
+use super::hal::Backend;
+use super::servo::Servo as Servo;
 
 use std::time::Duration;
 use std::thread;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-pub fn new(trigger: (hal::GpioChip, i32), echo: (hal::GpioChip, i32)) -> Ultrasonic {
+/// Time the servo needs to turn the sensor to the next angle of the scan
+const SERVO_SETTLE: Duration = Duration::from_millis(30);
+
+/// Longest echo to wait for, 30000µs are about 5m which is beyond the range of the sensor
+const ECHO_TIMEOUT: Duration = Duration::from_micros(30000);
+
+/// Distance in cm measured at an angle in rad, None if the measurement failed
+pub type Reading = (f64, Option<f64>);
+
+/// A sensor which measures the distance to the next obstacle continuously, e.g. for the collision detection
+pub trait Sensor: Send {
+	/// Start measuring
+	fn start(&mut self) -> Result<(), Error>;
+
+	/// Stop measuring
+	fn stop(&mut self) -> Result<(), Error>;
+
+	/// The last measured distance in cm, None if the last measurement failed
+	fn distance(&self) -> Option<f64>;
+
+	/// Why the last measurement failed, None if it succeeded
+	fn error(&self) -> Option<Error> {
+		None
+	}
+}
+
+pub fn new(hal: Arc<dyn Backend>, trigger: (hal::GpioChip, i32), echo: (hal::GpioChip, i32)) -> Ultrasonic {
 	Ultrasonic {
+		hal,
 		trigger_chip: trigger.0,
 		trigger_pin: trigger.1,
 		echo_chip: echo.0,
 		echo_pin: echo.1,
 		stop: Arc::new(AtomicBool::new(false)),
+		distance: Arc::new(AtomicU64::new(f64::NAN.to_bits())),
+		error: Arc::new(Mutex::new(None)),
+		scan: None,
+		readings: Arc::new(Mutex::new(vec!())),
 	}
 }
 
+/// Create a sensor which is mounted on a servo and scans an arc
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the sensor is attached to
+/// * `trigger` - GPIO-Chip and Pin of the trigger
+/// * `echo` - GPIO-Chip and Pin of the echo
+/// * `servo` - The servo the sensor is mounted on
+/// * `from` - Start angle of the arc in rad, 0.0 is straight ahead
+/// * `to` - End angle of the arc in rad
+/// * `steps` - Number of measurements over the arc
+pub fn scanning(hal: Arc<dyn Backend>, trigger: (hal::GpioChip, i32), echo: (hal::GpioChip, i32), servo: Servo, from: f64, to: f64, steps: usize) -> Ultrasonic {
+	let steps = steps.max(1);
+	let angles: Vec<f64> = match steps {
+		1 => vec!(from),
+		_ => (0..steps).map(|i| from + (to - from) * i as f64 / (steps - 1) as f64).collect(),
+	};
+
+	let mut sensor = new(hal, trigger, echo);
+	sensor.readings = Arc::new(Mutex::new(angles.iter().map(|angle| (*angle, None)).collect()));
+	sensor.scan = Some((servo, Arc::new(angles)));
+	sensor
+}
+
 #[derive(Clone)]
 pub struct Ultrasonic {
+	hal: Arc<dyn Backend>,
 	trigger_chip: hal::GpioChip,
 	trigger_pin: i32,
 	echo_chip: hal::GpioChip,
 	echo_pin: i32,
 	stop: Arc<AtomicBool>,
+	distance: Arc<AtomicU64>,
+	error: Arc<Mutex<Option<Error>>>,
+	scan: Option<(Servo, Arc<Vec<f64>>)>,
+	readings: Arc<Mutex<Vec<Reading>>>,
 }
 
 impl Ultrasonic {
-	/// Start the distance measure process in a thread
-	pub fn start(&mut self) -> Result<(),()> {
-		match hal::gpio_init(self.trigger_chip, self.trigger_pin, hal::GpioHandle::OUTPUT) {
-			Err(_) => return Err(()),
-			_ => {},
-		}
-		match hal::gpio_init(self.echo_chip, self.echo_pin, hal::GpioHandle::INPUT) {
-			Err(_) => return Err(()),
-			_ => {},
+	/// Start the distance measure process in a thread, also after it was stopped
+	pub fn start(&mut self) -> Result<(), Error> {
+		self.stop.store(true, Ordering::Relaxed);
+		self.stop = Arc::new(AtomicBool::new(false));
+		self.hal.gpio_init(self.trigger_chip, self.trigger_pin, hal::GpioHandle::OUTPUT)?;
+		self.hal.gpio_init_event(self.echo_chip, self.echo_pin)?;
+		if let Some((servo, _)) = self.scan.as_mut() {
+			servo.start()?;
 		}
 
 		// Spawn an unhandled thread
 		thread::spawn({
+			let hal = self.hal.clone();
 			let stop = self.stop.clone();
-			let trigger = (self.trigger_chip.clone(), self.trigger_pin.clone());
-			let echo = (self.echo_chip.clone(), self.echo_pin.clone());
+			let last_distance = self.distance.clone();
+			let last_error = self.error.clone();
+			let scan = self.scan.clone();
+			let readings = self.readings.clone();
+			let mut index = 0;
+			let mut forward = true;
+			let trigger = (self.trigger_chip, self.trigger_pin);
+			let echo = (self.echo_chip, self.echo_pin);
 
 			// Distance = ((speed of sound in the air) * time) / 2
 			// Speed of sound is: 343m/s = 0.0343 cm/uS
@@ -51,32 +120,135 @@ impl Ultrasonic {
 					let inner_stop = stop.clone();
 					if inner_stop.load(Ordering::Relaxed) { break; }
 
+					// Turn the sensor to the next angle of the arc, back and forth
+					if let Some((servo, angles)) = &scan {
+						servo.set_angle(angles[index]);
+						thread::sleep(SERVO_SETTLE);
+					}
+
 					// Initialize the Sensor by sending a 10 ms pulse
-					let result = match hal::gpio_send_pulse(trigger.0, trigger.1, hal::GpioTrigger::LOW, Duration::from_micros(10)) {
+					let result = match hal.gpio_send_pulse(trigger.0, trigger.1, hal::GpioTrigger::LOW, Duration::from_micros(10)) {
 						Ok(_) => {
-							hal::gpio_read_pulse(echo.0, echo.1, hal::GpioTrigger::HIGH)
+							hal.gpio_read_pulse(echo.0, echo.1, hal::GpioTrigger::HIGH, ECHO_TIMEOUT)
 						},
 						Err(err) => Err(err),
 					};
 
-					let duration = result.unwrap_or_default();
-					let distance = speed_constant * duration.as_micros() as f64;
-					println!("Distance ({:?}): {}cm", duration, distance);
+					// Without an obstacle in range every measurement times out, so the error is kept instead of printed
+					let distance = match result {
+						Ok(duration) => {
+							let distance = speed_constant * duration.as_micros() as f64;
+							println!("Distance ({:?}): {}cm", duration, distance);
+							Some(distance)
+						},
+						Err(err) => {
+							*last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(err);
+							None
+						},
+					};
+					if distance.is_some() {
+						*last_error.lock().unwrap_or_else(|err| err.into_inner()) = None;
+					}
+					last_distance.store(distance.unwrap_or(f64::NAN).to_bits(), Ordering::Relaxed);
+
+					if let Some((_, angles)) = &scan {
+						readings.lock().unwrap_or_else(|err| err.into_inner())[index] = (angles[index], distance);
+						if angles.len() > 1 {
+							if (forward && index + 1 == angles.len()) || (!forward && index == 0) {
+								forward = !forward;
+							}
+							index = if forward { index + 1 } else { index - 1 };
+						}
+					}
 
 					thread::sleep(Duration::from_millis(10));
 				}
 
 				println!("Distance Thread stopped");
-				hal::gpio_cleanup(trigger.0, trigger.1);
-				hal::gpio_cleanup(echo.0, echo.1);
 			}
 		});
 		Ok(())
 	}
 
+	/// The last measured distance in cm, None if the last measurement failed
+	pub fn distance(&self) -> Option<f64> {
+		let distance = f64::from_bits(self.distance.load(Ordering::Relaxed));
+		if distance.is_nan() { None } else { Some(distance) }
+	}
+
+	/// Why the last measurement failed, e.g. a Timeout without an obstacle in range, None if it succeeded
+	pub fn error(&self) -> Option<Error> {
+		self.error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+	}
+
+	/// The last distance in cm measured at each angle in rad of the scan, empty if the sensor is not scanning
+	pub fn scan(&self) -> Vec<Reading> {
+		self.readings.lock().unwrap_or_else(|err| err.into_inner()).clone()
+	}
+
 	/// Stop the distance measurement
-	pub fn stop(&mut self) {
+	///
+	/// The pins are released here and not in the thread, which could release them after a restart
+	pub fn stop(&mut self) -> Result<(), Error> {
 		self.stop.store(true, Ordering::Relaxed);
+		self.hal.gpio_cleanup(self.trigger_chip, self.trigger_pin);
+		self.hal.gpio_cleanup(self.echo_chip, self.echo_pin);
+		match self.scan.as_mut() {
+			Some((servo, _)) => servo.stop(),
+			None => Ok(()),
+		}
+	}
+
+}
+
+impl Sensor for Ultrasonic {
+	fn start(&mut self) -> Result<(), Error> {
+		Ultrasonic::start(self)
+	}
+
+	fn stop(&mut self) -> Result<(), Error> {
+		Ultrasonic::stop(self)
 	}
 
+	fn distance(&self) -> Option<f64> {
+		Ultrasonic::distance(self)
+	}
+
+	fn error(&self) -> Option<Error> {
+		Ultrasonic::error(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::simulated::Simulated;
+
+	/// Time for a few measurements
+	const SETTLE: Duration = Duration::from_millis(60);
+
+	#[test]
+	fn keeps_the_error_and_restarts() {
+		let hal = Arc::new(Simulated::default());
+		let mut sensor = new(hal.clone(), (hal::GpioChip::GPIO3, 17), (hal::GpioChip::GPIO3, 20));
+		sensor.start().unwrap();
+		thread::sleep(SETTLE);
+		assert_eq!(sensor.distance(), None);
+		assert!(matches!(sensor.error(), Some(Error::Timeout(_))), "error {:?}", sensor.error());
+
+		// An echo of 1ms is 17cm
+		hal.set_pulse(hal::GpioChip::GPIO3, 20, Duration::from_micros(1000));
+		thread::sleep(SETTLE);
+		assert!((sensor.distance().unwrap() - 17.15).abs() < 1e-9, "distance {:?}", sensor.distance());
+		assert!(sensor.error().is_none());
+
+		// The pins are initialized again, the stopped thread does not release them
+		sensor.stop().unwrap();
+		thread::sleep(SETTLE);
+		hal.set_pulse(hal::GpioChip::GPIO3, 20, Duration::from_micros(2000));
+		sensor.start().unwrap();
+		thread::sleep(SETTLE);
+		assert!((sensor.distance().unwrap() - 34.3).abs() < 1e-9, "distance {:?} after the restart", sensor.distance());
+		sensor.stop().unwrap();
+	}
 }
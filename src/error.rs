@@ -0,0 +1,42 @@
+use std::fmt;
+
+/// Errors of the robot and its hardware
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+	/// A device could not be initialized or is not initialized yet
+	Init(String),
+	/// Reading from a device failed
+	Read(String),
+	/// Writing to a device failed
+	Write(String),
+	/// A device did not answer in time
+	Timeout(String),
+	/// The channel, pin or wheel does not exist or is not configured
+	InvalidChannel(String),
+	/// The backend does not provide the device
+	NotSupported(String),
+	/// A calibration could not be calculated from the measurements
+	Calibration(String),
+	/// A file could not be read or written
+	Io(String),
+	/// A motor or its encoder does not behave as commanded, e.g. a jammed wheel or a loose cable
+	Fault(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Init(msg) => write!(f, "Initialization failed: {}", msg),
+			Error::Read(msg) => write!(f, "Read failed: {}", msg),
+			Error::Write(msg) => write!(f, "Write failed: {}", msg),
+			Error::Timeout(msg) => write!(f, "Timeout: {}", msg),
+			Error::InvalidChannel(msg) => write!(f, "Invalid channel: {}", msg),
+			Error::NotSupported(msg) => write!(f, "Not supported: {}", msg),
+			Error::Calibration(msg) => write!(f, "Calibration failed: {}", msg),
+			Error::Io(msg) => write!(f, "IO error: {}", msg),
+			Error::Fault(msg) => write!(f, "Fault: {}", msg),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
@@ -1,42 +1,207 @@
-extern crate librobotcontrol_sys;
+#[cfg(target_arch = "arm")]
+pub mod librobotcontrol;
+#[cfg(target_os = "linux")]
+pub mod linux;
+pub mod recorder;
+pub mod replay;
+pub mod simulated;
 
-use std::thread::sleep;
+use super::error::Error;
+
+use std::sync::Arc;
+use std::thread::{sleep, yield_now};
 use std::time::{Duration, Instant};
 
 pub const BUTTON_DEBOUNCE: ::std::os::raw::c_int = 2000; // 2ms
 pub const MOTOR_PWM: ::std::os::raw::c_int = 25000; // 25kHz
 
-pub fn cleanup() {
-	unsafe {
-		librobotcontrol_sys::rc_button_cleanup();
-		librobotcontrol_sys::rc_encoder_eqep_cleanup();
-		librobotcontrol_sys::rc_encoder_pru_cleanup();
-		librobotcontrol_sys::rc_led_cleanup();
-		librobotcontrol_sys::rc_motor_cleanup();
-		librobotcontrol_sys::rc_servo_cleanup();
+/// Hardware backend used by the robot to talk to encoders, motors, buttons and GPIOs
+///
+/// The BeagleBone Blue is driven through `librobotcontrol::LibRobotControl`, other Linux boards
+/// through `linux::Linux`, everywhere else the in-memory `simulated::Simulated` backend can be used.
+/// `recorder::Recorder` records the I/O of any of them, so `replay::Replay` can play it back.
+pub trait Backend: Send + Sync {
+	/// Release all resources the backend has acquired
+	fn cleanup(&self);
+
+	/// Initialize a button so callbacks can be registered
+	fn init_button(&self, button: Button) -> Result<(), Error>;
+
+	/// Register the callbacks which are called when the button is pressed and released
+	///
+	/// Already registered callbacks are replaced
+	fn register_button_callbacks(&self, button: Button, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>) -> Result<(), Error>;
+
+	/// Initialize all encoders
+	fn init_encoders(&self) -> Result<(), Error>;
+
+	/// Get the current count of the encoder
+	fn get_encoder_value(&self, encoder: Encoder) -> Result<i32, Error>;
+
+	/// Initialize all motors and brake them
+	fn init_motors(&self) -> Result<(), Error>;
+
+	/// Run the motor with the given duty from -1.0 up to 1.0
+	fn run_motor(&self, motor: Motor, speed: f64) -> Result<(), Error>;
+
+	/// Brake the motor
+	fn brake_motor(&self, motor: Motor) -> Result<(), Error>;
+
+	/// Let the motor spin freely
+	fn free_spin_motor(&self, motor: Motor) -> Result<(), Error>;
+
+	/// Put the drivers of all motors into standby or wake them up, in standby all motors spin freely
+	fn motor_standby(&self, _enable: bool) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No motor standby available on this backend")))
+	}
+
+	/// Initialize a GPIO pin as input or output
+	fn gpio_init(&self, chip: GpioChip, pin: i32, direction: GpioHandle) -> Result<(), Error>;
+
+	/// Release a GPIO pin
+	fn gpio_cleanup(&self, chip: GpioChip, pin: i32);
+
+	/// Set the value of an output pin
+	fn gpio_set_value(&self, chip: GpioChip, pin: i32, value: i32) -> Result<(), Error>;
+
+	/// Get the value of an input pin
+	fn gpio_get_value(&self, chip: GpioChip, pin: i32) -> Result<i32, Error>;
+
+	/// Send a pulse with the given value and length to the pin
+	fn gpio_send_pulse(&self, chip: GpioChip, pin: i32, value: GpioTrigger, time: Duration) -> Result<(), Error> {
+		self.gpio_set_value(chip, pin, value.get())?;
+		sleep(time);
+		self.gpio_set_value(chip, pin, value.inv())
+	}
+
+	/// Initialize an input pin for edge events, so `gpio_read_pulse` can wait for them
+	///
+	/// Backends without edge events initialize a plain input and poll its value
+	fn gpio_init_event(&self, chip: GpioChip, pin: i32) -> Result<(), Error> {
+		self.gpio_init(chip, pin, GpioHandle::INPUT)
+	}
+
+	/// Wait for a pulse with the given value on the pin and return its length
+	///
+	/// The pin has to be initialized with `gpio_init_event`. This default implementation polls the value,
+	/// backends with edge events should time the pulse with them instead.
+	///
+	/// # Arguments
+	///
+	/// * `chip` - The GPIO chip of the pin
+	/// * `pin` - The pin on the chip
+	/// * `value` - The value of the pulse
+	/// * `timeout` - Maximum time to wait for the start and the end of the pulse
+	fn gpio_read_pulse(&self, chip: GpioChip, pin: i32, value: GpioTrigger, timeout: Duration) -> Result<Duration, Error> {
+		let signal_check = value.get();
+		let deadline = Instant::now() + timeout;
+		let timeout = || Error::Timeout(format!("No pulse on GPIO{}_{}", chip as i32, pin));
+
+		let mut signal = self.gpio_get_value(chip, pin)?;
+
+		// Wait for the signal to start
+		while signal != signal_check {
+			if Instant::now() > deadline { return Err(timeout()); }
+			yield_now();
+			signal = self.gpio_get_value(chip, pin)?;
+		}
+
+		// Count the duration the signal is in the given state
+		let start = Instant::now();
+		while signal == signal_check {
+			if Instant::now() > deadline { return Err(timeout()); }
+			yield_now();
+			signal = self.gpio_get_value(chip, pin)?;
+		}
+		let duration = start.elapsed();
+		Ok(duration)
+	}
+
+	/// Switch a LED on or off
+	fn set_led(&self, _led: Led, _on: bool) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No LEDs available on this backend")))
+	}
+
+	/// Initialize the servo outputs
+	fn init_servos(&self) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No servos available on this backend")))
+	}
+
+	/// Switch the power rail of the servos on or off
+	fn servo_power_rail(&self, _enable: bool) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No servos available on this backend")))
+	}
+
+	/// Send a single pulse with the given width in µs to the servo
+	fn servo_send_pulse(&self, _servo: Servo, _width: i32) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No servos available on this backend")))
+	}
+
+	/// Initialize the ADC to measure voltages
+	fn init_adc(&self) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No ADC available on this backend")))
+	}
+
+	/// Get the voltage of the battery pack in V
+	fn battery_voltage(&self) -> Result<f64, Error> {
+		Err(Error::NotSupported(String::from("No ADC available on this backend")))
+	}
+
+	/// Get the voltage on the DC jack in V
+	fn jack_voltage(&self) -> Result<f64, Error> {
+		Err(Error::NotSupported(String::from("No ADC available on this backend")))
+	}
+
+	/// Initialize the IMU so it delivers samples continuously
+	fn init_imu(&self) -> Result<(), Error> {
+		Err(Error::NotSupported(String::from("No IMU available on this backend")))
+	}
+
+	/// Get the latest sample of the IMU
+	fn read_imu(&self) -> Result<ImuData, Error> {
+		Err(Error::NotSupported(String::from("No IMU available on this backend")))
+	}
 
-		librobotcontrol_sys::rc_bmp_power_off();
-		librobotcontrol_sys::rc_mpu_power_off();
+	/// The time the control steps are measured with, a replay runs on the time of its recording
+	fn now(&self) -> Instant {
+		Instant::now()
 	}
 }
 
+/// Get the backend for the platform the robot is built for
+///
+/// On the BeagleBone Blue (ARM) this is librobotcontrol, on all other platforms the simulation
+pub fn default_backend() -> Arc<dyn Backend> {
+	#[cfg(target_arch = "arm")]
+	{ Arc::new(librobotcontrol::LibRobotControl) }
+	#[cfg(not(target_arch = "arm"))]
+	{ Arc::new(simulated::Simulated::default()) }
+}
+
+/// Limit a motor speed to a duty from -1.0 up to 1.0
+pub(crate) fn clamp_duty(speed: f64) -> f64 {
+	speed.clamp(-1.0, 1.0)
+}
+
 /// Buttons
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Button {
 	#[default]
 	Pause = 5,
 	Mode = 4,
 }
-pub fn init_button(button: Button) {
-	unsafe { librobotcontrol_sys::rc_button_init(2, button as i32, 1, BUTTON_DEBOUNCE); }
-}
-pub fn register_button_callback(button: Button, callback: unsafe extern "C" fn()) {
-	unsafe { librobotcontrol_sys::rc_button_set_callbacks(2, button as i32, Some(callback), None); }
+
+
+/// LEDs
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Led {
+	Green = 0,
+	Red = 1,
 }
 
 
 /// Encoders
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Encoder {
 	#[default]
 	ENCODER1 = 1,
@@ -44,22 +209,10 @@ pub enum Encoder {
 	ENCODER3 = 3,
 	ENCODER4 = 4,
 }
-pub fn init_encoders() {
-	unsafe {
-		librobotcontrol_sys::rc_encoder_eqep_init();
-		librobotcontrol_sys::rc_encoder_pru_init();
-	}
-}
-pub fn get_encoder_value(encoder: Encoder) -> i32 {
-	match encoder {
-		Encoder::ENCODER4 => unsafe { librobotcontrol_sys::rc_encoder_pru_read() }
-		_ =>  unsafe { librobotcontrol_sys::rc_encoder_eqep_read(encoder as i32) }
-	}
-}
 
 
 /// Motors
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Motor {
 	#[default]
 	MOTOR1 = 1,
@@ -67,33 +220,43 @@ pub enum Motor {
 	MOTOR3 = 3,
 	MOTOR4 = 4,
 }
-pub fn init_motors() {
-	unsafe {
-		librobotcontrol_sys::rc_motor_init_freq(MOTOR_PWM);
-		librobotcontrol_sys::rc_motor_brake(Motor::MOTOR1 as i32);
-		librobotcontrol_sys::rc_motor_brake(Motor::MOTOR2 as i32);
-		librobotcontrol_sys::rc_motor_brake(Motor::MOTOR3 as i32);
-		librobotcontrol_sys::rc_motor_brake(Motor::MOTOR4 as i32);
-	}
-}
-pub fn run_motor(motor: Motor, speed: f64) -> i32 {
-	let mut duty = if speed > 1.0 { 1.0 } else { speed };
-	duty = if speed < -1.0 { -1.0 } else { duty };
-	unsafe { librobotcontrol_sys::rc_motor_set(motor as i32, duty) }
+
+/// How a motor is stopped
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopMode {
+	/// Short the motor, so it stops quickly
+	#[default]
+	Brake,
+	/// Disconnect the motor, so it coasts to a stop
+	FreeSpin,
+	/// Put the motor drivers into low-power standby, all motors spin freely
+	Standby,
 }
-pub fn brake_motor(motor: Motor) -> i32 {
-	unsafe { librobotcontrol_sys::rc_motor_brake(motor as i32) }
+
+
+/// Servos
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Servo {
+	SERVO1 = 1,
+	SERVO2 = 2,
+	SERVO3 = 3,
+	SERVO4 = 4,
+	SERVO5 = 5,
+	SERVO6 = 6,
+	SERVO7 = 7,
+	SERVO8 = 8,
 }
 
 
 /// GPIO
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum GpioChip {
 	GPIO0 = 0,
 	GPIO1 = 1,
 	GPIO2 = 2,
 	GPIO3 = 3,
 }
+#[allow(non_camel_case_types)]
 #[derive(Clone, Copy)]
 pub enum GpioHandle {
 	INPUT = 0,
@@ -120,49 +283,17 @@ impl GpioTrigger {
 		}
 	}
 }
-pub fn gpio_init(chip: GpioChip, pin: i32, direction: GpioHandle) -> Result<(), String> {
-	if unsafe { librobotcontrol_sys::rc_gpio_init(chip as i32, pin, direction as i32) } != 0 {
-		Err(format!("Unable to open GPIO{}_{}", chip as i32, pin))
-	} else {
-		Ok(())
-	}
-}
-pub fn gpio_cleanup(chip: GpioChip, pin: i32) {
-	unsafe { librobotcontrol_sys::rc_gpio_cleanup(chip as i32, pin); }
-}
-pub fn gpio_send_pulse(chip: GpioChip, pin: i32, value: GpioTrigger, time: Duration) -> Result<(), String> {
-	if unsafe { librobotcontrol_sys::rc_gpio_set_value(chip as i32, pin, value.get()) } != 0 {
-		return Err(format!("Unable to initialize pulse to GPIO{}_{} for {}us", chip as i32, pin, time.as_micros()));
-	}
-	sleep(time);
-	if unsafe { librobotcontrol_sys::rc_gpio_set_value(chip as i32, pin, value.inv()) } != 0 {
-		return Err(format!("Unable to tear down pulse to GPIO{}_{} after {}us", chip as i32, pin, time.as_micros()));
-	}
-	Ok(())
-}
-pub fn gpio_read_pulse(chip: GpioChip, pin: i32, value: GpioTrigger) -> Result<Duration, String> {
-	let signal_err = -1;
-	let signal_check = value.get();
-	let max_loop = 10240;
-	let mut num_loop = 0;
-
-	let mut signal = unsafe { librobotcontrol_sys::rc_gpio_get_value(chip as i32, pin) };
-	if signal == signal_err { return Err(format!("Unable to get a signal from GPIO{}_{}", chip as i32, pin)); }
-
-	// Wait for the signal to start
-	while signal != signal_check {
-		num_loop = num_loop + 1;
-		if num_loop > max_loop { return Err(String::from("Timeout while reading GPIO")); }
-		signal = unsafe { librobotcontrol_sys::rc_gpio_get_value(chip as i32, pin) };
-	}
-
-	// Count the duration the signal is in the given state
-	let start = Instant::now();
-	while signal == signal_check {
-		num_loop = num_loop + 1;
-		if num_loop > max_loop { return Err(String::from("Timeout while reading GPIO")); }
-		signal = unsafe { librobotcontrol_sys::rc_gpio_get_value(chip as i32, pin) };
-	}
-	let duration = start.elapsed();
-	Ok(duration)
+
+
+/// IMU
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct ImuData {
+	/// Acceleration in m/s² along the X, Y and Z axis of the board
+	pub accel: [f64; 3],
+	/// Angular velocity in deg/s around the X, Y and Z axis of the board
+	pub gyro: [f64; 3],
+	/// Orientation of the board as quaternion (W, X, Y, Z)
+	pub quaternion: [f64; 4],
+	/// Magnetic field in µT along the X, Y and Z axis of the board
+	pub mag: [f64; 3],
 }
@@ -0,0 +1,258 @@
+extern crate librobotcontrol_sys;
+
+use super::{Backend, Button, Encoder, Led, Motor, Servo, GpioChip, GpioHandle, GpioTrigger, ImuData, BUTTON_DEBOUNCE, MOTOR_PWM};
+use super::super::error::Error;
+
+use std::os::raw::c_int;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Request events for the rising and the falling edge (GPIOEVENT_REQUEST_BOTH_EDGES)
+const GPIOEVENT_REQUEST_BOTH_EDGES: c_int = 3;
+
+/// Return values of `rc_gpio_poll`
+const RC_GPIOEVENT_ERROR: c_int = -1;
+const RC_GPIOEVENT_TIMEOUT: c_int = 0;
+const RC_GPIOEVENT_RISING_EDGE: c_int = 1;
+const RC_GPIOEVENT_FALLING_EDGE: c_int = 2;
+
+/// Sample rate of the DMP in Hz
+pub const IMU_SAMPLE_RATE: ::std::os::raw::c_int = 100;
+
+/// Data of the MPU, librobotcontrol writes into it from its own interrupt thread
+struct MpuData(*mut librobotcontrol_sys::rc_mpu_data_t);
+unsafe impl Send for MpuData {}
+unsafe impl Sync for MpuData {}
+
+static MPU_DATA: OnceLock<MpuData> = OnceLock::new();
+
+/// The encoder read functions return the count, so an error can not be detected from it
+static ENCODERS_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Convert the return code of librobotcontrol into a Result
+///
+/// # Arguments
+///
+/// * `code` - The return code, everything except 0 is a failure
+/// * `error` - Creates the error in case of a failure
+fn check(code: ::std::os::raw::c_int, error: impl FnOnce() -> Error) -> Result<(), Error> {
+	if code == 0 { Ok(()) } else { Err(error()) }
+}
+
+/// Wait for an edge event on a pin, other edges are skipped
+///
+/// # Arguments
+///
+/// * `chip` - The GPIO chip of the pin
+/// * `pin` - The pin on the chip
+/// * `edge` - `RC_GPIOEVENT_RISING_EDGE` or `RC_GPIOEVENT_FALLING_EDGE`
+/// * `deadline` - Give up when the edge did not occur until then
+///
+/// # Returns
+///
+/// The kernel timestamp of the edge in ns
+fn poll_edge(chip: GpioChip, pin: i32, edge: c_int, deadline: Instant) -> Result<u64, Error> {
+	let timeout = || Error::Timeout(format!("No pulse on GPIO{}_{}", chip as i32, pin));
+	loop {
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		if remaining.is_zero() {
+			return Err(timeout());
+		}
+
+		// poll only takes ms, round up so the last fraction of a ms is waited for as well
+		let mut time: u64 = 0;
+		let timeout_ms = remaining.as_micros().div_ceil(1000) as c_int;
+		match unsafe { librobotcontrol_sys::rc_gpio_poll(chip as i32, pin, timeout_ms, &mut time) } {
+			RC_GPIOEVENT_ERROR => return Err(Error::Read(format!("Unable to poll GPIO{}_{}", chip as i32, pin))),
+			RC_GPIOEVENT_TIMEOUT => return Err(timeout()),
+			event if event == edge => return Ok(time),
+			_ => {},
+		}
+	}
+}
+
+/// Backend for the BeagleBone Blue based on librobotcontrol
+#[derive(Default, Copy, Clone)]
+pub struct LibRobotControl;
+
+impl Backend for LibRobotControl {
+	fn cleanup(&self) {
+		unsafe {
+			librobotcontrol_sys::rc_adc_cleanup();
+			librobotcontrol_sys::rc_button_cleanup();
+			librobotcontrol_sys::rc_encoder_eqep_cleanup();
+			librobotcontrol_sys::rc_encoder_pru_cleanup();
+			librobotcontrol_sys::rc_led_cleanup();
+			librobotcontrol_sys::rc_motor_cleanup();
+			librobotcontrol_sys::rc_servo_cleanup();
+
+			librobotcontrol_sys::rc_bmp_power_off();
+			librobotcontrol_sys::rc_mpu_power_off();
+		}
+		ENCODERS_INITIALIZED.store(false, Ordering::Relaxed);
+	}
+
+	fn init_button(&self, button: Button) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_button_init(2, button as i32, 1, BUTTON_DEBOUNCE) },
+			|| Error::Init(format!("Unable to initialize button {:?}", button)))
+	}
+
+	fn register_button_callbacks(&self, button: Button, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_button_set_callbacks(2, button as i32, pressed, released) },
+			|| Error::Init(format!("Unable to register the callback for button {:?}", button)))
+	}
+
+	fn init_encoders(&self) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_encoder_eqep_init() }, || Error::Init(String::from("Unable to initialize the eQEP encoders")))?;
+		check(unsafe { librobotcontrol_sys::rc_encoder_pru_init() }, || Error::Init(String::from("Unable to initialize the PRU encoder")))?;
+		ENCODERS_INITIALIZED.store(true, Ordering::Relaxed);
+		Ok(())
+	}
+
+	fn get_encoder_value(&self, encoder: Encoder) -> Result<i32, Error> {
+		if !ENCODERS_INITIALIZED.load(Ordering::Relaxed) {
+			return Err(Error::Init(format!("Encoders are not initialized, unable to read {:?}", encoder)));
+		}
+		match encoder {
+			Encoder::ENCODER4 => Ok(unsafe { librobotcontrol_sys::rc_encoder_pru_read() }),
+			_ =>  Ok(unsafe { librobotcontrol_sys::rc_encoder_eqep_read(encoder as i32) }),
+		}
+	}
+
+	fn init_motors(&self) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_motor_init_freq(MOTOR_PWM) }, || Error::Init(String::from("Unable to initialize the motors")))?;
+		for motor in [Motor::MOTOR1, Motor::MOTOR2, Motor::MOTOR3, Motor::MOTOR4] {
+			self.brake_motor(motor)?;
+		}
+		Ok(())
+	}
+
+	fn run_motor(&self, motor: Motor, speed: f64) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_motor_set(motor as i32, super::clamp_duty(speed)) },
+			|| Error::Write(format!("Unable to set the duty of {:?}", motor)))
+	}
+
+	fn brake_motor(&self, motor: Motor) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_motor_brake(motor as i32) },
+			|| Error::Write(format!("Unable to brake {:?}", motor)))
+	}
+
+	fn free_spin_motor(&self, motor: Motor) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_motor_free_spin(motor as i32) },
+			|| Error::Write(format!("Unable to free spin {:?}", motor)))
+	}
+
+	fn motor_standby(&self, enable: bool) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_motor_standby(enable as i32) },
+			|| Error::Write(format!("Unable to switch the motor standby to {}", enable)))
+	}
+
+	fn gpio_init(&self, chip: GpioChip, pin: i32, direction: GpioHandle) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_gpio_init(chip as i32, pin, direction as i32) },
+			|| Error::Init(format!("Unable to open GPIO{}_{}", chip as i32, pin)))
+	}
+
+	fn gpio_init_event(&self, chip: GpioChip, pin: i32) -> Result<(), Error> {
+		match unsafe { librobotcontrol_sys::rc_gpio_init_event(chip as i32, pin, 0, GPIOEVENT_REQUEST_BOTH_EDGES) } {
+			-1 => Err(Error::Init(format!("Unable to initialize edge events on GPIO{}_{}", chip as i32, pin))),
+			_ => Ok(()),
+		}
+	}
+
+	fn gpio_cleanup(&self, chip: GpioChip, pin: i32) {
+		unsafe { librobotcontrol_sys::rc_gpio_cleanup(chip as i32, pin); }
+	}
+
+	fn gpio_set_value(&self, chip: GpioChip, pin: i32, value: i32) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_gpio_set_value(chip as i32, pin, value) },
+			|| Error::Write(format!("Unable to set GPIO{}_{} to {}", chip as i32, pin, value)))
+	}
+
+	fn gpio_get_value(&self, chip: GpioChip, pin: i32) -> Result<i32, Error> {
+		match unsafe { librobotcontrol_sys::rc_gpio_get_value(chip as i32, pin) } {
+			-1 => Err(Error::Read(format!("Unable to get a signal from GPIO{}_{}", chip as i32, pin))),
+			value => Ok(value),
+		}
+	}
+
+	fn gpio_read_pulse(&self, chip: GpioChip, pin: i32, value: GpioTrigger, timeout: Duration) -> Result<Duration, Error> {
+		// The kernel timestamps the edges, so a busy control loop does not distort the length
+		let deadline = Instant::now() + timeout;
+		let (start_edge, end_edge) = match value {
+			GpioTrigger::HIGH => (RC_GPIOEVENT_RISING_EDGE, RC_GPIOEVENT_FALLING_EDGE),
+			GpioTrigger::LOW => (RC_GPIOEVENT_FALLING_EDGE, RC_GPIOEVENT_RISING_EDGE),
+		};
+		let start = poll_edge(chip, pin, start_edge, deadline)?;
+		let end = poll_edge(chip, pin, end_edge, deadline)?;
+		Ok(Duration::from_nanos(end.saturating_sub(start)))
+	}
+
+	fn set_led(&self, led: Led, on: bool) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_led_set(led as librobotcontrol_sys::rc_led_t, on as i32) },
+			|| Error::Write(format!("Unable to switch the {:?} LED", led)))
+	}
+
+	fn init_servos(&self) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_servo_init() }, || Error::Init(String::from("Unable to initialize the servos")))
+	}
+
+	fn servo_power_rail(&self, enable: bool) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_servo_power_rail_en(enable as i32) },
+			|| Error::Write(String::from("Unable to switch the servo power rail")))
+	}
+
+	fn servo_send_pulse(&self, servo: Servo, width: i32) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_servo_send_pulse_us(servo as i32, width) },
+			|| Error::Write(format!("Unable to send a pulse to {:?}", servo)))
+	}
+
+	fn init_adc(&self) -> Result<(), Error> {
+		check(unsafe { librobotcontrol_sys::rc_adc_init() }, || Error::Init(String::from("Unable to initialize the ADC")))
+	}
+
+	fn battery_voltage(&self) -> Result<f64, Error> {
+		let voltage = unsafe { librobotcontrol_sys::rc_adc_batt() };
+		if voltage < 0.0 { Err(Error::Read(String::from("Unable to read the battery voltage"))) } else { Ok(voltage) }
+	}
+
+	fn jack_voltage(&self) -> Result<f64, Error> {
+		let voltage = unsafe { librobotcontrol_sys::rc_adc_dc_jack() };
+		if voltage < 0.0 { Err(Error::Read(String::from("Unable to read the DC jack voltage"))) } else { Ok(voltage) }
+	}
+
+	fn init_imu(&self) -> Result<(), Error> {
+		if MPU_DATA.get().is_some() {
+			return Ok(());
+		}
+
+		// The data has to live as long as the DMP interrupt thread is running
+		let data = Box::into_raw(Box::new(unsafe { std::mem::zeroed::<librobotcontrol_sys::rc_mpu_data_t>() }));
+		let mut conf = unsafe { librobotcontrol_sys::rc_mpu_default_config() };
+		conf.dmp_sample_rate = IMU_SAMPLE_RATE;
+		conf.dmp_fetch_accel_gyro = 1;
+		conf.enable_magnetometer = 1;
+
+		if unsafe { librobotcontrol_sys::rc_mpu_initialize_dmp(data, conf) } != 0 {
+			drop(unsafe { Box::from_raw(data) });
+			return Err(Error::Init(String::from("Unable to initialize the MPU")));
+		}
+		let _ = MPU_DATA.set(MpuData(data));
+		Ok(())
+	}
+
+	fn read_imu(&self) -> Result<ImuData, Error> {
+		match MPU_DATA.get() {
+			Some(data) => {
+				let data = unsafe { std::ptr::read_volatile(data.0) };
+				Ok(ImuData {
+					accel: data.accel,
+					gyro: data.gyro,
+					quaternion: data.dmp_quat,
+					mag: data.mag,
+				})
+			},
+			None => Err(Error::Init(String::from("MPU is not initialized"))),
+		}
+	}
+}
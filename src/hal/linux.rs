@@ -0,0 +1,696 @@
+extern crate gpio_cdev;
+
+use super::{Backend, Button, Encoder, Motor, GpioChip, GpioHandle, GpioTrigger, BUTTON_DEBOUNCE};
+use super::super::config::Config;
+use super::super::error::Error;
+
+use gpio_cdev::{Chip, EventRequestFlags, EventType, LineEventHandle, LineHandle, LineRequestFlags};
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the GPIO character devices are
+const GPIO_DEV: &str = "/dev";
+
+/// Name the GPIO lines are requested with
+const CONSUMER: &str = "robot_diff_drive";
+
+/// Period of the motor PWM in ns (25kHz)
+pub const PWM_PERIOD: u32 = 40000;
+
+/// The encoder and button threads check this often if they have to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Change of the count for a transition of the quadrature signal, indexed by (previous AB << 2 | next AB)
+const QUADRATURE: [i32; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+/// A H-bridge driven by a sysfs PWM for the speed and two sysfs GPIOs for the direction
+///
+/// Both direction pins high brake the motor and both low let it spin freely, like on a TB6612 or L298N.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HBridge {
+	/// The PWM chip, /sys/class/pwm/pwmchipX
+	pub pwm_chip: u32,
+	/// The channel on the PWM chip
+	pub pwm_channel: u32,
+	/// GPIO number of the first direction pin, high to drive forward
+	pub in1: u32,
+	/// GPIO number of the second direction pin, high to drive backward
+	pub in2: u32,
+}
+
+impl FromStr for HBridge {
+	type Err = Error;
+
+	/// Parse `pwm_chip,pwm_channel,in1,in2`
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		match numbers(value).as_deref() {
+			Some(&[pwm_chip, pwm_channel, in1, in2]) => Ok(HBridge { pwm_chip, pwm_channel, in1, in2 }),
+			_ => Err(Error::Io(format!("Invalid H-bridge {}, expected pwm_chip,pwm_channel,in1,in2", value))),
+		}
+	}
+}
+
+/// A quadrature encoder with its A and B signal on two lines of a GPIO chip
+///
+/// Every edge of both signals is counted, so the resolution is 4 times the number of lines of the encoder
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Quadrature {
+	pub chip: GpioChip,
+	pub a: u32,
+	pub b: u32,
+}
+
+impl FromStr for Quadrature {
+	type Err = Error;
+
+	/// Parse `chip,a,b`
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		match numbers(value).as_deref() {
+			Some(&[chip, a, b]) => Ok(Quadrature { chip: gpio_chip(chip).ok_or_else(|| Error::Io(format!("Invalid GPIO chip {}", chip)))?, a, b }),
+			_ => Err(Error::Io(format!("Invalid encoder {}, expected chip,a,b", value))),
+		}
+	}
+}
+
+/// Parse a comma separated list of numbers, None if one is invalid
+fn numbers(value: &str) -> Option<Vec<u32>> {
+	value.split(',').map(|number| number.trim().parse().ok()).collect()
+}
+
+fn gpio_chip(chip: u32) -> Option<GpioChip> {
+	[GpioChip::GPIO0, GpioChip::GPIO1, GpioChip::GPIO2, GpioChip::GPIO3].into_iter().find(|gpio| *gpio as u32 == chip)
+}
+
+/// A GPIO line requested from the character device
+enum Line {
+	Output(LineHandle),
+	Input(LineHandle),
+	/// Shared, so a pulse can be waited for without holding the state
+	Events(Arc<Mutex<LineEventHandle>>),
+}
+
+#[derive(Default)]
+struct State {
+	lines: HashMap<(GpioChip, i32), Line>,
+	buttons: HashMap<Button, LineEventHandle>,
+	counts: HashMap<Encoder, Arc<AtomicI32>>,
+	encoders_stop: Arc<AtomicBool>,
+	buttons_stop: Arc<AtomicBool>,
+	motors_initialized: bool,
+}
+
+/// Create a backend for a generic Linux board like the Raspberry Pi
+///
+/// # Arguments
+///
+/// * `sysfs` - Where sysfs is mounted, `/sys` on a real board or a directory tree for testing
+pub fn new<P: AsRef<Path>>(sysfs: P) -> Linux {
+	Linux {
+		sysfs: sysfs.as_ref().to_path_buf(),
+		bridges: HashMap::new(),
+		standby: None,
+		encoders: HashMap::new(),
+		button_lines: HashMap::new(),
+		state: Mutex::new(State::default()),
+	}
+}
+
+/// Create a backend for a Linux board with the pins from a configuration
+///
+/// `linux.motorN = pwm_chip,pwm_channel,in1,in2`, `linux.encoderN = chip,a,b`, `linux.standby = gpio`,
+/// `linux.pause = chip,line` and `linux.mode = chip,line`. Without them the two wheels and the buttons
+/// are wired to a Raspberry Pi like this:
+///
+/// * MOTOR3 (left): PWM0 (GPIO12), GPIO5 and GPIO6, ENCODER3: GPIO17 and GPIO27
+/// * MOTOR2 (right): PWM1 (GPIO13), GPIO20 and GPIO21, ENCODER2: GPIO22 and GPIO23
+/// * Standby of the drivers: GPIO16, Pause: GPIO24, Mode: GPIO25
+///
+/// # Arguments
+///
+/// * `sysfs` - Where sysfs is mounted, `/sys` on a real board
+/// * `config` - The configuration with the pins
+pub fn from_config<P: AsRef<Path>>(sysfs: P, config: &Config) -> Linux {
+	let mut linux = new(sysfs);
+	let motors = [
+		(Motor::MOTOR1, Encoder::ENCODER1, None),
+		(Motor::MOTOR2, Encoder::ENCODER2, Some((HBridge { pwm_chip: 0, pwm_channel: 1, in1: 20, in2: 21 }, Quadrature { chip: GpioChip::GPIO0, a: 22, b: 23 }))),
+		(Motor::MOTOR3, Encoder::ENCODER3, Some((HBridge { pwm_chip: 0, pwm_channel: 0, in1: 5, in2: 6 }, Quadrature { chip: GpioChip::GPIO0, a: 17, b: 27 }))),
+		(Motor::MOTOR4, Encoder::ENCODER4, None),
+	];
+	for (index, (motor, encoder, default)) in motors.into_iter().enumerate() {
+		let bridge = config.get(&format!("linux.motor{}", index + 1)).or(default.map(|default| default.0));
+		let pins = config.get(&format!("linux.encoder{}", index + 1)).or(default.map(|default| default.1));
+		if let Some(bridge) = bridge {
+			linux.add_motor(motor, bridge);
+		}
+		if let Some(pins) = pins {
+			linux.add_encoder(encoder, pins);
+		}
+	}
+	linux.set_standby_pin(config.get_or("linux.standby", 16));
+	for (button, key, default) in [(Button::Pause, "linux.pause", 24), (Button::Mode, "linux.mode", 25)] {
+		let (chip, line) = config.get::<String>(key).as_deref().and_then(numbers)
+			.and_then(|numbers| match numbers.as_slice() {
+				&[chip, line] => Some((gpio_chip(chip)?, line)),
+				_ => None,
+			})
+			.unwrap_or((GpioChip::GPIO0, default));
+		linux.add_button(button, chip, line);
+	}
+	linux
+}
+
+/// Backend for Linux boards, the H-bridges are driven through sysfs and the inputs are read from the GPIO character device
+///
+/// The pins have to be added before the backend is shared with the robot.
+pub struct Linux {
+	sysfs: PathBuf,
+	bridges: HashMap<Motor, HBridge>,
+	standby: Option<u32>,
+	encoders: HashMap<Encoder, Quadrature>,
+	button_lines: HashMap<Button, (GpioChip, u32)>,
+	state: Mutex<State>,
+}
+
+impl Linux {
+	/// Drive a motor with a H-bridge
+	pub fn add_motor(&mut self, motor: Motor, bridge: HBridge) {
+		self.bridges.insert(motor, bridge);
+	}
+
+	/// Set the GPIO number of the standby pin of the motor drivers, it is low in standby
+	pub fn set_standby_pin(&mut self, gpio: u32) {
+		self.standby = Some(gpio);
+	}
+
+	/// Read an encoder from two GPIO lines
+	pub fn add_encoder(&mut self, encoder: Encoder, pins: Quadrature) {
+		self.encoders.insert(encoder, pins);
+	}
+
+	/// Read a button from a GPIO line, the button connects the line to ground when pressed
+	pub fn add_button(&mut self, button: Button, chip: GpioChip, line: u32) {
+		self.button_lines.insert(button, (chip, line));
+	}
+
+	fn state(&self) -> MutexGuard<'_, State> {
+		self.state.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	fn chip(&self, chip: GpioChip) -> Result<Chip, Error> {
+		let path = Path::new(GPIO_DEV).join(format!("gpiochip{}", chip as i32));
+		Chip::new(&path).map_err(|err| Error::Init(format!("Unable to open {}: {}", path.display(), err)))
+	}
+
+	/// Request a line for edge events on both edges
+	fn events(&self, chip: GpioChip, line: u32, flags: LineRequestFlags) -> Result<LineEventHandle, Error> {
+		self.chip(chip)?
+			.get_line(line)
+			.and_then(|line| line.events(flags, EventRequestFlags::BOTH_EDGES, CONSUMER))
+			.map_err(|err| Error::Init(format!("Unable to request edge events on GPIO{}_{}: {}", chip as i32, line, err)))
+	}
+
+	/// Export a sysfs GPIO as output
+	fn export_gpio(&self, gpio: u32) -> Result<(), Error> {
+		let class = self.sysfs.join("class/gpio");
+		if !class.join(format!("gpio{}", gpio)).exists() {
+			write(&class.join("export"), gpio)?;
+		}
+		write(&class.join(format!("gpio{}/direction", gpio)), "out")
+	}
+
+	fn set_gpio(&self, gpio: u32, value: bool) -> Result<(), Error> {
+		write(&self.sysfs.join(format!("class/gpio/gpio{}/value", gpio)), value as i32)
+	}
+
+	/// Export a sysfs PWM and enable it with a duty of 0
+	fn export_pwm(&self, bridge: &HBridge) -> Result<(), Error> {
+		let chip = self.sysfs.join(format!("class/pwm/pwmchip{}", bridge.pwm_chip));
+		let channel = chip.join(format!("pwm{}", bridge.pwm_channel));
+		if !channel.exists() {
+			write(&chip.join("export"), bridge.pwm_channel)?;
+		}
+		write(&channel.join("duty_cycle"), 0)?;
+		write(&channel.join("period"), PWM_PERIOD)?;
+		write(&channel.join("enable"), 1)
+	}
+
+	fn set_pwm(&self, bridge: &HBridge, duty: f64) -> Result<(), Error> {
+		let path = self.sysfs.join(format!("class/pwm/pwmchip{}/pwm{}/duty_cycle", bridge.pwm_chip, bridge.pwm_channel));
+		write(&path, (duty.abs().min(1.0) * PWM_PERIOD as f64).round() as u32)
+	}
+
+	/// Set the direction pins and the duty of a H-bridge
+	fn drive(&self, motor: Motor, in1: bool, in2: bool, duty: f64) -> Result<(), Error> {
+		if !self.state().motors_initialized {
+			return Err(Error::Init(format!("Motors are not initialized, unable to drive {:?}", motor)));
+		}
+		let bridge = self.bridges.get(&motor)
+			.ok_or_else(|| Error::InvalidChannel(format!("No H-bridge for {:?}", motor)))?;
+		self.set_gpio(bridge.in1, in1)?;
+		self.set_gpio(bridge.in2, in2)?;
+		self.set_pwm(bridge, duty)
+	}
+}
+
+/// Write a value into a sysfs file
+fn write<T: Display>(path: &Path, value: T) -> Result<(), Error> {
+	fs::write(path, value.to_string()).map_err(|err| Error::Write(format!("Unable to write {}: {}", path.display(), err)))
+}
+
+/// Wait until one of the files can be read
+///
+/// # Returns
+///
+/// The indices of the readable files, empty after the timeout
+fn poll(fds: &[RawFd], timeout: Duration) -> Result<Vec<usize>, Error> {
+	let mut poll_fds: Vec<libc::pollfd> = fds.iter()
+		.map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN | libc::POLLPRI, revents: 0 })
+		.collect();
+	let timeout_ms = timeout.as_micros().div_ceil(1000) as libc::c_int;
+	match unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout_ms) } {
+		-1 => Err(Error::Read(format!("Unable to poll GPIO events: {}", std::io::Error::last_os_error()))),
+		_ => Ok(poll_fds.iter().enumerate().filter(|(_, fd)| fd.revents != 0).map(|(index, _)| index).collect()),
+	}
+}
+
+/// An edge of the A or B signal of a quadrature encoder
+#[derive(Copy, Clone, Debug)]
+struct Edge {
+	/// Time of the edge in ns as the kernel stamped it
+	timestamp: u64,
+	/// Bit of the signal in the state, 1 for A and 0 for B
+	bit: usize,
+	/// Level of the signal after the edge
+	value: usize,
+}
+
+/// Sort the edges of both signals by their time and decode them
+///
+/// # Arguments
+///
+/// * `state` - The levels of the signals before the edges (A << 1 | B), updated to the levels after them
+/// * `edges` - The edges of both signals
+///
+/// # Returns
+///
+/// The change of the count
+fn decode(state: &mut usize, edges: &mut [Edge]) -> i32 {
+	edges.sort_by_key(|edge| edge.timestamp);
+	edges.iter().map(|edge| {
+		let next = (*state & !(1 << edge.bit)) | (edge.value << edge.bit);
+		let change = QUADRATURE[*state << 2 | next];
+		*state = next;
+		change
+	}).sum()
+}
+
+/// Count the edges of a quadrature encoder until it is stopped
+fn count_edges(mut a: LineEventHandle, mut b: LineEventHandle, count: Arc<AtomicI32>, stop: Arc<AtomicBool>) {
+	let mut state = (a.get_value().unwrap_or(0) << 1 | b.get_value().unwrap_or(0)) as usize;
+	let fds = [a.as_raw_fd(), b.as_raw_fd()];
+	while !stop.load(Ordering::Relaxed) {
+		let mut ready = match poll(&fds, POLL_INTERVAL) {
+			Ok(ready) => ready,
+			Err(err) => { println!("ERROR: {}", err); break; },
+		};
+
+		// At speed both lines have several edges queued, all of them are read before they are decoded in the order they happened
+		let mut edges = vec!();
+		while !ready.is_empty() {
+			for index in ready {
+				let (handle, bit) = match index {
+					0 => (&mut a, 1),
+					_ => (&mut b, 0),
+				};
+				match handle.get_event() {
+					Ok(event) => edges.push(Edge {
+						timestamp: event.timestamp(),
+						bit,
+						value: match event.event_type() {
+							EventType::RisingEdge => 1,
+							EventType::FallingEdge => 0,
+						},
+					}),
+					Err(err) => println!("ERROR: Unable to read an encoder edge: {}", err),
+				}
+			}
+			ready = match poll(&fds, Duration::ZERO) {
+				Ok(ready) => ready,
+				Err(err) => { println!("ERROR: {}", err); vec!() },
+			};
+		}
+		count.fetch_add(decode(&mut state, &mut edges), Ordering::Relaxed);
+	}
+}
+
+/// Call the callbacks of a button on its debounced edges until it is stopped
+fn watch_button(mut line: LineEventHandle, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>, stop: Arc<AtomicBool>) {
+	let debounce = Duration::from_micros(BUTTON_DEBOUNCE as u64).as_nanos() as u64;
+	let mut last = 0;
+	while !stop.load(Ordering::Relaxed) {
+		match poll(&[line.as_raw_fd()], POLL_INTERVAL) {
+			Ok(ready) if ready.is_empty() => continue,
+			Ok(_) => {},
+			Err(err) => { println!("ERROR: {}", err); break; },
+		}
+		let event = match line.get_event() {
+			Ok(event) => event,
+			Err(err) => { println!("ERROR: Unable to read a button edge: {}", err); continue; },
+		};
+		if event.timestamp().saturating_sub(last) < debounce {
+			continue;
+		}
+		last = event.timestamp();
+
+		// The line is active low, so pressing is a falling edge
+		let callback = match event.event_type() {
+			EventType::FallingEdge => pressed,
+			EventType::RisingEdge => released,
+		};
+		if let Some(callback) = callback {
+			unsafe { callback(); }
+		}
+	}
+}
+
+impl Backend for Linux {
+	fn cleanup(&self) {
+		let mut state = self.state();
+		state.encoders_stop.store(true, Ordering::Relaxed);
+		state.buttons_stop.store(true, Ordering::Relaxed);
+		state.buttons_stop = Arc::new(AtomicBool::new(false));
+		state.lines.clear();
+		state.buttons.clear();
+		state.counts.clear();
+
+		// Let the motors spin freely and put the drivers into standby
+		if state.motors_initialized {
+			for bridge in self.bridges.values() {
+				let _ = self.set_gpio(bridge.in1, false);
+				let _ = self.set_gpio(bridge.in2, false);
+				let _ = self.set_pwm(bridge, 0.0);
+				let _ = write(&self.sysfs.join(format!("class/pwm/pwmchip{}/pwm{}/enable", bridge.pwm_chip, bridge.pwm_channel)), 0);
+			}
+			if let Some(gpio) = self.standby {
+				let _ = self.set_gpio(gpio, false);
+			}
+		}
+		state.motors_initialized = false;
+	}
+
+	fn init_button(&self, button: Button) -> Result<(), Error> {
+		let (chip, line) = *self.button_lines.get(&button)
+			.ok_or_else(|| Error::InvalidChannel(format!("No GPIO line for button {:?}", button)))?;
+		let handle = self.events(chip, line, LineRequestFlags::INPUT)?;
+		self.state().buttons.insert(button, handle);
+		Ok(())
+	}
+
+	fn register_button_callbacks(&self, button: Button, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>) -> Result<(), Error> {
+		let mut state = self.state();
+		let line = state.buttons.remove(&button)
+			.ok_or_else(|| Error::Init(format!("Button {:?} is not initialized", button)))?;
+		let stop = state.buttons_stop.clone();
+		thread::spawn(move || watch_button(line, pressed, released, stop));
+		Ok(())
+	}
+
+	fn init_encoders(&self) -> Result<(), Error> {
+		let mut state = self.state();
+		state.encoders_stop.store(true, Ordering::Relaxed);
+		state.encoders_stop = Arc::new(AtomicBool::new(false));
+		state.counts.clear();
+
+		for (encoder, pins) in self.encoders.iter() {
+			let a = self.events(pins.chip, pins.a, LineRequestFlags::INPUT)?;
+			let b = self.events(pins.chip, pins.b, LineRequestFlags::INPUT)?;
+			let count = Arc::new(AtomicI32::new(0));
+			state.counts.insert(*encoder, count.clone());
+
+			let stop = state.encoders_stop.clone();
+			thread::spawn(move || count_edges(a, b, count, stop));
+		}
+		Ok(())
+	}
+
+	fn get_encoder_value(&self, encoder: Encoder) -> Result<i32, Error> {
+		match self.state().counts.get(&encoder) {
+			Some(count) => Ok(count.load(Ordering::Relaxed)),
+			None => Err(Error::Init(format!("{:?} is not initialized", encoder))),
+		}
+	}
+
+	fn init_motors(&self) -> Result<(), Error> {
+		if let Some(gpio) = self.standby {
+			self.export_gpio(gpio)?;
+			self.set_gpio(gpio, true)?;
+		}
+		for bridge in self.bridges.values() {
+			self.export_gpio(bridge.in1)?;
+			self.export_gpio(bridge.in2)?;
+			self.export_pwm(bridge)?;
+		}
+		self.state().motors_initialized = true;
+
+		for motor in self.bridges.keys() {
+			self.brake_motor(*motor)?;
+		}
+		Ok(())
+	}
+
+	fn run_motor(&self, motor: Motor, speed: f64) -> Result<(), Error> {
+		let duty = super::clamp_duty(speed);
+		self.drive(motor, duty >= 0.0, duty < 0.0, duty)
+	}
+
+	fn brake_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.drive(motor, true, true, 1.0)
+	}
+
+	fn free_spin_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.drive(motor, false, false, 0.0)
+	}
+
+	fn motor_standby(&self, enable: bool) -> Result<(), Error> {
+		let gpio = self.standby.ok_or_else(|| Error::NotSupported(String::from("No standby pin for the motor drivers")))?;
+		if !self.state().motors_initialized {
+			return Err(Error::Init(String::from("Motors are not initialized, unable to switch the standby")));
+		}
+		if enable {
+			for motor in self.bridges.keys() {
+				self.free_spin_motor(*motor)?;
+			}
+		}
+		self.set_gpio(gpio, !enable)
+	}
+
+	fn gpio_init(&self, chip: GpioChip, pin: i32, direction: GpioHandle) -> Result<(), Error> {
+		let mut flags = LineRequestFlags::from_bits_truncate(direction as u32);
+		if !flags.contains(LineRequestFlags::OUTPUT) {
+			flags.insert(LineRequestFlags::INPUT);
+		}
+		let handle = self.chip(chip)?
+			.get_line(pin as u32)
+			.and_then(|line| line.request(flags, 0, CONSUMER))
+			.map_err(|err| Error::Init(format!("Unable to request GPIO{}_{}: {}", chip as i32, pin, err)))?;
+		let line = match flags.contains(LineRequestFlags::OUTPUT) {
+			true => Line::Output(handle),
+			false => Line::Input(handle),
+		};
+		self.state().lines.insert((chip, pin), line);
+		Ok(())
+	}
+
+	fn gpio_init_event(&self, chip: GpioChip, pin: i32) -> Result<(), Error> {
+		let handle = self.events(chip, pin as u32, LineRequestFlags::INPUT)?;
+		self.state().lines.insert((chip, pin), Line::Events(Arc::new(Mutex::new(handle))));
+		Ok(())
+	}
+
+	fn gpio_cleanup(&self, chip: GpioChip, pin: i32) {
+		self.state().lines.remove(&(chip, pin));
+	}
+
+	fn gpio_set_value(&self, chip: GpioChip, pin: i32, value: i32) -> Result<(), Error> {
+		match self.state().lines.get(&(chip, pin)) {
+			Some(Line::Output(handle)) => handle.set_value(value as u8)
+				.map_err(|err| Error::Write(format!("Unable to set GPIO{}_{}: {}", chip as i32, pin, err))),
+			_ => Err(Error::Write(format!("GPIO{}_{} is not initialized as output", chip as i32, pin))),
+		}
+	}
+
+	fn gpio_get_value(&self, chip: GpioChip, pin: i32) -> Result<i32, Error> {
+		let value = match self.state().lines.get(&(chip, pin)) {
+			Some(Line::Output(handle)) | Some(Line::Input(handle)) => handle.get_value(),
+			Some(Line::Events(handle)) => handle.lock().unwrap_or_else(|err| err.into_inner()).get_value(),
+			None => return Err(Error::Read(format!("GPIO{}_{} is not initialized", chip as i32, pin))),
+		};
+		value.map(i32::from).map_err(|err| Error::Read(format!("Unable to get a signal from GPIO{}_{}: {}", chip as i32, pin, err)))
+	}
+
+	fn gpio_read_pulse(&self, chip: GpioChip, pin: i32, value: GpioTrigger, timeout: Duration) -> Result<Duration, Error> {
+		let deadline = Instant::now() + timeout;
+		let (start_edge, end_edge) = match value {
+			GpioTrigger::HIGH => (EventType::RisingEdge, EventType::FallingEdge),
+			GpioTrigger::LOW => (EventType::FallingEdge, EventType::RisingEdge),
+		};
+
+		// The other lines, the encoders and the motors must not wait for the pulse
+		let handle = match self.state().lines.get(&(chip, pin)) {
+			Some(Line::Events(handle)) => handle.clone(),
+			_ => return Err(Error::Read(format!("GPIO{}_{} is not initialized for edge events", chip as i32, pin))),
+		};
+		let mut handle = handle.lock().unwrap_or_else(|err| err.into_inner());
+
+		// Wait for an edge and return its kernel timestamp, other edges are skipped
+		let mut poll_edge = |edge: EventType| -> Result<u64, Error> {
+			loop {
+				let remaining = deadline.saturating_duration_since(Instant::now());
+				if remaining.is_zero() || poll(&[handle.as_raw_fd()], remaining)?.is_empty() {
+					return Err(Error::Timeout(format!("No pulse on GPIO{}_{}", chip as i32, pin)));
+				}
+				let event = handle.get_event()
+					.map_err(|err| Error::Read(format!("Unable to read an edge of GPIO{}_{}: {}", chip as i32, pin, err)))?;
+				if event.event_type() == edge {
+					return Ok(event.timestamp());
+				}
+			}
+		};
+		let start = poll_edge(start_edge)?;
+		let end = poll_edge(end_edge)?;
+		Ok(Duration::from_nanos(end.saturating_sub(start)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Create a fake sysfs tree with the exported PWM channel and GPIOs of one H-bridge
+	fn sysfs(name: &str) -> PathBuf {
+		let root = std::env::temp_dir().join(format!("robot_diff_drive_{}_{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(root.join("class/pwm/pwmchip0/pwm1")).unwrap();
+		for gpio in [5, 6, 7] {
+			fs::create_dir_all(root.join(format!("class/gpio/gpio{}", gpio))).unwrap();
+		}
+		root
+	}
+
+	fn read(root: &Path, file: &str) -> String {
+		fs::read_to_string(root.join(file)).unwrap()
+	}
+
+	fn backend(root: &Path) -> Linux {
+		let mut linux = new(root);
+		linux.add_motor(Motor::MOTOR1, HBridge { pwm_chip: 0, pwm_channel: 1, in1: 5, in2: 6 });
+		linux.set_standby_pin(7);
+		linux
+	}
+
+	/// The edges of a signal which runs `cycles` times through the quadrature states, an edge every µs
+	fn quadrature(states: [usize; 4], cycles: usize) -> (Vec<Edge>, Vec<Edge>) {
+		let (mut a, mut b) = (vec!(), vec!());
+		let mut state = states[0];
+		for (index, next) in states.iter().cycle().skip(1).take(cycles * 4).enumerate() {
+			let timestamp = index as u64 * 1000;
+			match state ^ next {
+				0b10 => a.push(Edge { timestamp, bit: 1, value: next >> 1 }),
+				_ => b.push(Edge { timestamp, bit: 0, value: next & 1 }),
+			}
+			state = *next;
+		}
+		(a, b)
+	}
+
+	#[test]
+	fn decodes_interleaved_edges() {
+		// Forward the state runs through 00, 01, 11, 10
+		for (states, direction) in [([0b00, 0b01, 0b11, 0b10], 1), ([0b00, 0b10, 0b11, 0b01], -1)] {
+			let (a, b) = quadrature(states, 25);
+
+			// All edges of A are read before the ones of B, they are counted in the order they happened
+			let mut edges = [a.clone(), b.clone()].concat();
+			let mut state = 0b00;
+			assert_eq!(decode(&mut state, &mut edges), direction * 100);
+			assert_eq!(state, 0b00);
+
+			// Read in small batches the count is the same
+			let mut state = 0b00;
+			let batches: Vec<Vec<Edge>> = a.chunks(5).zip(b.chunks(5)).map(|(a, b)| [b, a].concat()).collect();
+			let count: i32 = batches.into_iter().map(|mut batch| decode(&mut state, &mut batch)).sum();
+			assert_eq!(count, direction * 100);
+		}
+	}
+
+	#[test]
+	fn configures_the_pins() {
+		let linux = from_config("/sys", &Config::default());
+		assert_eq!(linux.bridges.get(&Motor::MOTOR3), Some(&HBridge { pwm_chip: 0, pwm_channel: 0, in1: 5, in2: 6 }));
+		assert_eq!(linux.encoders.get(&Encoder::ENCODER2), Some(&Quadrature { chip: GpioChip::GPIO0, a: 22, b: 23 }));
+		assert!(!linux.bridges.contains_key(&Motor::MOTOR1));
+
+		let config = Config::parse("linux.motor1 = 1, 0, 7, 8\nlinux.encoder1 = 2,3,4\nlinux.encoder2 = 0,1\nlinux.standby = 9\nlinux.mode = 1,11");
+		let linux = from_config("/sys", &config);
+		assert_eq!(linux.bridges.get(&Motor::MOTOR1), Some(&HBridge { pwm_chip: 1, pwm_channel: 0, in1: 7, in2: 8 }));
+		assert_eq!(linux.encoders.get(&Encoder::ENCODER1), Some(&Quadrature { chip: GpioChip::GPIO2, a: 3, b: 4 }));
+		assert_eq!(linux.encoders.get(&Encoder::ENCODER2), Some(&Quadrature { chip: GpioChip::GPIO0, a: 22, b: 23 }), "An invalid encoder keeps the default");
+		assert_eq!(linux.standby, Some(9));
+		assert_eq!(linux.button_lines.get(&Button::Mode), Some(&(GpioChip::GPIO1, 11)));
+		assert_eq!(linux.button_lines.get(&Button::Pause), Some(&(GpioChip::GPIO0, 24)));
+	}
+
+	#[test]
+	fn drives_the_h_bridge() {
+		let root = sysfs("drive");
+		let linux = backend(&root);
+		assert!(linux.run_motor(Motor::MOTOR1, 0.5).is_err());
+
+		linux.init_motors().unwrap();
+		assert_eq!(read(&root, "class/pwm/pwmchip0/pwm1/period"), "40000");
+		assert_eq!(read(&root, "class/pwm/pwmchip0/pwm1/enable"), "1");
+		assert_eq!(read(&root, "class/gpio/gpio5/direction"), "out");
+		assert_eq!(read(&root, "class/gpio/gpio7/value"), "1");
+
+		linux.run_motor(Motor::MOTOR1, 0.5).unwrap();
+		assert_eq!(read(&root, "class/pwm/pwmchip0/pwm1/duty_cycle"), "20000");
+		assert_eq!((read(&root, "class/gpio/gpio5/value"), read(&root, "class/gpio/gpio6/value")), ("1".into(), "0".into()));
+
+		linux.run_motor(Motor::MOTOR1, -2.0).unwrap();
+		assert_eq!(read(&root, "class/pwm/pwmchip0/pwm1/duty_cycle"), "40000");
+		assert_eq!((read(&root, "class/gpio/gpio5/value"), read(&root, "class/gpio/gpio6/value")), ("0".into(), "1".into()));
+
+		assert!(matches!(linux.run_motor(Motor::MOTOR2, 0.5), Err(Error::InvalidChannel(_))));
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn stops_the_h_bridge() {
+		let root = sysfs("stop");
+		let linux = backend(&root);
+		linux.init_motors().unwrap();
+
+		linux.brake_motor(Motor::MOTOR1).unwrap();
+		assert_eq!((read(&root, "class/gpio/gpio5/value"), read(&root, "class/gpio/gpio6/value")), ("1".into(), "1".into()));
+
+		linux.run_motor(Motor::MOTOR1, 0.5).unwrap();
+		linux.motor_standby(true).unwrap();
+		assert_eq!(read(&root, "class/pwm/pwmchip0/pwm1/duty_cycle"), "0");
+		assert_eq!((read(&root, "class/gpio/gpio5/value"), read(&root, "class/gpio/gpio6/value")), ("0".into(), "0".into()));
+		assert_eq!(read(&root, "class/gpio/gpio7/value"), "0");
+
+		linux.cleanup();
+		assert_eq!(read(&root, "class/pwm/pwmchip0/pwm1/enable"), "0");
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
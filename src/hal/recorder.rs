@@ -0,0 +1,406 @@
+use super::{Backend, Button, Encoder, Led, Motor, Servo, GpioChip, GpioHandle, GpioTrigger, ImuData};
+use super::simulated::MotorState;
+use super::super::error::Error;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The backends call plain C functions without any context, so the recording of the button
+/// callbacks goes through this log and the callbacks registered by the robot
+static BUTTON_LOG: Mutex<Option<Arc<Log>>> = Mutex::new(None);
+static BUTTON_CALLBACKS: Mutex<Vec<(Button, bool, unsafe extern "C" fn())>> = Mutex::new(Vec::new());
+
+/// Record an edge of a button and pass it on to the callback registered by the robot
+fn button_edge(button: Button, pressed: bool) {
+	if let Some(log) = BUTTON_LOG.lock().unwrap_or_else(|err| err.into_inner()).as_ref() {
+		log.write(&Record::Button(button, pressed));
+	}
+	let callback = BUTTON_CALLBACKS.lock().unwrap_or_else(|err| err.into_inner()).iter()
+		.find(|(b, p, _)| *b == button && *p == pressed)
+		.map(|(_, _, callback)| *callback);
+	if let Some(callback) = callback {
+		unsafe { callback(); }
+	}
+}
+
+extern "C" fn pause_pressed() { button_edge(Button::Pause, true); }
+extern "C" fn pause_released() { button_edge(Button::Pause, false); }
+extern "C" fn mode_pressed() { button_edge(Button::Mode, true); }
+extern "C" fn mode_released() { button_edge(Button::Mode, false); }
+
+/// A value returned by the backend or a command sent to it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+	/// The count read from an encoder
+	Encoder(Encoder, Result<i32, Error>),
+	/// The length of a pulse read from a pin
+	Pulse(GpioChip, i32, Result<Duration, Error>),
+	/// A button was pressed (true) or released (false)
+	Button(Button, bool),
+	/// A command sent to a motor and its result
+	Motor(Motor, MotorState, Result<(), Error>),
+	/// The motor drivers were put into standby (true) or woken up (false)
+	Standby(bool, Result<(), Error>),
+	/// The values read from the IMU
+	Imu(Result<ImuData, Error>),
+	/// The voltage of the battery pack in V
+	Voltage(Result<f64, Error>),
+}
+
+impl fmt::Display for Record {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Record::Encoder(encoder, result) => write!(f, "encoder {:?} {}", encoder, format_result(result, |value| value.to_string())),
+			Record::Pulse(chip, pin, result) => write!(f, "pulse {:?} {} {}", chip, pin, format_result(result, |value| value.as_nanos().to_string())),
+			Record::Button(button, pressed) => write!(f, "button {:?} {}", button, if *pressed { "pressed" } else { "released" }),
+			Record::Motor(motor, command, result) => {
+				let command = match command {
+					MotorState::Running(duty) => format!("run {}", duty),
+					MotorState::Braked => String::from("brake"),
+					MotorState::FreeSpin => String::from("free"),
+				};
+				write!(f, "motor {:?} {} {}", motor, command, format_result(result, |_| String::new()).trim_end())
+			},
+			Record::Standby(enable, result) => write!(f, "standby {} {}", enable, format_result(result, |_| String::new()).trim_end()),
+			Record::Imu(result) => write!(f, "imu {}", format_result(result, |data| {
+				let values: Vec<String> = data.accel.iter().chain(&data.gyro).chain(&data.quaternion).chain(&data.mag).map(f64::to_string).collect();
+				values.join(" ")
+			})),
+			Record::Voltage(result) => write!(f, "voltage {}", format_result(result, |value| value.to_string())),
+		}
+	}
+}
+
+impl FromStr for Record {
+	type Err = Error;
+
+	fn from_str(line: &str) -> Result<Self, Self::Err> {
+		let encoders = [Encoder::ENCODER1, Encoder::ENCODER2, Encoder::ENCODER3, Encoder::ENCODER4];
+		let motors = [Motor::MOTOR1, Motor::MOTOR2, Motor::MOTOR3, Motor::MOTOR4];
+		let chips = [GpioChip::GPIO0, GpioChip::GPIO1, GpioChip::GPIO2, GpioChip::GPIO3];
+
+		let fields: Vec<&str> = line.split(' ').collect();
+		let record = || -> Option<Record> {
+			Some(match fields.as_slice() {
+				["encoder", encoder, result @ ..] => Record::Encoder(
+					variant(&encoders, encoder)?,
+					parse_result(result, |value| value.first()?.parse().ok())?,
+				),
+				["pulse", chip, pin, result @ ..] => Record::Pulse(
+					variant(&chips, chip)?,
+					pin.parse().ok()?,
+					parse_result(result, |value| value.first()?.parse().ok().map(Duration::from_nanos))?,
+				),
+				["button", button, "pressed"] => Record::Button(variant(&[Button::Pause, Button::Mode], button)?, true),
+				["button", button, "released"] => Record::Button(variant(&[Button::Pause, Button::Mode], button)?, false),
+				["motor", motor, "run", duty, result @ ..] => Record::Motor(
+					variant(&motors, motor)?,
+					MotorState::Running(duty.parse().ok()?),
+					parse_result(result, |_| Some(()))?,
+				),
+				["motor", motor, "brake", result @ ..] => Record::Motor(variant(&motors, motor)?, MotorState::Braked, parse_result(result, |_| Some(()))?),
+				["motor", motor, "free", result @ ..] => Record::Motor(variant(&motors, motor)?, MotorState::FreeSpin, parse_result(result, |_| Some(()))?),
+				["standby", enable, result @ ..] => Record::Standby(enable.parse().ok()?, parse_result(result, |_| Some(()))?),
+				["imu", result @ ..] => Record::Imu(parse_result(result, |value| {
+					let values = value.iter().map(|value| value.parse().ok()).collect::<Option<Vec<f64>>>()?;
+					if values.len() != 13 {
+						return None;
+					}
+					Some(ImuData {
+						accel: values[0..3].try_into().ok()?,
+						gyro: values[3..6].try_into().ok()?,
+						quaternion: values[6..10].try_into().ok()?,
+						mag: values[10..13].try_into().ok()?,
+					})
+				})?),
+				["voltage", result @ ..] => Record::Voltage(parse_result(result, |value| value.first()?.parse().ok())?),
+				_ => return None,
+			})
+		};
+		record().ok_or_else(|| Error::Io(format!("Invalid record: {}", line)))
+	}
+}
+
+/// Format the result of a backend call: `ok <value>` or `err <kind> <message>`
+fn format_result<T, F: Fn(&T) -> String>(result: &Result<T, Error>, value: F) -> String {
+	match result {
+		Ok(ok) => format!("ok {}", value(ok)),
+		Err(err) => {
+			let (kind, message) = match err {
+				Error::Init(msg) => ("Init", msg),
+				Error::Read(msg) => ("Read", msg),
+				Error::Write(msg) => ("Write", msg),
+				Error::Timeout(msg) => ("Timeout", msg),
+				Error::InvalidChannel(msg) => ("InvalidChannel", msg),
+				Error::NotSupported(msg) => ("NotSupported", msg),
+				Error::Calibration(msg) => ("Calibration", msg),
+				Error::Io(msg) => ("Io", msg),
+				Error::Fault(msg) => ("Fault", msg),
+			};
+			// A record is a single line
+			format!("err {} {}", kind, message.replace('\n', " "))
+		},
+	}
+}
+
+/// Parse the result of a backend call, None if it is invalid
+fn parse_result<T, F: Fn(&[&str]) -> Option<T>>(fields: &[&str], value: F) -> Option<Result<T, Error>> {
+	match fields {
+		["ok", value_fields @ ..] => value(value_fields).map(Ok),
+		["err", kind, message @ ..] => {
+			let message = message.join(" ");
+			let err = match *kind {
+				"Init" => Error::Init(message),
+				"Read" => Error::Read(message),
+				"Write" => Error::Write(message),
+				"Timeout" => Error::Timeout(message),
+				"InvalidChannel" => Error::InvalidChannel(message),
+				"NotSupported" => Error::NotSupported(message),
+				"Calibration" => Error::Calibration(message),
+				"Io" => Error::Io(message),
+				"Fault" => Error::Fault(message),
+				_ => return None,
+			};
+			Some(Err(err))
+		},
+		_ => None,
+	}
+}
+
+/// Find the variant of an enum by its name
+fn variant<T: fmt::Debug + Copy>(variants: &[T], name: &str) -> Option<T> {
+	variants.iter().copied().find(|variant| format!("{:?}", variant) == name)
+}
+
+/// Parse a line of a recording: the time in µs since the start of the recording and the record
+pub fn parse_line(line: &str) -> Result<(u64, Record), Error> {
+	let (time, record) = line.split_once(' ').ok_or_else(|| Error::Io(format!("Invalid record: {}", line)))?;
+	let time = time.parse().map_err(|_| Error::Io(format!("Invalid time of record: {}", line)))?;
+	Ok((time, record.parse()?))
+}
+
+/// The file a recording is written to, one line per record
+struct Log {
+	start: Instant,
+	file: Mutex<LineWriter<File>>,
+}
+
+impl Log {
+	fn write(&self, record: &Record) {
+		let time = self.start.elapsed().as_micros();
+		// A failing recording must not stop the robot, it only misses the records
+		let _ = writeln!(self.file.lock().unwrap_or_else(|err| err.into_inner()), "{} {}", time, record);
+	}
+}
+
+/// Create a backend which records all I/O of another backend into a file
+///
+/// The values of the encoders, the pulses, the buttons, the IMU and the battery voltage are recorded with the motor commands,
+/// so `replay::Replay` can feed them back into the robot. Only one instance records the buttons,
+/// creating a new one takes them over.
+///
+/// # Arguments
+///
+/// * `hal` - The backend which drives the robot
+/// * `path` - Path to the file, an existing file is overwritten
+pub fn new<P: AsRef<Path>>(hal: Arc<dyn Backend>, path: P) -> Result<Recorder, Error> {
+	let file = File::create(&path).map_err(|err| Error::Io(format!("Unable to create {}: {}", path.as_ref().display(), err)))?;
+	let log = Arc::new(Log {
+		start: Instant::now(),
+		file: Mutex::new(LineWriter::new(file)),
+	});
+	*BUTTON_LOG.lock().unwrap_or_else(|err| err.into_inner()) = Some(log.clone());
+	Ok(Recorder { hal, log })
+}
+
+/// Backend which passes everything on to another backend and records its I/O
+pub struct Recorder {
+	hal: Arc<dyn Backend>,
+	log: Arc<Log>,
+}
+
+impl Recorder {
+	fn motor(&self, motor: Motor, command: MotorState, result: Result<(), Error>) -> Result<(), Error> {
+		self.log.write(&Record::Motor(motor, command, result.clone()));
+		result
+	}
+}
+
+impl Backend for Recorder {
+	fn cleanup(&self) {
+		self.hal.cleanup();
+		let _ = self.log.file.lock().unwrap_or_else(|err| err.into_inner()).flush();
+	}
+
+	fn init_button(&self, button: Button) -> Result<(), Error> {
+		self.hal.init_button(button)
+	}
+
+	fn register_button_callbacks(&self, button: Button, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>) -> Result<(), Error> {
+		let (record_pressed, record_released): (unsafe extern "C" fn(), unsafe extern "C" fn()) = match button {
+			Button::Pause => (pause_pressed, pause_released),
+			Button::Mode => (mode_pressed, mode_released),
+		};
+		let mut callbacks = BUTTON_CALLBACKS.lock().unwrap_or_else(|err| err.into_inner());
+		callbacks.retain(|(b, _, _)| *b != button);
+		callbacks.extend(pressed.map(|callback| (button, true, callback)));
+		callbacks.extend(released.map(|callback| (button, false, callback)));
+		drop(callbacks);
+		self.hal.register_button_callbacks(button, pressed.map(|_| record_pressed), released.map(|_| record_released))
+	}
+
+	fn init_encoders(&self) -> Result<(), Error> {
+		self.hal.init_encoders()
+	}
+
+	fn get_encoder_value(&self, encoder: Encoder) -> Result<i32, Error> {
+		let result = self.hal.get_encoder_value(encoder);
+		self.log.write(&Record::Encoder(encoder, result.clone()));
+		result
+	}
+
+	fn init_motors(&self) -> Result<(), Error> {
+		self.hal.init_motors()
+	}
+
+	fn run_motor(&self, motor: Motor, speed: f64) -> Result<(), Error> {
+		self.motor(motor, MotorState::Running(speed), self.hal.run_motor(motor, speed))
+	}
+
+	fn brake_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.motor(motor, MotorState::Braked, self.hal.brake_motor(motor))
+	}
+
+	fn free_spin_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.motor(motor, MotorState::FreeSpin, self.hal.free_spin_motor(motor))
+	}
+
+	fn motor_standby(&self, enable: bool) -> Result<(), Error> {
+		let result = self.hal.motor_standby(enable);
+		self.log.write(&Record::Standby(enable, result.clone()));
+		result
+	}
+
+	fn gpio_init(&self, chip: GpioChip, pin: i32, direction: GpioHandle) -> Result<(), Error> {
+		self.hal.gpio_init(chip, pin, direction)
+	}
+
+	fn gpio_cleanup(&self, chip: GpioChip, pin: i32) {
+		self.hal.gpio_cleanup(chip, pin)
+	}
+
+	fn gpio_set_value(&self, chip: GpioChip, pin: i32, value: i32) -> Result<(), Error> {
+		self.hal.gpio_set_value(chip, pin, value)
+	}
+
+	fn gpio_get_value(&self, chip: GpioChip, pin: i32) -> Result<i32, Error> {
+		self.hal.gpio_get_value(chip, pin)
+	}
+
+	fn gpio_send_pulse(&self, chip: GpioChip, pin: i32, value: GpioTrigger, time: Duration) -> Result<(), Error> {
+		self.hal.gpio_send_pulse(chip, pin, value, time)
+	}
+
+	fn gpio_init_event(&self, chip: GpioChip, pin: i32) -> Result<(), Error> {
+		self.hal.gpio_init_event(chip, pin)
+	}
+
+	fn gpio_read_pulse(&self, chip: GpioChip, pin: i32, value: GpioTrigger, timeout: Duration) -> Result<Duration, Error> {
+		let result = self.hal.gpio_read_pulse(chip, pin, value, timeout);
+		self.log.write(&Record::Pulse(chip, pin, result.clone()));
+		result
+	}
+
+	fn set_led(&self, led: Led, on: bool) -> Result<(), Error> {
+		self.hal.set_led(led, on)
+	}
+
+	fn init_servos(&self) -> Result<(), Error> {
+		self.hal.init_servos()
+	}
+
+	fn servo_power_rail(&self, enable: bool) -> Result<(), Error> {
+		self.hal.servo_power_rail(enable)
+	}
+
+	fn servo_send_pulse(&self, servo: Servo, width: i32) -> Result<(), Error> {
+		self.hal.servo_send_pulse(servo, width)
+	}
+
+	fn init_adc(&self) -> Result<(), Error> {
+		self.hal.init_adc()
+	}
+
+	fn battery_voltage(&self) -> Result<f64, Error> {
+		let result = self.hal.battery_voltage();
+		self.log.write(&Record::Voltage(result.clone()));
+		result
+	}
+
+	fn jack_voltage(&self) -> Result<f64, Error> {
+		self.hal.jack_voltage()
+	}
+
+	fn init_imu(&self) -> Result<(), Error> {
+		self.hal.init_imu()
+	}
+
+	fn read_imu(&self) -> Result<ImuData, Error> {
+		let result = self.hal.read_imu();
+		self.log.write(&Record::Imu(result.clone()));
+		result
+	}
+
+	fn now(&self) -> Instant {
+		self.hal.now()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn records_round_trip() {
+		let imu = ImuData {
+			accel: [0.1, -9.81, 1e-7],
+			gyro: [0.0, 12.5, -3.0],
+			quaternion: [1.0, 0.0, 0.0, 0.0],
+			mag: [21.3, -4.0, 40.000001],
+		};
+		let records = [
+			Record::Encoder(Encoder::ENCODER3, Ok(-1234)),
+			Record::Encoder(Encoder::ENCODER1, Err(Error::Read(String::from("Unable to read ENCODER1")))),
+			Record::Pulse(GpioChip::GPIO3, 20, Ok(Duration::from_nanos(580_123))),
+			Record::Pulse(GpioChip::GPIO3, 20, Err(Error::Timeout(String::from("No pulse on GPIO3_20")))),
+			Record::Button(Button::Pause, true),
+			Record::Button(Button::Mode, false),
+			Record::Motor(Motor::MOTOR2, MotorState::Running(-0.125), Ok(())),
+			Record::Motor(Motor::MOTOR4, MotorState::Braked, Err(Error::Write(String::from("Unable to drive MOTOR4")))),
+			Record::Motor(Motor::MOTOR1, MotorState::FreeSpin, Ok(())),
+			Record::Standby(true, Ok(())),
+			Record::Imu(Ok(imu)),
+			Record::Imu(Err(Error::Init(String::from("IMU is not initialized")))),
+			Record::Voltage(Ok(7.123456789)),
+			Record::Voltage(Err(Error::Fault(String::from("ADC\nbroken")))),
+		];
+		for record in records {
+			let line = format!("42 {}", record);
+			let (time, parsed) = parse_line(&line).unwrap();
+			assert_eq!(time, 42);
+			// A message with a line break is recorded on a single line
+			let record = match record {
+				Record::Voltage(Err(Error::Fault(_))) => Record::Voltage(Err(Error::Fault(String::from("ADC broken")))),
+				record => record,
+			};
+			assert_eq!(parsed, record, "{}", line);
+		}
+		assert!(parse_line("42 imu ok 1 2 3").is_err());
+		assert!(parse_line("42 voltage maybe").is_err());
+	}
+}
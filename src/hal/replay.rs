@@ -0,0 +1,369 @@
+use super::{Backend, Button, Encoder, Led, Motor, Servo, GpioChip, GpioHandle, GpioTrigger, ImuData};
+use super::recorder::{self, Record};
+use super::simulated::{MotorState, Simulated};
+use super::super::error::Error;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A replayed duty may differ this much from the recorded one, the slew-rate limit depends on the time between the steps
+const DUTY_TOLERANCE: f64 = 0.05;
+
+/// Recorded values with the time in µs they were recorded at
+type Values<T> = VecDeque<(u64, Result<T, Error>)>;
+
+/// Recorded commands with their results
+type Commands<T> = VecDeque<(T, Result<(), Error>)>;
+
+#[derive(Default)]
+struct State {
+	/// Time in µs of the recording up to which it was replayed, it advances with the recorded values
+	clock: u64,
+	encoders: HashMap<Encoder, Values<i32>>,
+	imu: Values<ImuData>,
+	voltages: Values<f64>,
+	pulses: HashMap<(GpioChip, i32), Values<Duration>>,
+	buttons: VecDeque<(u64, Button, bool)>,
+	motors: HashMap<Motor, Commands<MotorState>>,
+	standby: Commands<bool>,
+	divergences: Vec<String>,
+}
+
+/// Load a recording of `recorder::Recorder` to replay it
+///
+/// # Arguments
+///
+/// * `path` - Path to the recording
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Replay, Error> {
+	let content = fs::read_to_string(&path).map_err(|err| Error::Io(format!("Unable to read {}: {}", path.as_ref().display(), err)))?;
+
+	let mut state = State::default();
+	for line in content.lines().filter(|line| !line.trim().is_empty()) {
+		match recorder::parse_line(line)? {
+			(time, Record::Encoder(encoder, result)) => state.encoders.entry(encoder).or_default().push_back((time, result)),
+			(time, Record::Pulse(chip, pin, result)) => state.pulses.entry((chip, pin)).or_default().push_back((time, result)),
+			(time, Record::Button(button, pressed)) => state.buttons.push_back((time, button, pressed)),
+			(_, Record::Motor(motor, command, result)) => state.motors.entry(motor).or_default().push_back((command, result)),
+			(_, Record::Standby(enable, result)) => state.standby.push_back((enable, result)),
+			(time, Record::Imu(result)) => state.imu.push_back((time, result)),
+			(time, Record::Voltage(result)) => state.voltages.push_back((time, result)),
+		}
+	}
+
+	Ok(Replay {
+		sim: Simulated::default(),
+		state: Mutex::new(state),
+		start: Instant::now(),
+	})
+}
+
+/// Backend which feeds a recording back into the robot, e.g. to reproduce a failure on a desktop
+///
+/// The values of the encoders and the IMU are returned in the recorded order and advance the time of
+/// the recording, the pulses, the battery voltages and the button presses are replayed once this time reaches them.
+/// The robot measures its steps with this time, so it may be stopped in a debugger without a stall.
+/// The motor commands are compared with the recorded ones and their recorded results are returned.
+/// Everything which is not recorded, like the voltage of the DC jack, is simulated.
+pub struct Replay {
+	sim: Simulated,
+	state: Mutex<State>,
+	/// The start of the recording on the clock of the replay
+	start: Instant,
+}
+
+impl Replay {
+	fn state(&self) -> MutexGuard<'_, State> {
+		self.state.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	/// Check if all encoder values were replayed
+	pub fn finished(&self) -> bool {
+		self.state().encoders.values().all(|values| values.is_empty())
+	}
+
+	/// Time in µs of the recording up to which it was replayed
+	pub fn clock(&self) -> u64 {
+		self.state().clock
+	}
+
+	/// The motor commands which differ from the recorded ones
+	pub fn divergences(&self) -> Vec<String> {
+		self.state().divergences.clone()
+	}
+
+	/// The last state a motor was set to, like `Simulated::motor_state`
+	pub fn motor_state(&self, motor: Motor) -> MotorState {
+		self.sim.motor_state(motor)
+	}
+
+	/// Return the next recorded value, advance the time of the recording to it and replay the button presses up to there
+	///
+	/// # Arguments
+	///
+	/// * `values` - Selects the recorded values
+	/// * `name` - Name of the values for the error at the end of the recording
+	fn next<T>(&self, values: impl FnOnce(&mut State) -> Option<&mut Values<T>>, name: &str) -> Result<T, Error> {
+		let mut state = self.state();
+		let (time, result) = values(&mut state).and_then(|values| values.pop_front())
+			.ok_or_else(|| Error::Read(format!("End of the recording of {}", name)))?;
+		state.clock = state.clock.max(time);
+
+		// The callbacks must not be called while the state is locked
+		let mut buttons = vec!();
+		while state.buttons.front().is_some_and(|(time, _, _)| *time <= state.clock) {
+			buttons.extend(state.buttons.pop_front());
+		}
+		drop(state);
+
+		for (_, button, pressed) in buttons {
+			match pressed {
+				true => self.sim.press_button(button),
+				false => self.sim.release_button(button),
+			}
+		}
+		result
+	}
+
+	/// Compare a motor command with the recorded one and return the recorded result
+	fn motor(&self, motor: Motor, command: MotorState) -> Result<(), Error> {
+		let mut state = self.state();
+		let clock = state.clock;
+		let result = match state.motors.get_mut(&motor).and_then(|commands| commands.pop_front()) {
+			Some((recorded, result)) => {
+				let same = match (recorded, command) {
+					(MotorState::Running(recorded), MotorState::Running(duty)) => (recorded - duty).abs() <= DUTY_TOLERANCE,
+					(recorded, command) => recorded == command,
+				};
+				if !same {
+					state.divergences.push(format!("{}µs: {:?} recorded {:?}, replayed {:?}", clock, motor, recorded, command));
+				}
+				result
+			},
+			None => {
+				state.divergences.push(format!("{}µs: {:?} no command recorded, replayed {:?}", clock, motor, command));
+				Ok(())
+			},
+		};
+		drop(state);
+
+		result?;
+		match command {
+			MotorState::Running(duty) => self.sim.run_motor(motor, duty),
+			MotorState::Braked => self.sim.brake_motor(motor),
+			MotorState::FreeSpin => self.sim.free_spin_motor(motor),
+		}
+	}
+}
+
+impl Backend for Replay {
+	fn cleanup(&self) {
+		self.sim.cleanup();
+	}
+
+	fn init_button(&self, button: Button) -> Result<(), Error> {
+		self.sim.init_button(button)
+	}
+
+	fn register_button_callbacks(&self, button: Button, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>) -> Result<(), Error> {
+		self.sim.register_button_callbacks(button, pressed, released)
+	}
+
+	fn init_encoders(&self) -> Result<(), Error> {
+		self.sim.init_encoders()
+	}
+
+	fn get_encoder_value(&self, encoder: Encoder) -> Result<i32, Error> {
+		self.next(|state| state.encoders.get_mut(&encoder), &format!("{:?}", encoder))
+	}
+
+	fn init_motors(&self) -> Result<(), Error> {
+		self.sim.init_motors()
+	}
+
+	fn run_motor(&self, motor: Motor, speed: f64) -> Result<(), Error> {
+		self.motor(motor, MotorState::Running(speed))
+	}
+
+	fn brake_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.motor(motor, MotorState::Braked)
+	}
+
+	fn free_spin_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.motor(motor, MotorState::FreeSpin)
+	}
+
+	fn motor_standby(&self, enable: bool) -> Result<(), Error> {
+		let mut state = self.state();
+		let clock = state.clock;
+		let result = match state.standby.pop_front() {
+			Some((recorded, result)) => {
+				if recorded != enable {
+					state.divergences.push(format!("{}µs: standby recorded {}, replayed {}", clock, recorded, enable));
+				}
+				result
+			},
+			None => {
+				state.divergences.push(format!("{}µs: standby not recorded, replayed {}", clock, enable));
+				Ok(())
+			},
+		};
+		drop(state);
+
+		result.and_then(|_| self.sim.motor_standby(enable))
+	}
+
+	fn gpio_init(&self, chip: GpioChip, pin: i32, direction: GpioHandle) -> Result<(), Error> {
+		self.sim.gpio_init(chip, pin, direction)
+	}
+
+	fn gpio_cleanup(&self, chip: GpioChip, pin: i32) {
+		self.sim.gpio_cleanup(chip, pin)
+	}
+
+	fn gpio_set_value(&self, chip: GpioChip, pin: i32, value: i32) -> Result<(), Error> {
+		self.sim.gpio_set_value(chip, pin, value)
+	}
+
+	fn gpio_get_value(&self, chip: GpioChip, pin: i32) -> Result<i32, Error> {
+		self.sim.gpio_get_value(chip, pin)
+	}
+
+	fn gpio_read_pulse(&self, chip: GpioChip, pin: i32, _value: GpioTrigger, timeout: Duration) -> Result<Duration, Error> {
+		// Wait until the replay reaches the recorded pulse, so the sensor measures along with the robot
+		let deadline = Instant::now() + timeout;
+		loop {
+			let mut state = self.state();
+			let clock = state.clock;
+			let pulses = state.pulses.get_mut(&(chip, pin));
+			match pulses.as_ref().and_then(|pulses| pulses.front()) {
+				Some((time, _)) if *time <= clock => {
+					if let Some((_, result)) = pulses.and_then(|pulses| pulses.pop_front()) {
+						return result;
+					}
+				},
+				_ if Instant::now() > deadline => return Err(Error::Timeout(format!("No pulse on GPIO{}_{}", chip as i32, pin))),
+				_ => {},
+			}
+			drop(state);
+			sleep(Duration::from_millis(1));
+		}
+	}
+
+	fn set_led(&self, led: Led, on: bool) -> Result<(), Error> {
+		self.sim.set_led(led, on)
+	}
+
+	fn init_servos(&self) -> Result<(), Error> {
+		self.sim.init_servos()
+	}
+
+	fn servo_power_rail(&self, enable: bool) -> Result<(), Error> {
+		self.sim.servo_power_rail(enable)
+	}
+
+	fn servo_send_pulse(&self, servo: Servo, width: i32) -> Result<(), Error> {
+		self.sim.servo_send_pulse(servo, width)
+	}
+
+	fn init_adc(&self) -> Result<(), Error> {
+		self.sim.init_adc()
+	}
+
+	fn battery_voltage(&self) -> Result<f64, Error> {
+		// The battery is measured by the time, so a replay may measure a step earlier or later than the recording,
+		// the voltage recorded last up to now is returned without advancing the time
+		let mut state = self.state();
+		while state.voltages.len() > 1 && state.voltages.get(1).is_some_and(|(time, _)| *time <= state.clock) {
+			state.voltages.pop_front();
+		}
+		match state.voltages.front() {
+			Some((_, result)) => result.clone(),
+			None => Err(Error::Read(String::from("No battery voltage recorded"))),
+		}
+	}
+
+	fn jack_voltage(&self) -> Result<f64, Error> {
+		self.sim.jack_voltage()
+	}
+
+	fn init_imu(&self) -> Result<(), Error> {
+		self.sim.init_imu()
+	}
+
+	fn read_imu(&self) -> Result<ImuData, Error> {
+		self.next(|state| Some(&mut state.imu), "the IMU")
+	}
+
+	fn now(&self) -> Instant {
+		self.start + Duration::from_micros(self.clock())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::sync::Arc;
+
+	#[test]
+	fn replays_a_recording() {
+		let path = std::env::temp_dir().join(format!("robot_diff_drive_replay_{}", std::process::id()));
+		let sim = Arc::new(Simulated::default());
+		let imu = ImuData { gyro: [0.0, 0.0, 90.0], quaternion: [1.0, 0.0, 0.0, 0.0], ..Default::default() };
+
+		// Record a few steps of a robot
+		let recorder = recorder::new(sim.clone(), &path).unwrap();
+		recorder.init_encoders().unwrap();
+		recorder.init_motors().unwrap();
+		sim.set_encoder_value(Encoder::ENCODER1, 10);
+		sim.set_imu(imu);
+		sim.set_voltages(7.2, 0.0);
+		assert_eq!(recorder.get_encoder_value(Encoder::ENCODER1), Ok(10));
+		assert_eq!(recorder.read_imu(), Ok(imu));
+		assert_eq!(recorder.battery_voltage(), Ok(7.2));
+		recorder.run_motor(Motor::MOTOR1, 0.5).unwrap();
+		sleep(Duration::from_millis(5));
+		sim.set_voltages(6.5, 0.0);
+		assert_eq!(recorder.battery_voltage(), Ok(6.5));
+		sim.set_encoder_value(Encoder::ENCODER1, 25);
+		assert_eq!(recorder.get_encoder_value(Encoder::ENCODER1), Ok(25));
+		recorder.brake_motor(Motor::MOTOR1).unwrap();
+		recorder.cleanup();
+
+		// The replay returns the recorded values, not the ones of a simulation
+		let replay = load(&path).unwrap();
+		let start = replay.now();
+		replay.init_encoders().unwrap();
+		replay.init_motors().unwrap();
+		assert_eq!(replay.get_encoder_value(Encoder::ENCODER1), Ok(10));
+		assert_eq!(replay.read_imu(), Ok(imu));
+		assert_eq!(replay.battery_voltage(), Ok(7.2));
+		replay.run_motor(Motor::MOTOR1, 0.51).unwrap();
+		assert_eq!(replay.motor_state(Motor::MOTOR1), MotorState::Running(0.51));
+
+		// The clock only advances with the recording, a stop in a debugger does not count
+		let first = replay.now();
+		sleep(Duration::from_millis(10));
+		assert_eq!(replay.now(), first);
+
+		// The voltage is measured by the time, it changes once the replay reached it
+		assert_eq!(replay.battery_voltage(), Ok(7.2));
+		assert_eq!(replay.get_encoder_value(Encoder::ENCODER1), Ok(25));
+		assert!(replay.now().duration_since(first) >= Duration::from_millis(5));
+		assert_eq!(replay.now().duration_since(start), Duration::from_micros(replay.clock()));
+		assert_eq!(replay.battery_voltage(), Ok(6.5));
+		replay.run_motor(Motor::MOTOR1, 0.3).unwrap();
+
+		assert!(replay.finished());
+		assert!(matches!(replay.read_imu(), Err(Error::Read(_))));
+		assert!(matches!(replay.get_encoder_value(Encoder::ENCODER1), Err(Error::Read(_))));
+		let divergences = replay.divergences();
+		assert_eq!(divergences.len(), 1, "{:?}", divergences);
+		assert!(divergences[0].contains("MOTOR1 recorded Braked, replayed Running(0.3)"), "{}", divergences[0]);
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,343 @@
+use super::{Backend, Button, Encoder, Led, Motor, Servo, GpioChip, GpioHandle, GpioTrigger, ImuData};
+use super::super::error::Error;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// State of a simulated motor
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub enum MotorState {
+	#[default]
+	Braked,
+	FreeSpin,
+	Running(f64),
+}
+
+/// Callbacks of a button when it is pressed and released
+type ButtonCallbacks = (Option<unsafe extern "C" fn()>, Option<unsafe extern "C" fn()>);
+
+#[derive(Default)]
+struct State {
+	encoders: HashMap<Encoder, i32>,
+	motors: HashMap<Motor, MotorState>,
+	buttons: HashMap<Button, ButtonCallbacks>,
+	gpio: HashMap<(GpioChip, i32), i32>,
+	pulses: HashMap<(GpioChip, i32), Duration>,
+	imu: Option<ImuData>,
+	leds: HashMap<Led, bool>,
+	servo_rail: bool,
+	servos: HashMap<Servo, i32>,
+	voltages: Option<(f64, f64)>,
+	encoders_initialized: bool,
+	motors_initialized: bool,
+	standby: bool,
+	failing_encoders: HashSet<Encoder>,
+	failing_motors: HashSet<Motor>,
+	/// The time once it is advanced by hand, it stands still in between
+	clock: Option<Instant>,
+}
+
+/// In-memory backend which records all motor commands and returns preset sensor values
+///
+/// Used to run the robot without a BeagleBone Blue, e.g. on a laptop or in a test.
+#[derive(Default)]
+pub struct Simulated {
+	state: Mutex<State>,
+}
+
+impl Simulated {
+	fn state(&self) -> MutexGuard<'_, State> {
+		// A panic in another thread must not make the simulation unusable
+		self.state.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	/// Set the count the encoder returns
+	///
+	/// # Arguments
+	///
+	/// * `encoder` - Which Encoder
+	/// * `value` - The new encoder count
+	pub fn set_encoder_value(&self, encoder: Encoder, value: i32) {
+		self.state().encoders.insert(encoder, value);
+	}
+
+	/// Let all reads of the encoder fail, e.g. to simulate a loose cable
+	pub fn set_encoder_failing(&self, encoder: Encoder, failing: bool) {
+		let mut state = self.state();
+		match failing {
+			true => state.failing_encoders.insert(encoder),
+			false => state.failing_encoders.remove(&encoder),
+		};
+	}
+
+	/// Let all commands to the motor fail, e.g. to simulate a broken driver
+	pub fn set_motor_failing(&self, motor: Motor, failing: bool) {
+		let mut state = self.state();
+		match failing {
+			true => state.failing_motors.insert(motor),
+			false => state.failing_motors.remove(&motor),
+		};
+	}
+
+	/// Apply a command to a motor
+	fn set_motor(&self, motor: Motor, command: MotorState) -> Result<(), Error> {
+		let mut state = self.state();
+		if !state.motors_initialized {
+			return Err(Error::Init(format!("Motors are not initialized, unable to drive {:?}", motor)));
+		}
+		if state.failing_motors.contains(&motor) {
+			return Err(Error::Write(format!("Unable to drive {:?}", motor)));
+		}
+		state.motors.insert(motor, command);
+		Ok(())
+	}
+
+	/// Get the last state a motor was set to, in standby all motors spin freely
+	pub fn motor_state(&self, motor: Motor) -> MotorState {
+		let state = self.state();
+		match state.standby {
+			true => MotorState::FreeSpin,
+			false => state.motors.get(&motor).copied().unwrap_or_default(),
+		}
+	}
+
+	/// Get the duty the motor is running with, a stopped motor has a duty of 0.0
+	pub fn motor_duty(&self, motor: Motor) -> f64 {
+		match self.motor_state(motor) {
+			MotorState::Running(duty) => duty,
+			MotorState::Braked | MotorState::FreeSpin => 0.0,
+		}
+	}
+
+	/// Check if the motor drivers are in standby
+	pub fn standby(&self) -> bool {
+		self.state().standby
+	}
+
+	/// Simulate a press on a button by calling the registered callback
+	pub fn press_button(&self, button: Button) {
+		let callback = self.state().buttons.get(&button).and_then(|callbacks| callbacks.0);
+		if let Some(callback) = callback {
+			unsafe { callback(); }
+		}
+	}
+
+	/// Simulate the release of a button by calling the registered callback
+	pub fn release_button(&self, button: Button) {
+		let callback = self.state().buttons.get(&button).and_then(|callbacks| callbacks.1);
+		if let Some(callback) = callback {
+			unsafe { callback(); }
+		}
+	}
+
+	/// Set the value an input pin returns
+	pub fn set_gpio_value(&self, chip: GpioChip, pin: i32, value: i32) {
+		self.state().gpio.insert((chip, pin), value);
+	}
+
+	/// Get the value of a pin, -1 if it was not initialized
+	pub fn gpio_value(&self, chip: GpioChip, pin: i32) -> i32 {
+		self.state().gpio.get(&(chip, pin)).copied().unwrap_or(-1)
+	}
+
+	/// Set the length of the pulse which is read from the pin, e.g. the echo of an ultrasonic sensor
+	pub fn set_pulse(&self, chip: GpioChip, pin: i32, duration: Duration) {
+		self.state().pulses.insert((chip, pin), duration);
+	}
+
+	/// Check if the LED is switched on
+	pub fn led(&self, led: Led) -> bool {
+		self.state().leds.get(&led).copied().unwrap_or_default()
+	}
+
+	/// Check if the power rail of the servos is switched on
+	pub fn servo_power(&self) -> bool {
+		self.state().servo_rail
+	}
+
+	/// Get the width of the last pulse in µs sent to the servo
+	pub fn servo_pulse(&self, servo: Servo) -> Option<i32> {
+		self.state().servos.get(&servo).copied()
+	}
+
+	/// Set the voltages the ADC measures
+	///
+	/// # Arguments
+	///
+	/// * `battery` - Voltage of the battery pack in V
+	/// * `jack` - Voltage on the DC jack in V
+	pub fn set_voltages(&self, battery: f64, jack: f64) {
+		self.state().voltages = Some((battery, jack));
+	}
+
+	/// Set the sample the IMU returns
+	pub fn set_imu(&self, data: ImuData) {
+		self.state().imu = Some(data);
+	}
+
+	/// Advance the time of the backend by hand, from the first call on it only moves on with this
+	///
+	/// # Arguments
+	///
+	/// * `duration` - Time to move on by
+	pub fn advance(&self, duration: Duration) {
+		let mut state = self.state();
+		let now = state.clock.unwrap_or_else(Instant::now);
+		state.clock = Some(now + duration);
+	}
+}
+
+impl Backend for Simulated {
+	fn cleanup(&self) {
+		let mut state = self.state();
+		state.buttons.clear();
+		state.gpio.clear();
+		state.leds.clear();
+		state.servo_rail = false;
+		state.motors.values_mut().for_each(|motor| *motor = MotorState::Braked);
+		state.encoders_initialized = false;
+		state.motors_initialized = false;
+		state.standby = false;
+	}
+
+	fn init_button(&self, button: Button) -> Result<(), Error> {
+		self.state().buttons.insert(button, (None, None));
+		Ok(())
+	}
+
+	fn register_button_callbacks(&self, button: Button, pressed: Option<unsafe extern "C" fn()>, released: Option<unsafe extern "C" fn()>) -> Result<(), Error> {
+		match self.state().buttons.get_mut(&button) {
+			Some(registered) => { *registered = (pressed, released); Ok(()) },
+			None => Err(Error::Init(format!("Button {:?} is not initialized", button))),
+		}
+	}
+
+	fn init_encoders(&self) -> Result<(), Error> {
+		self.state().encoders_initialized = true;
+		Ok(())
+	}
+
+	fn get_encoder_value(&self, encoder: Encoder) -> Result<i32, Error> {
+		let state = self.state();
+		if !state.encoders_initialized {
+			return Err(Error::Init(format!("Encoders are not initialized, unable to read {:?}", encoder)));
+		}
+		if state.failing_encoders.contains(&encoder) {
+			return Err(Error::Read(format!("Unable to read {:?}", encoder)));
+		}
+		Ok(state.encoders.get(&encoder).copied().unwrap_or_default())
+	}
+
+	fn init_motors(&self) -> Result<(), Error> {
+		let mut state = self.state();
+		for motor in [Motor::MOTOR1, Motor::MOTOR2, Motor::MOTOR3, Motor::MOTOR4] {
+			state.motors.insert(motor, MotorState::Braked);
+		}
+		state.motors_initialized = true;
+		Ok(())
+	}
+
+	fn run_motor(&self, motor: Motor, speed: f64) -> Result<(), Error> {
+		self.set_motor(motor, MotorState::Running(super::clamp_duty(speed)))
+	}
+
+	fn brake_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.set_motor(motor, MotorState::Braked)
+	}
+
+	fn free_spin_motor(&self, motor: Motor) -> Result<(), Error> {
+		self.set_motor(motor, MotorState::FreeSpin)
+	}
+
+	fn motor_standby(&self, enable: bool) -> Result<(), Error> {
+		let mut state = self.state();
+		if !state.motors_initialized {
+			return Err(Error::Init(String::from("Motors are not initialized, unable to switch the standby")));
+		}
+		// Like the real drivers, all motors are set to free spin when entering the standby
+		if enable && !state.standby {
+			state.motors.values_mut().for_each(|motor| *motor = MotorState::FreeSpin);
+		}
+		state.standby = enable;
+		Ok(())
+	}
+
+	fn gpio_init(&self, chip: GpioChip, pin: i32, _direction: GpioHandle) -> Result<(), Error> {
+		self.state().gpio.entry((chip, pin)).or_insert(0);
+		Ok(())
+	}
+
+	fn gpio_cleanup(&self, chip: GpioChip, pin: i32) {
+		self.state().gpio.remove(&(chip, pin));
+	}
+
+	fn gpio_set_value(&self, chip: GpioChip, pin: i32, value: i32) -> Result<(), Error> {
+		match self.state().gpio.get_mut(&(chip, pin)) {
+			Some(current) => { *current = value; Ok(()) },
+			None => Err(Error::Write(format!("GPIO{}_{} is not initialized", chip as i32, pin))),
+		}
+	}
+
+	fn gpio_get_value(&self, chip: GpioChip, pin: i32) -> Result<i32, Error> {
+		match self.gpio_value(chip, pin) {
+			-1 => Err(Error::Read(format!("Unable to get a signal from GPIO{}_{}", chip as i32, pin))),
+			value => Ok(value),
+		}
+	}
+
+	fn gpio_read_pulse(&self, chip: GpioChip, pin: i32, _value: GpioTrigger, timeout: Duration) -> Result<Duration, Error> {
+		self.gpio_get_value(chip, pin)?;
+		match self.state().pulses.get(&(chip, pin)) {
+			Some(duration) if *duration <= timeout => Ok(*duration),
+			_ => Err(Error::Timeout(format!("No pulse on GPIO{}_{}", chip as i32, pin))),
+		}
+	}
+
+	fn set_led(&self, led: Led, on: bool) -> Result<(), Error> {
+		self.state().leds.insert(led, on);
+		Ok(())
+	}
+
+	fn init_servos(&self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn servo_power_rail(&self, enable: bool) -> Result<(), Error> {
+		self.state().servo_rail = enable;
+		Ok(())
+	}
+
+	fn servo_send_pulse(&self, servo: Servo, width: i32) -> Result<(), Error> {
+		self.state().servos.insert(servo, width);
+		Ok(())
+	}
+
+	fn init_adc(&self) -> Result<(), Error> {
+		// A fully charged 2S pack without a charger attached
+		self.state().voltages.get_or_insert((8.4, 0.0));
+		Ok(())
+	}
+
+	fn battery_voltage(&self) -> Result<f64, Error> {
+		self.state().voltages.map(|v| v.0).ok_or_else(|| Error::Init(String::from("ADC is not initialized")))
+	}
+
+	fn jack_voltage(&self) -> Result<f64, Error> {
+		self.state().voltages.map(|v| v.1).ok_or_else(|| Error::Init(String::from("ADC is not initialized")))
+	}
+
+	fn init_imu(&self) -> Result<(), Error> {
+		let mut state = self.state();
+		state.imu.get_or_insert_with(|| ImuData { quaternion: [1.0, 0.0, 0.0, 0.0], ..Default::default() });
+		Ok(())
+	}
+
+	fn read_imu(&self) -> Result<ImuData, Error> {
+		self.state().imu.ok_or_else(|| Error::Init(String::from("IMU is not initialized")))
+	}
+
+	fn now(&self) -> Instant {
+		self.state().clock.unwrap_or_else(Instant::now)
+	}
+}
@@ -0,0 +1,77 @@
+use super::error::Error;
+use super::hal::{Backend, ImuData};
+
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Create a new IMU on the given backend
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the MPU is attached to
+pub fn new(hal: Arc<dyn Backend>) -> Imu {
+	Imu {
+		hal,
+		gyro_bias: 0.0,
+		acceleration_bias: 0.0,
+	}
+}
+
+/// Inertial measurement unit (MPU) to measure the rotation of the robot
+pub struct Imu {
+	hal: Arc<dyn Backend>,
+	gyro_bias: f64,
+	acceleration_bias: f64,
+}
+
+impl Imu {
+	/// Start the IMU, from now on it delivers samples continuously
+	pub fn start(&mut self) -> Result<(), Error> {
+		self.hal.init_imu()
+	}
+
+	/// Measure the offset of the gyro and the forward acceleration, the robot must not move while calibrating
+	///
+	/// # Arguments
+	///
+	/// * `samples` - Number of samples to average
+	/// * `interval` - Time to wait between two samples
+	pub fn calibrate(&mut self, samples: u32, interval: Duration) -> Result<(), Error> {
+		let (mut gyro, mut acceleration) = (0.0, 0.0);
+		for _ in 0..samples {
+			let data = self.hal.read_imu()?;
+			gyro += data.gyro[2];
+			acceleration += data.accel[0];
+			sleep(interval);
+		}
+		if samples > 0 {
+			self.gyro_bias = gyro / samples as f64;
+			self.acceleration_bias = acceleration / samples as f64;
+		}
+		Ok(())
+	}
+
+	/// Get the latest raw sample
+	pub fn read(&self) -> Result<ImuData, Error> {
+		self.hal.read_imu()
+	}
+
+	/// Get the yaw rate of the robot in rad/s, counterclockwise is positive
+	pub fn yaw_rate(&self) -> Result<f64, Error> {
+		let data = self.hal.read_imu()?;
+		Ok((data.gyro[2] - self.gyro_bias).to_radians())
+	}
+
+	/// Get the forward acceleration of the robot in mm/s²
+	pub fn acceleration(&self) -> Result<f64, Error> {
+		let data = self.hal.read_imu()?;
+		Ok((data.accel[0] - self.acceleration_bias) * 1000.0)
+	}
+
+	/// Get the heading of the robot in rad as calculated by the DMP
+	pub fn heading(&self) -> Result<f64, Error> {
+		let [w, x, y, z] = self.hal.read_imu()?.quaternion;
+		Ok((2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z)))
+	}
+}
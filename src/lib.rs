@@ -0,0 +1,23 @@
+pub mod hal;
+pub mod planner;
+pub mod battery;
+pub mod button;
+pub mod characterization;
+pub mod compass;
+pub mod config;
+pub mod distance;
+pub mod error;
+pub mod imu;
+pub mod motor;
+pub mod wheel;
+pub mod position;
+pub mod profiler;
+pub mod diff_drive;
+pub mod servo;
+pub mod simulator;
+pub mod slip;
+pub mod status;
+pub mod tof;
+pub mod umbmark;
+pub mod units;
+pub mod watchdog;
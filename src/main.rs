@@ -1,85 +1,346 @@
-mod hal;
-mod planner;
-mod distance;
-mod motor;
-mod wheel;
-mod position;
-mod diff_drive;
-
 use std::thread::sleep;
 use std::time::Duration;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use wheel::Wheel as Wheel;
+use robot_diff_drive::{hal, planner, battery, button, characterization, compass, config, distance, diff_drive, imu, profiler, slip, umbmark, watchdog};
+use robot_diff_drive::simulator;
+use robot_diff_drive::motor::{FaultDetection, VelocityControl};
+use robot_diff_drive::position::OdometryError;
+use robot_diff_drive::units::{AngularVelocity, Duty, Length, Velocity};
+use robot_diff_drive::wheel::Wheel as Wheel;
+
+/// Configuration of the robot, e.g. with the calibrated wheels
+const ROBOT_CONFIGURATION: &str = "robot.conf";
+
+/// File the magnetometer calibration is saved to
+const COMPASS_CALIBRATION: &str = "compass.conf";
+
+/// File the feedforward gains of the motors are saved to
+const MOTOR_CHARACTERIZATION: &str = "motors.conf";
+
+/// Standard deviation of the position at which the robot pauses for a re-localization
+const MAX_UNCERTAINTY: Length = Length::mm(100.0);
 
 fn main() {
+	let command = std::env::args().nth(1);
+
 	let terminate = Arc::new(AtomicBool::new(false));
 	signal_hook::flag::register(signal_hook::consts::SIGINT,  Arc::clone(&terminate)).unwrap();
 	signal_hook::flag::register(signal_hook::consts::SIGQUIT, Arc::clone(&terminate)).unwrap();
 
-	// Pause and Start buttons for controlling
-	hal::init_button(hal::Button::Pause);
-	hal::init_button(hal::Button::Mode);
-	hal::register_button_callback(hal::Button::Pause, pause_pressed);
-	hal::register_button_callback(hal::Button::Mode, mode_pressed);
+	// Robot Values, the calibrated ones of `robot_diff_drive calibrate-odometry` are in the configuration
+	let config = config::load(ROBOT_CONFIGURATION).unwrap_or_default();
+	let wheel_distance = Length::mm(config.get_or("wheel.distance", 155.0));
+	let caster_wheel_distance = Length::mm(163.0);
 
-	// Initialize Encoders and Motors
-	hal::init_encoders();
-	hal::init_motors();
-
-	// Robot Values
-	let wheel_distance = 155.0;
-	let caster_wheel_distance = 163.0;
-
-	let wheel_left_raduis = 40.0;
+	let wheel_left_raduis = Length::mm(config.get_or("wheel.left.radius", 40.0));
 	let wheel_left_hal = (hal::Encoder::ENCODER3, hal::Motor::MOTOR3);
 	let wheel_left_gearbox = 3441.0 / 104.0;
 	let wheel_left_resolution = 32.0;
 	let wheel_left_reversed = true;
 
-	let wheel_right_raduis = 40.0;
+	let wheel_right_raduis = Length::mm(config.get_or("wheel.right.radius", 40.0));
 	let wheel_right_hal = (hal::Encoder::ENCODER2, hal::Motor::MOTOR2);
 	let wheel_right_gearbox = 3441.0 / 104.0;
 	let wheel_right_resolution = 32.0;
 	let wheel_right_reversed = false;
 
-	// Initialize the robot
-	let mut robot = diff_drive::new(wheel_distance, caster_wheel_distance);
-	robot.add_wheel(Wheel::left(
+	let wheel_left = Wheel::left(
 		wheel_left_raduis,
 		wheel_left_hal.0,
 		wheel_left_hal.1,
 		wheel_left_gearbox,
 		wheel_left_resolution
-	), wheel_left_reversed);
-	robot.add_wheel(Wheel::right(
+	);
+	let wheel_right = Wheel::right(
 		wheel_right_raduis,
 		wheel_right_hal.0,
 		wheel_right_hal.1,
 		wheel_right_gearbox,
 		wheel_right_resolution
-	), wheel_right_reversed);
+	);
+
+	// A four-wheel chassis has its rear wheels on the remaining channels and skid-steers,
+	// it turns `chassis.slip` times slower than a differential drive with the same wheel distance
+	let mut wheels = vec!((wheel_left, wheel_left_reversed), (wheel_right, wheel_right_reversed));
+	let kinematics = match config.get_or("chassis.wheels", 2) {
+		4 => {
+			wheels.push((Wheel::left(wheel_left_raduis, hal::Encoder::ENCODER4, hal::Motor::MOTOR4, wheel_left_gearbox, wheel_left_resolution), wheel_left_reversed));
+			wheels.push((Wheel::right(wheel_right_raduis, hal::Encoder::ENCODER1, hal::Motor::MOTOR1, wheel_right_gearbox, wheel_right_resolution), wheel_right_reversed));
+			diff_drive::Kinematics::SkidSteer { slip: config.get_or("chassis.slip", 1.5) }
+		},
+		_ => diff_drive::Kinematics::Differential,
+	};
+
+	// Hardware backend, `hal.backend` selects `librobotcontrol` on the BeagleBone Blue, `linux` on other boards
+	// like the Raspberry Pi with the pins of `hal::linux::from_config`, or the physics `simulation` everywhere else
+	let default_backend = match cfg!(target_arch = "arm") {
+		true => "librobotcontrol",
+		false => "simulation",
+	};
+	let hal: Arc<dyn hal::Backend> = match config.get_or("hal.backend", String::from(default_backend)).as_str() {
+		#[cfg(target_arch = "arm")]
+		"librobotcontrol" => hal::default_backend(),
+		#[cfg(target_os = "linux")]
+		"linux" => Arc::new(hal::linux::from_config("/sys", &config)),
+		"simulation" => {
+			let mut sim = simulator::new(wheel_distance);
+			for (wheel, reversed) in &wheels {
+				sim.add_wheel(wheel, *reversed, simulator::MotorModel::default());
+			}
+			if let diff_drive::Kinematics::SkidSteer { slip } = kinematics {
+				sim.set_slip(slip);
+			}
+			sim.set_pose(200.0, 200.0, 0.0);
+			let hal = sim.backend();
+			sim.spawn(Arc::clone(&terminate));
+			hal
+		},
+		backend => {
+			println!("ERROR: Backend {} is not available on this platform", backend);
+			terminate.store(true, Ordering::Relaxed);
+			return;
+		},
+	};
+
+	// Record all I/O with `robot_diff_drive record <file>` and play it back with `robot_diff_drive replay <file>`
+	let mut replay = None;
+	let hal: Arc<dyn hal::Backend> = match (command.as_deref(), std::env::args().nth(2)) {
+		(Some("record"), Some(path)) => match hal::recorder::new(hal.clone(), &path) {
+			Ok(recorder) => Arc::new(recorder),
+			Err(err) => {
+				println!("ERROR: {}", err);
+				hal
+			},
+		},
+		(Some("replay"), Some(path)) => match hal::replay::load(&path) {
+			Ok(player) => {
+				let player = Arc::new(player);
+				replay = Some(player.clone());
+				player
+			},
+			Err(err) => {
+				println!("ERROR: {}", err);
+				terminate.store(true, Ordering::Relaxed);
+				return;
+			},
+		},
+		_ => hal,
+	};
+
+	// Pause and Mode buttons for controlling
+	let mut buttons = button::new(hal.clone());
+	if let Err(err) = buttons.start() {
+		println!("ERROR: {}", err);
+	}
+
+	// Initialize Encoders and Motors, the robot can not drive without them
+	if let Err(err) = hal.init_encoders().and_then(|_| hal.init_motors()) {
+		println!("ERROR: {}", err);
+		terminate.store(true, Ordering::Relaxed);
+		hal.cleanup();
+		return;
+	}
+
+	// Initialize the robot
+	let mut robot = diff_drive::new(hal.clone(), wheel_distance, caster_wheel_distance);
+	for (wheel, reversed) in wheels {
+		robot.add_wheel(wheel, reversed);
+	}
+	robot.set_kinematics(kinematics);
+
+	// Ramp the motors up within 250ms instead of jumping, so the wheels do not slip
+	robot.set_slew_rate(Some(4.0));
+
+	// Drive both wheels equally fast even if one motor is stronger than the other, a speed of 1.0 is 1m/s
+	robot.set_velocity_control(Some(VelocityControl {
+		max_velocity: Velocity::mm_per_s(1000.0),
+		kp: 0.001,
+		ki: 0.01,
+		kd: 0.0,
+		ks: 0.03,
+		kv: 0.0008,
+		ka: 0.0,
+	}));
+	if let Ok(characterization) = characterization::Characterization::load(MOTOR_CHARACTERIZATION) {
+		robot.set_feedforward(characterization.left, characterization.right);
+	}
+
+	// Speed up and slow down gently and come to rest at the last goal, so nothing on the robot spills
+	robot.add_profiler(profiler::new(profiler::Limits {
+		full_speed: Velocity::mm_per_s(1000.0),
+		max_velocity: Velocity::mm_per_s(300.0),
+		max_acceleration: 500.0,
+		max_jerk: 2000.0,
+		max_angular_velocity: AngularVelocity::rad_per_s(3.0),
+		max_angular_acceleration: 10.0,
+		max_angular_jerk: 40.0,
+	}));
+
+	// Stop with a fault if a wheel does not turn for 300ms although it is driven, e.g. against a wall
+	robot.set_fault_detection(Some(FaultDetection {
+		min_duty: Duty::new(0.15),
+		timeout: Duration::from_millis(300),
+		max_velocity: Velocity::mm_per_s(2000.0),
+	}));
+
+	// Brake on a critical battery and compensate the motors for the voltage sag
+	let mut pack = battery::new(hal.clone());
+	match pack.start() {
+		Ok(_) => robot.add_battery(pack, true),
+		Err(err) => println!("ERROR: {}", err),
+	}
+
+	// Fuse the gyro into the orientation, the robot must stand still while calibrating
+	let mut gyro = imu::new(hal.clone());
+	match gyro.start().and_then(|_| gyro.calibrate(100, Duration::from_millis(10))) {
+		Ok(_) => robot.add_imu(gyro, 0.98),
+		Err(err) => println!("ERROR: {}", err),
+	}
+
+	// Trust the IMU instead of spinning wheels and halve the speed until they grip again
+	robot.add_slip_detection(slip::new(slip::SlipDetection {
+		max_velocity_error: Velocity::mm_per_s(50.0),
+		max_yaw_rate_error: AngularVelocity::rad_per_s(0.3),
+		odometry_weight: 0.2,
+		traction_control: Some(0.5),
+	}));
+
+	// Calibrate the compass by spinning in place: `robot_diff_drive calibrate-compass`
+	let mut heading = compass::new(hal.clone());
+	if command.as_deref() == Some("calibrate-compass") {
+		match compass::calibrate(&mut robot, &mut heading, Duty::new(0.2), Duration::from_secs(20)).and_then(|cal| cal.save(COMPASS_CALIBRATION)) {
+			Ok(_) => println!("Compass calibration saved to {}", COMPASS_CALIBRATION),
+			Err(err) => println!("ERROR: {}", err),
+		}
+		terminate.store(true, Ordering::Relaxed);
+		hal.cleanup();
+		return;
+	}
+
+	// Calibrate the wheels by driving 1m squares, the end positions are entered on stdin: `robot_diff_drive calibrate-odometry`
+	if command.as_deref() == Some("calibrate-odometry") {
+		let nominal = umbmark::Calibration {
+			left_radius: wheel_left_raduis,
+			right_radius: wheel_right_raduis,
+			wheel_distance,
+		};
+		match umbmark::calibrate(&mut robot, nominal, Length::mm(1000.0), 5, &mut std::io::stdin().lock()).and_then(|cal| cal.save(ROBOT_CONFIGURATION)) {
+			Ok(_) => println!("Odometry calibration saved to {}", ROBOT_CONFIGURATION),
+			Err(err) => println!("ERROR: {}", err),
+		}
+		terminate.store(true, Ordering::Relaxed);
+		hal.cleanup();
+		return;
+	}
+
+	// Fit the feedforward of the motors by spinning in place: `robot_diff_drive characterize-motors`
+	if command.as_deref() == Some("characterize-motors") {
+		match characterization::characterize(&mut robot, 0.5, Duration::from_secs(1)).and_then(|fit| fit.save(MOTOR_CHARACTERIZATION)) {
+			Ok(_) => println!("Motor characterization saved to {}", MOTOR_CHARACTERIZATION),
+			Err(err) => println!("ERROR: {}", err),
+		}
+		terminate.store(true, Ordering::Relaxed);
+		hal.cleanup();
+		return;
+	}
 
 	// Add a collision detection
-	robot.collision_detection( &mut[ distance::new((hal::GpioChip::GPIO3, 17), (hal::GpioChip::GPIO3, 20)) ] );
+	if let Err(err) = robot.collision_detection( &mut[ distance::new(hal.clone(), (hal::GpioChip::GPIO3, 17), (hal::GpioChip::GPIO3, 20)) ] ) {
+		println!("ERROR: {}", err);
+	}
+
+	// The Mode button cycles through the missions
+	let missions: [&[(f64, f64)]; 2] = [
+		&[(400.0, 0.0), (800.0, 400.0), (0.0, 0.0)],
+		&[(600.0, 200.0), (600.0, 600.0), (200.0, 600.0), (200.0, 200.0)],
+	];
+	let plan = |start: (f64, f64, f64), points: &[(f64, f64)]| {
+		let mut planner = planner::from_points(start, points);
+		planner.set_max_uncertainty(Some(MAX_UNCERTAINTY));
+		planner
+	};
+	let mut mission = 0;
+	let mut start = (200.0, 200.0, 0.0);
+	robot.path_planner(plan(start, missions[mission]));
+
+	// Each wheel is off by about 3mm per metre, the robot pauses once its position gets too uncertain
+	robot.set_odometry_error(OdometryError { left: 0.01, right: 0.01 });
+
+	// Correct the orientation with the compass once it is calibrated
+	if let Ok(calibration) = compass::Calibration::load(COMPASS_CALIBRATION) {
+		heading.set_calibration(calibration);
+		if let Err(err) = robot.add_compass(heading, 0.001) {
+			println!("ERROR: {}", err);
+		}
+	}
+
+	// Brake all motors if the loop below stalls for more than 100ms
+	robot.add_watchdog(watchdog::new(hal.clone(), Duration::from_millis(100)));
 
-	robot.path_planner(planner::from_points((200.0, 200.0, 0.0), &[(400.0, 0.0), (800.0, 400.0), (0.0, 0.0)]));
 	robot.start(true);
 
+	let mut slips = 0;
+	let mut lost = false;
 	while !terminate.load(Ordering::Relaxed) {
 		sleep(Duration::from_millis(1));
-		robot.step();
-	}
+		if let Err(err) = robot.step() {
+			println!("ERROR: {}", err);
+		}
+		if robot.slip_events() > slips {
+			slips = robot.slip_events();
+			if let Some(slip) = robot.slip() {
+				println!("Slip {}: wheels {} and {} faster than measured", slips, slip.velocity_error, slip.yaw_rate_error);
+			}
+		}
+		if robot.needs_localization() && !lost {
+			lost = true;
+			println!("Position uncertain by {}, put the robot back to the start of the mission and press Pause", robot.uncertainty());
+		}
+		if replay.as_ref().is_some_and(|replay| replay.finished()) {
+			terminate.store(true, Ordering::Relaxed);
+		}
 
-	robot.halt();
-	hal::cleanup();
-}
-
-extern "C" fn pause_pressed() {
-	println!("Pause Pressed!");
-}
+		// Pause: pause or resume, restart the mission after a re-localization, long press to quit, double press to reset the watchdog
+		// Mode: start the next mission from where the robot is
+		while let Some(event) = buttons.poll() {
+			match (event.button, event.press) {
+				(hal::Button::Pause, button::Press::Long) => terminate.store(true, Ordering::Relaxed),
+				(hal::Button::Pause, button::Press::Double) => robot.reset_watchdog(),
+				(hal::Button::Pause, _) if robot.is_running() => {
+					if let Err(err) = robot.pause() {
+						println!("ERROR: {}", err);
+					}
+				},
+				(hal::Button::Pause, _) if lost => {
+					lost = false;
+					robot.path_planner(plan(start, missions[mission]));
+					robot.resume();
+				},
+				(hal::Button::Pause, _) => robot.resume(),
+				(hal::Button::Mode, button::Press::Short) => {
+					mission = (mission + 1) % missions.len();
+					println!("Mission {}", mission);
+					// The robot stays where it is, so does the uncertainty of its position
+					let (x, y, phi) = robot.pose();
+					let covariance = robot.covariance();
+					start = (x.as_mm(), y.as_mm(), phi.as_rad());
+					robot.path_planner(plan(start, missions[mission]));
+					robot.set_covariance(covariance);
+				},
+				_ => {},
+			}
+		}
+	}
 
-extern "C" fn mode_pressed() {
-	println!("Mode Pressed!");
+	if let Err(err) = robot.halt() {
+		println!("ERROR: {}", err);
+	}
+	if let Some(replay) = replay {
+		for divergence in replay.divergences() {
+			println!("Diverged at {}", divergence);
+		}
+	}
+	hal.cleanup();
 }
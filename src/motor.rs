@@ -1,75 +1,454 @@
-use std::f32::consts::PI;
+use std::time::{Duration, Instant};
 
+use super::error::Error;
 use super::hal;
+use super::hal::StopMode;
+use super::units::{Angle, Duty, Length, Velocity};
 use super::wheel::Wheel as Wheel;
 use super::wheel::Orientation as Orientation;
 
+/// Time constant of the low-pass filter on the measured wheel velocity, the encoder steps are too coarse without it
+const VELOCITY_FILTER: Duration = Duration::from_millis(20);
+
+/// Gains of the closed-loop velocity control of a wheel, all velocities are in mm/s
+#[derive(Copy, Clone, Debug)]
+pub struct VelocityControl {
+	/// Velocity a speed of 1.0 stands for
+	pub max_velocity: Velocity,
+	/// Proportional gain, duty per mm/s of velocity error
+	pub kp: f64,
+	/// Integral gain, duty per mm of integrated velocity error
+	pub ki: f64,
+	/// Derivative gain, duty per mm/s² of change in the measured velocity
+	pub kd: f64,
+	/// Feedforward duty to overcome the static friction, applied in the direction of the target velocity
+	pub ks: f64,
+	/// Feedforward duty per mm/s of target velocity
+	pub kv: f64,
+	/// Feedforward duty per mm/s² of target acceleration
+	pub ka: f64,
+}
+
+/// Limits of the fault detection of a wheel
+#[derive(Copy, Clone, Debug)]
+pub struct FaultDetection {
+	/// Duty from which on the wheel has to turn, below it the motor may not overcome the friction
+	pub min_duty: Duty,
+	/// How long the wheel may stand still or turn against the duty before it is a fault
+	pub timeout: Duration,
+	/// The wheel can not turn faster, a larger change of the encoder within a step is a glitch
+	pub max_velocity: Velocity,
+}
+
 /// Implementation of a Motor with an attached Wheel
 #[derive(Default)]
 pub(crate) struct Motor {
 	pub(crate) wheel: Wheel,
 	pub(crate) reversed: bool,
+	#[allow(dead_code)]
 	pub(crate) rotations: i32,
-	pub(crate) angle: f32,
+	pub(crate) angle: Angle,
 	pub(crate) last_encoder: i32,
+	/// Maximum change of the duty per second, None for no limit
+	pub(crate) slew_rate: Option<f64>,
+	/// The duty the motor was last set to and when, None if it is stopped
+	pub(crate) duty: Duty,
+	pub(crate) last_command: Option<Instant>,
+	/// The motor put the drivers into standby and has to wake them up again
+	pub(crate) standby: bool,
+	/// Filtered velocity of the wheel
+	pub(crate) velocity: Velocity,
+	/// Closed-loop velocity control, None to send the speed as duty
+	pub(crate) velocity_control: Option<VelocityControl>,
+	/// Integrated velocity error, the target and measured velocity and the time of the last control step
+	pub(crate) integral: f64,
+	pub(crate) last_target: Velocity,
+	pub(crate) last_velocity: Velocity,
+	pub(crate) last_control: Option<Instant>,
+	/// Detect a stalled wheel, a wrong direction or glitches of the encoder, None to not check the wheel
+	pub(crate) fault_detection: Option<FaultDetection>,
+	/// Since when the wheel stands still or turns against the duty
+	pub(crate) stalled_since: Option<Instant>,
+	pub(crate) reversed_since: Option<Instant>,
+	/// When the encoder changed the last time
+	pub(crate) last_motion: Option<Instant>,
 }
 impl Motor {
 	/// Called on every calculation step
 	///
 	/// # Arguments
 	///
-	/// * `duration` - Duration in NS since the last calculation step
+	/// * `hal` - The hardware backend to read the encoder from
+	/// * `duration` - Duration since the last calculation step
 	///
 	/// # Returns
 	///
 	/// A tuple of ( Distance driven since last step, Angle changed since last step )
-	pub(crate) fn step(&mut self, _duration: u128) -> (f32, f32) {
+	/// or an Error if the wheel is not configured or the encoder could not be read
+	pub(crate) fn step(&mut self, hal: &dyn hal::Backend, duration: Duration) -> Result<(Length, Angle), Error> {
 		if let Orientation::UNDEFINED = self.wheel.orientation {
-			println!("ERROR: Undefined Wheel-Orientation for Encoder {} and Motor {}", self.wheel.encoder as i32, self.wheel.motor as i32);
-			return (0.0, 0.0);
+			return Err(Error::InvalidChannel(format!("Undefined Wheel-Orientation for Encoder {} and Motor {}", self.wheel.encoder as i32, self.wheel.motor as i32)));
 		}
 
-		let enc = hal::get_encoder_value(self.wheel.encoder);
+		let enc = hal.get_encoder_value(self.wheel.encoder)?;
 		let diff = enc - self.last_encoder;
 		self.last_encoder = enc;
 
-		let mut angle = diff as f32 / self.wheel.encoder_resolution / self.wheel.gear_ratio;
+		let mut angle = Angle::revolutions(diff as f64 / self.wheel.encoder_resolution / self.wheel.gear_ratio);
 		angle = match self.reversed {
-			true => angle * -1.0,
+			true => -angle,
 			false => angle,
 		};
 
-		self.angle = self.angle + angle;
-		let dist = angle * self.wheel.radius * PI;
+		self.angle += angle;
+		let dist = angle.arc(self.wheel.radius);
+		if let Some(detection) = self.fault_detection {
+			self.check(detection, dist, duration, hal.now())?;
+		}
+
+		// Low-pass filter the velocity, a single encoder step within a short step is a huge jump
+		if !duration.is_zero() {
+			let alpha = duration.as_secs_f64() / (VELOCITY_FILTER + duration).as_secs_f64();
+			self.velocity += (dist / duration - self.velocity) * alpha;
+		}
+
+		Ok((dist, self.angle))
+	}
+
+	/// Check if the wheel turns as the duty it was last set to commands
+	///
+	/// # Arguments
+	///
+	/// * `detection` - The limits of the fault detection
+	/// * `dist` - Distance driven since last step
+	/// * `duration` - Duration since the last calculation step
+	/// * `now` - The time of the backend
+	fn check(&mut self, detection: FaultDetection, dist: Length, duration: Duration, now: Instant) -> Result<(), Error> {
+		// The counts may arrive in bursts, so the jump is measured since the last motion, but at most over the timeout.
+		// A single count more is the quantization of the encoder.
+		let elapsed = self.last_motion.map_or(duration, |last| now.duration_since(last).min(detection.timeout)).max(duration);
+		let count = Angle::revolutions(1.0 / self.wheel.encoder_resolution / self.wheel.gear_ratio).arc(self.wheel.radius);
+		if dist.abs() > detection.max_velocity * elapsed + count {
+			return Err(Error::Fault(format!("{}: encoder jumped {} within {:?}", self.name(), dist, elapsed)));
+		}
+		if dist != Length::ZERO {
+			self.last_motion = Some(now);
+		}
+
+		let duty = self.duty.get();
+		if self.duty.abs() < detection.min_duty {
+			self.stalled_since = None;
+			self.reversed_since = None;
+			return Ok(());
+		}
+
+		// A single step without or against the motion is normal at slow speeds and on a change of the direction
+		let moved = dist.as_mm();
+		if moved == 0.0 {
+			self.stalled_since.get_or_insert(now);
+		} else if moved.signum() != duty.signum() {
+			self.stalled_since = None;
+			self.reversed_since.get_or_insert(now);
+		} else {
+			self.stalled_since = None;
+			self.reversed_since = None;
+		}
 
-		(dist, self.angle)
+		if self.stalled_since.is_some_and(|since| now.duration_since(since) > detection.timeout) {
+			return Err(Error::Fault(format!("{} does not turn with a duty of {}, it is stalled or the encoder is disconnected", self.name(), duty)));
+		}
+		if self.reversed_since.is_some_and(|since| now.duration_since(since) > detection.timeout) {
+			return Err(Error::Fault(format!("{} turns against a duty of {}, check the wiring and if it is reversed", self.name(), duty)));
+		}
+		Ok(())
+	}
+
+	/// Name of the wheel for messages, with the motor as there may be several wheels on a side
+	fn name(&self) -> String {
+		let side = match self.wheel.orientation {
+			Orientation::LEFT => "Left",
+			Orientation::RIGHT => "Right",
+			Orientation::UNDEFINED => "Undefined",
+		};
+		format!("{} wheel ({:?})", side, self.wheel.motor)
 	}
 
 	/// Get the total distance this motor and wheel drove
-	pub(crate) fn total_distance(&self) -> f32 {
-		self.angle * self.wheel.radius * PI
+	pub(crate) fn total_distance(&self) -> Length {
+		self.angle.arc(self.wheel.radius)
+	}
+
+	/// Get the duty for a speed, with a velocity control the duty which drives the wheel with this speed
+	///
+	/// Has to be called once per step after `step`, the integral of the velocity control would wind up otherwise
+	///
+	/// # Arguments
+	///
+	/// * `hal` - The hardware backend whose time the control steps are measured with
+	/// * `speed` - The speed for this motor
+	pub(crate) fn duty(&mut self, hal: &dyn hal::Backend, speed: Duty) -> Duty {
+		let control = match self.velocity_control {
+			Some(control) => control,
+			None => return speed,
+		};
+
+		let now = hal.now();
+		let dt = self.last_control.map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+		self.last_control = Some(now);
+
+		let target = control.max_velocity * speed.get();
+		let error = (target - self.velocity).as_mm_per_s();
+		let friction = match target.as_mm_per_s() {
+			target if target > 0.0 => control.ks,
+			target if target < 0.0 => -control.ks,
+			_ => 0.0,
+		};
+		let acceleration = match dt > 0.0 {
+			true => (target - self.last_target).as_mm_per_s() / dt,
+			false => 0.0,
+		};
+		self.last_target = target;
+		let feedforward = friction + control.kv * target.as_mm_per_s() + control.ka * acceleration;
+		// The derivative of the measurement does not kick on a change of the target
+		let derivative = match dt > 0.0 {
+			true => -control.kd * (self.velocity - self.last_velocity).as_mm_per_s() / dt,
+			false => 0.0,
+		};
+		self.last_velocity = self.velocity;
+
+		// Anti-windup: only integrate while the output is not saturated in the direction of the error
+		let integral = self.integral + control.ki * error * dt;
+		let output = feedforward + control.kp * error + integral + derivative;
+		if output.abs() <= 1.0 || output.signum() != error.signum() {
+			self.integral = integral;
+		}
+
+		Duty::new(feedforward + control.kp * error + self.integral + derivative)
 	}
 
 	/// Set the speed of the motor
 	///
-	/// The value can go from -1.0 up to 1.0
-	/// where minus values will drive backward
+	/// Negative duties will drive backward.
+	/// With a slew-rate limit the duty only moves towards the speed by the time passed since the last call,
+	/// so it has to be called repeatedly until the speed is reached.
 	///
 	/// # Arguments
 	///
+	/// * `hal` - The hardware backend to drive the motor with
 	/// * `speed` - The speed for this motor
-	pub(crate) fn set_speed(&self, speed: f64) {
-		let mut duty = if speed > 1.0 { 1.0 } else { speed };
-		duty = if speed < -1.0 { -1.0 } else { duty };
-		hal::run_motor(self.wheel.motor, match self.reversed {
-			true => -1.0 * duty,
-			false => duty,
-		});
+	pub(crate) fn set_speed(&mut self, hal: &dyn hal::Backend, speed: Duty) -> Result<(), Error> {
+		if self.standby {
+			hal.motor_standby(false)?;
+			self.standby = false;
+		}
+
+		let now = hal.now();
+		let mut duty = speed;
+		if let Some(rate) = self.slew_rate {
+			let elapsed = self.last_command.map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+			let max_change = rate * elapsed;
+			duty = Duty::new(duty.get().clamp(self.duty.get() - max_change, self.duty.get() + max_change));
+		}
+
+		hal.run_motor(self.wheel.motor, match self.reversed {
+			true => -duty.get(),
+			false => duty.get(),
+		})?;
+		self.duty = duty;
+		self.last_command = Some(now);
+		Ok(())
+	}
+
+	/// Stop the motor, the stop is immediate even with a slew-rate limit
+	///
+	/// # Arguments
+	///
+	/// * `hal` - The hardware backend to stop the motor with
+	/// * `mode` - Brake, let the motor spin freely or put the drivers into standby
+	pub(crate) fn stop(&mut self, hal: &dyn hal::Backend, mode: StopMode) -> Result<(), Error> {
+		match mode {
+			StopMode::Brake => hal.brake_motor(self.wheel.motor)?,
+			StopMode::FreeSpin => hal.free_spin_motor(self.wheel.motor)?,
+			StopMode::Standby => {
+				hal.motor_standby(true)?;
+				self.standby = true;
+			},
+		}
+		// The ramp and the velocity control start with the next command, not with the stop
+		self.duty = Duty::ZERO;
+		self.last_command = None;
+		self.integral = 0.0;
+		self.last_target = Velocity::ZERO;
+		self.last_control = None;
+		self.stalled_since = None;
+		self.reversed_since = None;
+		self.last_motion = None;
+		Ok(())
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::Backend;
+	use super::super::hal::simulated::Simulated;
+	use super::super::simulator;
+
+	/// Time between two control steps
+	const STEP: Duration = Duration::from_millis(10);
+
+	/// A control with only the given gains, all velocities up to 1000 mm/s
+	fn control(kp: f64, ki: f64, kd: f64) -> VelocityControl {
+		VelocityControl { max_velocity: Velocity::mm_per_s(1000.0), kp, ki, kd, ks: 0.0, kv: 0.0, ka: 0.0 }
+	}
+
+	/// A left 40mm wheel on the third motor and encoder
+	fn motor(velocity_control: Option<VelocityControl>, fault_detection: Option<FaultDetection>) -> Motor {
+		Motor {
+			wheel: Wheel::left(Length::mm(40.0), hal::Encoder::ENCODER3, hal::Motor::MOTOR3, 3441.0 / 104.0, 32.0),
+			velocity_control,
+			fault_detection,
+			..Motor::default()
+		}
+	}
+
+	/// Limits as configured on the robot
+	fn detection() -> FaultDetection {
+		FaultDetection {
+			min_duty: Duty::new(0.15),
+			timeout: Duration::from_millis(300),
+			max_velocity: Velocity::mm_per_s(2000.0),
+		}
+	}
+
+	/// A motor with fault detection running with the duty on a backend whose time is advanced by hand
+	fn running(duty: f64) -> (Simulated, Motor) {
+		let hal = Simulated::default();
+		hal.init_encoders().and_then(|_| hal.init_motors()).unwrap();
+		let mut motor = motor(None, Some(detection()));
+		motor.set_speed(&hal, Duty::new(duty)).unwrap();
+		(hal, motor)
 	}
 
-	/// Stop the motor and brake
-	pub(crate) fn stop(&self) {
-		hal::brake_motor(self.wheel.motor);
+	/// Move the encoder by the counts within a step and check the wheel
+	fn step(hal: &Simulated, motor: &mut Motor, counts: i32) -> Result<(Length, Angle), Error> {
+		hal.advance(STEP);
+		hal.set_encoder_value(motor.wheel.encoder, motor.last_encoder + counts);
+		motor.step(hal, STEP)
 	}
 
+	/// Check that the error is a fault with the message
+	fn assert_fault(result: Result<(Length, Angle), Error>, message: &str) {
+		match result {
+			Err(Error::Fault(fault)) => assert!(fault.contains(message), "{}", fault),
+			other => panic!("Expected a fault '{}', got {:?}", message, other),
+		}
+	}
+
+	#[test]
+	fn faults_on_a_stalled_wheel_after_the_timeout() {
+		let (hal, mut motor) = running(0.5);
+		for _ in 0..5 {
+			step(&hal, &mut motor, 10).unwrap();
+		}
+		// Stalled from the first step without motion on, a step ends exactly on the timeout
+		for _ in 0..=30 {
+			step(&hal, &mut motor, 0).unwrap();
+		}
+		assert_fault(step(&hal, &mut motor, 0), "does not turn");
+	}
+
+	#[test]
+	fn faults_on_a_wheel_turning_against_the_duty() {
+		let (hal, mut motor) = running(0.5);
+		for _ in 0..=30 {
+			step(&hal, &mut motor, -10).unwrap();
+		}
+		assert_fault(step(&hal, &mut motor, -10), "turns against");
+	}
+
+	#[test]
+	fn accepts_a_burst_of_counts_after_a_pause() {
+		let (hal, mut motor) = running(0.5);
+		for _ in 0..5 {
+			step(&hal, &mut motor, 10).unwrap();
+		}
+		for _ in 0..20 {
+			step(&hal, &mut motor, 0).unwrap();
+		}
+		// 400 counts are about 95mm, which the wheel can drive in the pause but not within a single step
+		step(&hal, &mut motor, 400).unwrap();
+		assert_fault(step(&hal, &mut motor, 400), "encoder jumped");
+	}
+
+	#[test]
+	fn does_not_check_below_the_minimum_duty() {
+		let (hal, mut motor) = running(0.1);
+		for _ in 0..100 {
+			step(&hal, &mut motor, 0).unwrap();
+		}
+		for _ in 0..100 {
+			step(&hal, &mut motor, -1).unwrap();
+		}
+	}
+
+	#[test]
+	fn freezes_the_integral_while_saturated() {
+		let hal = Simulated::default();
+		let mut motor = motor(Some(control(0.01, 0.01, 0.0)), None);
+		motor.duty(&hal, Duty::new(1.0));
+		for _ in 0..100 {
+			hal.advance(STEP);
+			assert_eq!(motor.duty(&hal, Duty::new(1.0)), Duty::new(1.0));
+			assert_eq!(motor.integral, 0.0);
+		}
+
+		// Saturated against the error it integrates to get out of the saturation
+		motor.velocity = Velocity::mm_per_s(1000.0);
+		motor.integral = 10.0;
+		hal.advance(STEP);
+		motor.duty(&hal, Duty::new(0.5));
+		assert!(motor.integral < 10.0, "integral {}", motor.integral);
+
+		// Not saturated it integrates
+		motor.velocity = Velocity::mm_per_s(550.0);
+		motor.integral = 0.0;
+		hal.advance(STEP);
+		motor.duty(&hal, Duty::new(0.5));
+		assert!((motor.integral + 0.005).abs() < 1e-9, "integral {}", motor.integral);
+	}
+
+	#[test]
+	fn does_not_kick_on_a_step_of_the_target() {
+		let hal = Simulated::default();
+		let mut motor = motor(Some(control(0.0, 0.0, 0.001)), None);
+		motor.duty(&hal, Duty::ZERO);
+		hal.advance(STEP);
+		assert_eq!(motor.duty(&hal, Duty::new(0.5)), Duty::ZERO);
+
+		// A change of the measured velocity is damped
+		motor.velocity = Velocity::mm_per_s(5.0);
+		hal.advance(STEP);
+		assert!((motor.duty(&hal, Duty::new(0.5)).get() + 0.5).abs() < 1e-9);
+	}
+
+	#[test]
+	fn tracks_the_target_velocity() {
+		let mut sim = simulator::new(Length::mm(150.0));
+		let mut motor = motor(Some(VelocityControl { ks: 0.03, kv: 0.0008, ..control(0.001, 0.01, 0.0) }), None);
+		sim.add_wheel(&motor.wheel, false, simulator::MotorModel::default());
+		let hal = sim.backend();
+		hal.init_encoders().and_then(|_| hal.init_motors()).unwrap();
+
+		for _ in 0..300 {
+			sim.advance(STEP);
+			motor.step(&*hal, STEP).unwrap();
+			let duty = motor.duty(&*hal, Duty::new(0.3));
+			motor.set_speed(&*hal, duty).unwrap();
+		}
+		let (velocity, _) = sim.wheel_velocities();
+		assert!((velocity - 300.0).abs() < 10.0, "velocity {}", velocity);
+		assert!((motor.velocity.as_mm_per_s() - 300.0).abs() < 15.0, "measured {}", motor.velocity);
+	}
 }
@@ -1,3 +1,5 @@
+use super::position::{self, Covariance};
+use super::units::Length;
 
 /// Create a path planner based on points
 ///
@@ -9,9 +11,11 @@
 /// # Result
 ///
 /// A prepared Planner instance
-pub fn from_points(init: (f64, f64, f32), points: &[(f64, f64)]) -> Planner {
-	let mut planner = Planner::default();
-	planner.start = init;
+pub fn from_points(init: (f64, f64, f64), points: &[(f64, f64)]) -> Planner {
+	let mut planner = Planner {
+		start: init,
+		..Default::default()
+	};
 	for point in points {
 		planner.push(point.0, point.1);
 	}
@@ -21,9 +25,10 @@ pub fn from_points(init: (f64, f64, f32), points: &[(f64, f64)]) -> Planner {
 /// Struct which identifies a path planner
 #[derive(Default)]
 pub struct Planner {
-	pub(crate) start: (f64, f64, f32),
+	pub(crate) start: (f64, f64, f64),
 	points: Vec<(f64, f64)>,
 	pos: usize,
+	max_uncertainty: Option<Length>,
 }
 
 impl Planner {
@@ -42,7 +47,7 @@ impl Planner {
 	/// # Result
 	///
 	/// A tuple ith the X- and Y-Coordinate and the Orientation
-	pub fn start(&self) -> (f64, f64, f32) {
+	pub fn start(&self) -> (f64, f64, f64) {
 		(self.start.0, self.start.1, self.start.2)
 	}
 
@@ -51,6 +56,29 @@ impl Planner {
 		self.pos = 0;
 	}
 
+	/// Check if the last goal was returned
+	pub fn is_finished(&self) -> bool {
+		self.pos >= self.points.len()
+	}
+
+	/// Ask for a re-localization once the position of the robot gets too uncertain
+	///
+	/// # Arguments
+	///
+	/// * `max` - Largest standard deviation of the position, None to never ask
+	pub fn set_max_uncertainty(&mut self, max: Option<Length>) {
+		self.max_uncertainty = max;
+	}
+
+	/// Check if the robot has to be re-localized before it drives on
+	///
+	/// # Arguments
+	///
+	/// * `covariance` - Covariance of the current pose
+	pub fn needs_localization(&self, covariance: &Covariance) -> bool {
+		self.max_uncertainty.is_some_and(|max| position::uncertainty(covariance) > max)
+	}
+
 	/// Returns the next goal to reach
 	///
 	/// # Result
@@ -60,7 +88,7 @@ impl Planner {
 	pub fn next_goal(&mut self) -> Result<(f64, f64), &str> {
 		if self.points.len() > self.pos {
 			let index = self.pos;
-			self.pos = self.pos + 1;
+			self.pos += 1;
 			Ok(self.points[index])
 		} else {
 			Err("No more goals")
@@ -1,14 +1,55 @@
-/// +/- number if mm around the goal meand the goal is reached
-const GOAL_AREA: f64 = 10.0;
+use super::units::{Angle, Duty, Length};
+
+/// +/- distance around the goal means the goal is reached
+const GOAL_AREA: Length = Length::mm(10.0);
+
+/// How the motion of a step is integrated into the position
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Integration {
+	/// Move along the old heading, then turn, the error grows with the turn within a step
+	Euler,
+	/// Move along the heading in the middle of the step (second-order Runge-Kutta)
+	RungeKutta,
+	/// Move along the circular arc the wheels drove, exact for a constant curvature within a step
+	#[default]
+	ExactArc,
+}
+
+/// Covariance of the pose, the rows and columns are X in mm², Y in mm² and the orientation in rad²
+pub type Covariance = [[f64; 3]; 3];
+
+/// Random error of the distances the wheels report, the variance grows proportional to the distance driven
+#[derive(Default, Copy, Clone, Debug)]
+pub struct OdometryError {
+	/// Variance of the distance of the left side in mm² per mm driven
+	pub left: f64,
+	/// Variance of the distance of the right side in mm² per mm driven
+	pub right: f64,
+}
+
+/// Standard deviation of the position along the direction it is most uncertain in
+///
+/// # Arguments
+///
+/// * `covariance` - Covariance of the pose
+pub fn uncertainty(covariance: &Covariance) -> Length {
+	let (xx, xy, yy) = (covariance[0][0], covariance[0][1], covariance[1][1]);
+	let largest = (xx + yy) / 2.0 + ((xx - yy) / 2.0).hypot(xy);
+	Length::mm(largest.max(0.0).sqrt())
+}
 
 /// Position of the Robot in the World and it's orientation
 #[derive(Default)]
 pub struct Position {
-	pub(crate) x: f64,
-	pub(crate) y: f64,
-	pub(crate) goal_x: f64,
-	pub(crate) goal_y: f64,
-	pub(crate) phi: f32,
+	pub(crate) x: Length,
+	pub(crate) y: Length,
+	pub(crate) goal_x: Length,
+	pub(crate) goal_y: Length,
+	pub(crate) phi: Angle,
+	pub(crate) gyro_weight: f64,
+	pub(crate) integration: Integration,
+	pub(crate) odometry_error: OdometryError,
+	pub(crate) covariance: Covariance,
 }
 
 impl Position {
@@ -18,17 +59,11 @@ impl Position {
 	///
 	/// * `x` - X-Position where the robot is at the moment
 	/// * `y` - Y-Position where the robot is at the moment
-	/// * `phi` - The robots alignment in rad
-	pub fn set_position(&mut self, x: f64, y: f64, phi: f32) {
-		match phi {
-			phi if phi > std::f32::consts::PI => self.set_position(x, y, phi - std::f32::consts::PI),
-			phi if phi < -std::f32::consts::PI => self.set_position(x, y, phi + std::f32::consts::PI),
-			_ => {
-				self.x = x;
-				self.y = y;
-				self.phi = phi;
-			}
-		}
+	/// * `phi` - The robots alignment
+	pub fn set_position(&mut self, x: Length, y: Length, phi: Angle) {
+		self.x = x;
+		self.y = y;
+		self.phi = phi.normalized();
 	}
 
 	/// Set the goal which should be reached
@@ -37,60 +72,151 @@ impl Position {
 	///
 	/// * `x` - X-Position to reach
 	/// * `y` - Y-Position to reach
-	pub fn set_goal(&mut self, x: f64, y: f64) {
+	pub fn set_goal(&mut self, x: Length, y: Length) {
 		self.goal_x = x;
 		self.goal_y = y;
 	}
 
+	/// Set how much the gyro is trusted compared to the encoders when calculating the orientation
+	///
+	/// # Arguments
+	///
+	/// * `weight` - 0.0 uses only the encoders, 1.0 only the gyro
+	pub fn set_gyro_weight(&mut self, weight: f64) {
+		self.gyro_weight = weight.clamp(0.0, 1.0);
+	}
+
+	/// Set how the motion of a step is integrated, the default is the exact arc
+	pub fn set_integration(&mut self, integration: Integration) {
+		self.integration = integration;
+	}
+
+	/// Set the error model of the wheels, the covariance stays zero without one
+	pub fn set_odometry_error(&mut self, error: OdometryError) {
+		self.odometry_error = error;
+	}
+
+	/// Set the covariance of the pose, e.g. after a re-localization
+	pub fn set_covariance(&mut self, covariance: Covariance) {
+		self.covariance = covariance;
+	}
+
 	/// Given the distance the left and right wheel travelled, the new Position of the robot is calculated and set
 	///
 	/// # Arguments
 	///
-	/// * `left` - Number of mm the left Wheel was driven
-	/// * `right` - Number of mm the right Wheel was driven
-	/// * `wheel_distance` - Number of mm the left and right wheels are apart from each other
-	pub fn calculate_position(&mut self, left: f32, right: f32, wheel_distance: f32) {
+	/// * `left` - Distance the left Wheel was driven
+	/// * `right` - Distance the right Wheel was driven
+	/// * `wheel_distance` - Distance the left and right wheels are apart from each other
+	/// * `gyro_angle` - Angle the gyro measured since the last calculation, if available
+	pub fn calculate_position(&mut self, left: Length, right: Length, wheel_distance: Length, gyro_angle: Option<Angle>) {
 		// Distance the center of the robot travelled
 		let distance = (left + right) / 2.0;
 
+		let wheel_angle = Angle::from_arc(right - left, wheel_distance);
+		let delta_angle = match gyro_angle {
+			Some(gyro) => gyro * self.gyro_weight + wheel_angle * (1.0 - self.gyro_weight),
+			None => wheel_angle,
+		};
+
 		// Delta values compared to the last position
-		let delta_x = self.phi.cos() * distance;
-		let delta_y = self.phi.sin() * distance;
-		let delta_angle = (right - left) / wheel_distance;
+		let half_angle = delta_angle / 2.0;
+		let (heading, length) = match self.integration {
+			Integration::Euler => (self.phi, distance),
+			Integration::RungeKutta => (self.phi + half_angle, distance),
+			// The chord of the arc points along the middle heading and is shorter than the arc by sin(x) / x
+			Integration::ExactArc => match half_angle.abs() < Angle::rad(1e-6) {
+				true => (self.phi + half_angle, distance * (1.0 - half_angle.as_rad().powi(2) / 6.0)),
+				false => (self.phi + half_angle, distance * (half_angle.sin() / half_angle.as_rad())),
+			},
+		};
+		let delta_x = length * heading.cos();
+		let delta_y = length * heading.sin();
 
+		self.propagate_covariance(left, right, wheel_distance, self.phi + half_angle, distance);
 		self.set_position(
-			self.x + delta_x as f64,
-			self.y + delta_y as f64,
+			self.x + delta_x,
+			self.y + delta_y,
 			self.phi + delta_angle
 		);
 	}
 
+	/// Propagate the covariance through a step of the odometry and add the error of the wheels
+	///
+	/// Only the wheels are modelled, the gyro and the compass are left out, so the uncertainty rather comes out too large.
+	///
+	/// # Arguments
+	///
+	/// * `left` - Distance the left Wheel was driven
+	/// * `right` - Distance the right Wheel was driven
+	/// * `wheel_distance` - Distance the left and right wheels are apart from each other
+	/// * `heading` - Orientation in the middle of the step
+	/// * `distance` - Distance the center of the robot travelled
+	fn propagate_covariance(&mut self, left: Length, right: Length, wheel_distance: Length, heading: Angle, distance: Length) {
+		let (sin, cos) = (heading.sin(), heading.cos());
+		let distance = distance.as_mm();
+		let turn = 1.0 / wheel_distance.as_mm();
+
+		// Change of the pose by the old pose and by the distance of each wheel
+		let pose = [[1.0, 0.0, -distance * sin], [0.0, 1.0, distance * cos], [0.0, 0.0, 1.0]];
+		let half_turn = distance * turn / 2.0;
+		let wheels = [
+			([cos / 2.0 + half_turn * sin, sin / 2.0 - half_turn * cos, -turn], self.odometry_error.left * left.abs().as_mm()),
+			([cos / 2.0 - half_turn * sin, sin / 2.0 + half_turn * cos, turn], self.odometry_error.right * right.abs().as_mm()),
+		];
+
+		let old = self.covariance;
+		for (i, row) in self.covariance.iter_mut().enumerate() {
+			for (j, value) in row.iter_mut().enumerate() {
+				let propagated: f64 = (0..3).map(|k| (0..3).map(|l| pose[i][k] * old[k][l] * pose[j][l]).sum::<f64>()).sum();
+				let added: f64 = wheels.iter().map(|(jacobian, variance)| jacobian[i] * jacobian[j] * variance).sum();
+				*value = propagated + added;
+			}
+		}
+	}
+
+	/// Pull the orientation towards an absolute heading, e.g. from a compass
+	///
+	/// # Arguments
+	///
+	/// * `heading` - The measured heading
+	/// * `weight` - How much of the difference is corrected [0.0 - 1.0]
+	pub fn correct_heading(&mut self, heading: Angle, weight: f64) {
+		let diff = (heading - self.phi).normalized();
+		self.set_position(self.x, self.y, self.phi + diff * weight.clamp(0.0, 1.0));
+	}
+
 	/// Calculates the velocities for a left and right wheel to the goal
 	///
 	/// # Arguments
 	///
-	/// * `wheel_distance` - Number of mm the left and right wheels are apart from each other
+	/// * `wheel_distance` - Distance the left and right wheels are apart from each other
 	///
 	/// # Result
 	///
-	/// A tuple with the (left, right) velocity to the end [ -1.0 - 1.0]
-	pub fn get_goal_velocities(&self, wheel_distance: f32) -> (f64, f64) {
+	/// A tuple with the (left, right) velocity to the end
+	pub fn get_goal_velocities(&self, _wheel_distance: Length) -> (Duty, Duty) {
 		let delta_x = self.goal_x - self.x;
 		let delta_y = self.goal_y - self.y;
-		let phi = delta_y.atan2(delta_x);
-		let delta_phi = phi - self.phi as f64;
-		let distance = ( delta_x.powi(2) + delta_y.powi(2) ).sqrt();
+		let phi = Angle::atan2(delta_y, delta_x);
+		let delta_phi = phi - self.phi;
+		let _distance = delta_x.hypot(delta_y);
 
 		// Factor based on delta-phi to define how faster/slower the right/left wheel should drive
-		let fact = delta_phi.abs() / std::f64::consts::PI;
+		let fact = delta_phi.abs().as_rad() / std::f64::consts::PI;
 
 		let speed = 0.05;
-		match delta_phi.is_sign_negative() {
-			true  => (speed * (2.0 + fact), speed * (1.0 - fact)),
-			false => (speed * (1.0 - fact), speed * (2.0 + fact))
+		match delta_phi.as_rad().is_sign_negative() {
+			true  => (Duty::new(speed * (2.0 + fact)), Duty::new(speed * (1.0 - fact))),
+			false => (Duty::new(speed * (1.0 - fact)), Duty::new(speed * (2.0 + fact)))
 		}
 	}
 
+	/// Distance from the position to the goal
+	pub fn goal_distance(&self) -> Length {
+		(self.goal_x - self.x).hypot(self.goal_y - self.y)
+	}
+
 	/// Check if the goal is reached
 	/// The goal is reached in a area of GOAL_AREA around the real goal
 	pub fn goal_reached(&self) -> bool {
@@ -100,33 +226,115 @@ impl Position {
 
 	/// Debug output for visualize the position and orientation
 	pub fn debug(&self) {
-		println!("{};{};{};{};{}", self.goal_x, self.goal_y, self.x, self.y, Self::degree(self.phi.into()));
+		println!("{};{};{};{};{}", self.goal_x.as_mm(), self.goal_y.as_mm(), self.x.as_mm(), self.y.as_mm(), self.phi.as_deg());
 	}
 
-	/// Convert a radian into a degree
-	///
-	/// # Arguments
-	///
-	/// * `rad` - Radian Value to convert
-	///
-	/// # Result
-	///
-	/// The angle in degree
-	pub fn degree(rad: f64) -> f64 {
-		(rad * 180.0) / std::f64::consts::PI
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+	const WHEEL_DISTANCE: Length = Length::mm(100.0);
+
+	/// Drive the same distances of the wheels a number of steps, starting at the given pose
+	fn drive(integration: Integration, start: (f64, f64, f64), left: f64, right: f64, steps: usize) -> (f64, f64, f64) {
+		let mut position = Position::default();
+		position.set_integration(integration);
+		position.set_position(Length::mm(start.0), Length::mm(start.1), Angle::rad(start.2));
+		for _ in 0..steps {
+			position.calculate_position(Length::mm(left), Length::mm(right), WHEEL_DISTANCE, None);
+		}
+		(position.x.as_mm(), position.y.as_mm(), position.phi.as_rad())
 	}
 
-	/// Convert a degree into a radian
-	///
-	/// # Arguments
-	///
-	/// * `deg` - Degree Value to convert
-	///
-	/// # Result
-	///
-	/// The angle in radian
-	pub fn radian(deg: f64) -> f64 {
-		(deg * std::f64::consts::PI) / 180.0
+	fn assert_pose(pose: (f64, f64, f64), expected: (f64, f64, f64), tolerance: f64) {
+		assert!((pose.0 - expected.0).abs() <= tolerance, "X {} instead of {}", pose.0, expected.0);
+		assert!((pose.1 - expected.1).abs() <= tolerance, "Y {} instead of {}", pose.1, expected.1);
+		assert!((pose.2 - expected.2).abs() <= tolerance, "Phi {} instead of {}", pose.2, expected.2);
 	}
 
+	#[test]
+	fn drives_straight_lines() {
+		for integration in [Integration::Euler, Integration::RungeKutta, Integration::ExactArc] {
+			let pose = drive(integration, (10.0, -20.0, FRAC_PI_4), 1.0, 1.0, 1000);
+			assert_pose(pose, (10.0 + 1000.0 * FRAC_PI_4.cos(), -20.0 + 1000.0 * FRAC_PI_4.sin(), FRAC_PI_4), 1e-9);
+		}
+	}
+
+	#[test]
+	fn rotates_in_place() {
+		// Each step turns by 2mm / 100mm = 0.02 rad, 100 steps are 2 rad
+		for integration in [Integration::Euler, Integration::RungeKutta, Integration::ExactArc] {
+			let pose = drive(integration, (50.0, 50.0, 0.0), -1.0, 1.0, 100);
+			assert_pose(pose, (50.0, 50.0, 2.0), 1e-9);
+		}
+		let pose = drive(Integration::ExactArc, (0.0, 0.0, 3.0), 1.0, -1.0, 100);
+		assert_pose(pose, (0.0, 0.0, 1.0), 1e-9);
+	}
+
+	#[test]
+	fn drives_arcs_exactly() {
+		// A quarter circle with a radius of 500mm around (0, 500) ends at (500, 500) facing along Y
+		let radius = 500.0;
+		for steps in [1, 7, 1000] {
+			let angle = FRAC_PI_2 / steps as f64;
+			let (left, right) = ((radius - 50.0) * angle, (radius + 50.0) * angle);
+			let pose = drive(Integration::ExactArc, (0.0, 0.0, 0.0), left, right, steps);
+			assert_pose(pose, (radius, radius, FRAC_PI_2), 1e-9);
+		}
+
+		// A full clockwise circle in three steps returns to the start, the orientation wraps on the way
+		let angle = 2.0 * PI / 3.0;
+		let pose = drive(Integration::ExactArc, (100.0, 100.0, 0.0), (200.0 + 50.0) * angle, (200.0 - 50.0) * angle, 3);
+		assert_pose(pose, (100.0, 100.0, 0.0), 1e-9);
+	}
+
+	#[test]
+	fn approximates_arcs() {
+		// Euler is first order and Runge-Kutta second order in the turn per step
+		let radius = 500.0;
+		let error = |integration, steps: usize| {
+			let angle = FRAC_PI_2 / steps as f64;
+			let pose = drive(integration, (0.0, 0.0, 0.0), (radius - 50.0) * angle, (radius + 50.0) * angle, steps);
+			(pose.0 - radius).hypot(pose.1 - radius)
+		};
+		assert!(error(Integration::Euler, 100) > 1.0);
+		assert!(error(Integration::Euler, 1000) < error(Integration::Euler, 100) / 5.0);
+		assert!(error(Integration::RungeKutta, 100) < 0.1);
+		assert!(error(Integration::RungeKutta, 1000) < error(Integration::RungeKutta, 100) / 50.0);
+	}
+
+	#[test]
+	fn propagates_uncertainty() {
+		let error = OdometryError { left: 0.01, right: 0.01 };
+		let mut position = Position::default();
+		for _ in 0..1000 {
+			position.calculate_position(Length::mm(1.0), Length::mm(1.0), WHEEL_DISTANCE, None);
+		}
+		assert_eq!(position.covariance, Covariance::default(), "No uncertainty without an error model");
+
+		// Along the line the variances of the wheels add up, across it the error of the heading grows with the distance
+		position.set_odometry_error(error);
+		for _ in 0..1000 {
+			position.calculate_position(Length::mm(1.0), Length::mm(1.0), WHEEL_DISTANCE, None);
+		}
+		let covariance = position.covariance;
+		assert!((covariance[0][0] - 5.0).abs() < 1e-9, "X variance {}", covariance[0][0]);
+		assert!((covariance[2][2] - 2e-3).abs() < 1e-12, "Phi variance {}", covariance[2][2]);
+		assert!(covariance[1][1] > 600.0 && covariance[1][1] < 700.0, "Y variance {}", covariance[1][1]);
+		assert!((uncertainty(&covariance).as_mm() - covariance[1][1].sqrt()).abs() < 1e-6);
+
+		// Turning in place does not move the robot, so the position only gets a quarter of the variance of both wheels
+		// on each step, while the uncertainty of the orientation grows
+		position.set_covariance(Covariance::default());
+		for _ in 0..100 {
+			position.calculate_position(Length::mm(-1.0), Length::mm(1.0), WHEEL_DISTANCE, None);
+		}
+		let covariance = position.covariance;
+		assert!((covariance[0][0] + covariance[1][1] - 0.5).abs() < 1e-9, "Position variance {}", covariance[0][0] + covariance[1][1]);
+		assert!((covariance[2][2] - 2e-4).abs() < 1e-12, "Phi variance {}", covariance[2][2]);
+	}
 }
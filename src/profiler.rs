@@ -0,0 +1,242 @@
+use super::units::{AngularVelocity, Duty, Length, Velocity};
+
+use std::time::Instant;
+
+/// Limits of the motion of the robot as a whole
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+	/// Velocity a speed of 1.0 stands for, like `VelocityControl::max_velocity`
+	pub full_speed: Velocity,
+	/// Fastest forward or backward velocity
+	pub max_velocity: Velocity,
+	/// Largest change of the velocity in mm/s²
+	pub max_acceleration: f64,
+	/// Largest change of the acceleration in mm/s³
+	pub max_jerk: f64,
+	/// Fastest turn
+	pub max_angular_velocity: AngularVelocity,
+	/// Largest change of the angular velocity in rad/s²
+	pub max_angular_acceleration: f64,
+	/// Largest change of the angular acceleration in rad/s³
+	pub max_angular_jerk: f64,
+}
+
+/// Create a velocity profiler, the robot starts at rest
+///
+/// # Arguments
+///
+/// * `limits` - The limits of the velocity, the acceleration and the jerk
+pub fn new(limits: Limits) -> Profiler {
+	Profiler {
+		limits,
+		linear: Axis::default(),
+		angular: Axis::default(),
+		last_step: None,
+	}
+}
+
+/// Velocity and acceleration of one degree of freedom of the robot
+#[derive(Copy, Clone, Default)]
+struct Axis {
+	velocity: f64,
+	acceleration: f64,
+}
+
+impl Axis {
+	/// Move the velocity towards the target within the limits
+	///
+	/// # Arguments
+	///
+	/// * `target` - The wanted velocity
+	/// * `max_velocity` - The target is limited to this velocity
+	/// * `max_acceleration` - Largest change of the velocity per second
+	/// * `max_jerk` - Largest change of the acceleration per second
+	/// * `dt` - Time in seconds since the last step
+	fn step(&mut self, target: f64, max_velocity: f64, max_acceleration: f64, max_jerk: f64, dt: f64) {
+		let target = target.clamp(-max_velocity, max_velocity);
+		let error = target - self.velocity;
+		if error == 0.0 {
+			self.acceleration = 0.0;
+			return;
+		}
+
+		// Accelerate only as much as can be taken back with the jerk limit until the target is reached,
+		// in steps of dt the acceleration a changes the velocity by a/2 * (a/j + dt) until it is back to zero
+		let acceleration = max_jerk * ((dt * dt / 4.0 + 2.0 * error.abs() / max_jerk).sqrt() - dt / 2.0);
+		let acceleration = acceleration.min(max_acceleration).copysign(error);
+		let change = max_jerk * dt;
+		self.acceleration += (acceleration - self.acceleration).clamp(-change, change);
+
+		// The target is not overshot, the acceleration is almost zero when it is reached and drops to zero on the next step
+		let velocity = self.velocity + self.acceleration * dt;
+		self.velocity = match (target - velocity).signum() == error.signum() {
+			true => velocity,
+			false => target,
+		};
+	}
+
+	/// The distance it takes to come to rest in steps of dt from the current velocity and acceleration
+	///
+	/// # Arguments
+	///
+	/// * `max_acceleration` - Largest change of the velocity per second
+	/// * `max_jerk` - Largest change of the acceleration per second
+	/// * `dt` - Time in seconds of a step
+	fn stop_distance(mut self, max_acceleration: f64, max_jerk: f64, dt: f64) -> f64 {
+		let mut distance = 0.0;
+		while dt > 0.0 && (self.velocity != 0.0 || self.acceleration != 0.0) {
+			self.step(0.0, f64::INFINITY, max_acceleration, max_jerk, dt);
+			distance += self.velocity.abs() * dt;
+		}
+		distance
+	}
+}
+
+/// Limits the velocity, acceleration and jerk of the speeds of a controller before they are sent to the motors
+///
+/// The speeds of the sides are split up into the linear and the angular velocity of the robot, which are limited
+/// separately. Before the last goal the robot slows down, so it comes to rest there instead of stopping dead.
+pub struct Profiler {
+	limits: Limits,
+	linear: Axis,
+	angular: Axis,
+	last_step: Option<Instant>,
+}
+
+impl Profiler {
+	/// Limit the speeds of the sides, has to be called on every step
+	///
+	/// # Arguments
+	///
+	/// * `now` - The time of the step
+	/// * `left` - The speed of the left side as the controller wants it
+	/// * `right` - The speed of the right side as the controller wants it
+	/// * `wheel_distance` - Distance between the wheels, the effective one of a skid-steer chassis
+	/// * `stop` - The distance to the point the robot has to come to rest at, None to drive on
+	///
+	/// # Returns
+	///
+	/// The (left, right) speed to send to the motors
+	pub fn step(&mut self, now: Instant, left: Duty, right: Duty, wheel_distance: Length, stop: Option<Length>) -> (Duty, Duty) {
+		let dt = self.last_step.map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+		self.last_step = Some(now);
+
+		let limits = self.limits;
+		let full_speed = limits.full_speed.as_mm_per_s();
+		let half_distance = wheel_distance.as_mm() / 2.0;
+		let linear = (left.get() + right.get()) / 2.0 * full_speed;
+		let angular = (right.get() - left.get()) / 2.0 * full_speed / half_distance;
+
+		// Brake as soon as the robot could not come to rest within the distance any more after another step towards the target
+		let (max_velocity, a, j) = (limits.max_velocity.as_mm_per_s(), limits.max_acceleration, limits.max_jerk);
+		let linear = match stop {
+			Some(distance) => {
+				let mut next = self.linear;
+				next.step(linear, max_velocity, a, j, dt);
+				match next.velocity.abs() * dt + next.stop_distance(a, j, dt) > distance.as_mm() {
+					true => 0.0,
+					false => linear,
+				}
+			},
+			None => linear,
+		};
+		self.linear.step(linear, max_velocity, a, j, dt);
+		self.angular.step(angular, limits.max_angular_velocity.as_rad_per_s(), limits.max_angular_acceleration, limits.max_angular_jerk, dt);
+
+		let turn = self.angular.velocity * half_distance;
+		(
+			Duty::new((self.linear.velocity - turn) / full_speed),
+			Duty::new((self.linear.velocity + turn) / full_speed),
+		)
+	}
+
+	/// Check if the robot stands still and does not accelerate any more
+	///
+	/// The velocity is set to the target once it is reached, so at rest it is exactly zero
+	pub fn at_rest(&self) -> bool {
+		self.linear.velocity == 0.0 && self.linear.acceleration == 0.0
+			&& self.angular.velocity == 0.0 && self.angular.acceleration == 0.0
+	}
+
+	/// Forget the motion, e.g. after the motors were stopped, the next step starts at rest
+	pub fn reset(&mut self) {
+		self.linear = Axis::default();
+		self.angular = Axis::default();
+		self.last_step = None;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	const DT: f64 = 0.01;
+
+	/// The limits of the robot in `main.rs`
+	fn limits() -> Limits {
+		Limits {
+			full_speed: Velocity::mm_per_s(1000.0),
+			max_velocity: Velocity::mm_per_s(300.0),
+			max_acceleration: 500.0,
+			max_jerk: 2000.0,
+			max_angular_velocity: AngularVelocity::rad_per_s(3.0),
+			max_angular_acceleration: 10.0,
+			max_angular_jerk: 40.0,
+		}
+	}
+
+	/// Step the axis towards the target until it is reached and check the limits on every step
+	fn step_to(axis: &mut Axis, target: f64) {
+		let limits = limits();
+		let error = target - axis.velocity;
+		for _ in 0..1000 {
+			let acceleration = axis.acceleration;
+			axis.step(target, limits.max_velocity.as_mm_per_s(), limits.max_acceleration, limits.max_jerk, DT);
+			assert!((axis.acceleration - acceleration).abs() / DT <= limits.max_jerk + 1e-6, "jerk {}", (axis.acceleration - acceleration) / DT);
+			assert!(axis.acceleration.abs() <= limits.max_acceleration, "acceleration {}", axis.acceleration);
+			assert!((target - axis.velocity) * error.signum() >= 0.0, "velocity {} overshot {}", axis.velocity, target);
+			if axis.velocity == target && axis.acceleration == 0.0 {
+				return;
+			}
+		}
+		panic!("velocity {} did not reach {}", axis.velocity, target);
+	}
+
+	#[test]
+	fn limits_the_jerk_and_acceleration() {
+		let mut axis = Axis::default();
+		step_to(&mut axis, 300.0);
+		step_to(&mut axis, -300.0);
+		step_to(&mut axis, 10.0);
+		step_to(&mut axis, 0.0);
+	}
+
+	#[test]
+	fn stops_within_the_distance() {
+		let limits = limits();
+		let mut profiler = new(limits);
+		let start = Instant::now();
+		let (mut now, mut driven, mut stop) = (start, 0.0, None);
+		loop {
+			// Full speed ahead, once it is reached stop 200mm further on
+			let acceleration = profiler.linear.acceleration;
+			let (left, right) = profiler.step(now, Duty::new(1.0), Duty::new(1.0), Length::mm(150.0), stop.map(|stop: f64| Length::mm(stop - driven)));
+			assert!((profiler.linear.acceleration - acceleration).abs() / DT <= limits.max_jerk + 1e-6, "jerk {}", (profiler.linear.acceleration - acceleration) / DT);
+			assert!(profiler.linear.acceleration.abs() <= limits.max_acceleration, "acceleration {}", profiler.linear.acceleration);
+			let velocity = (left.get() + right.get()) / 2.0 * limits.full_speed.as_mm_per_s();
+			driven += velocity * DT;
+			now += Duration::from_secs_f64(DT);
+			if stop.is_none() && velocity >= limits.max_velocity.as_mm_per_s() {
+				stop = Some(driven + 200.0);
+			}
+			if stop.is_some() && profiler.at_rest() {
+				break;
+			}
+			assert!(now.duration_since(start) < Duration::from_secs(10), "not at rest at {}mm", driven);
+		}
+		let stop = stop.unwrap();
+		assert!(driven <= stop, "drove {}mm past the stop", driven - stop);
+		assert!(driven > stop - 5.0, "stopped {}mm before the stop", stop - driven);
+	}
+}
@@ -0,0 +1,198 @@
+use super::error::Error;
+use super::hal;
+use super::hal::Backend;
+
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Servos expect a pulse every 20ms (50Hz)
+const PULSE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Create a new servo
+///
+/// The servo moves over an arc of 180° with pulses from 500µs up to 2500µs, 1500µs is the center
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the servo is attached to
+/// * `channel` - Which Servo-Channel
+pub fn new(hal: Arc<dyn Backend>, channel: hal::Servo) -> Servo {
+	Servo {
+		hal,
+		channel,
+		min_pulse: 500,
+		max_pulse: 2500,
+		range: PI,
+		pulse: Arc::new(AtomicI32::new(1500)),
+		sweep: Arc::new(Mutex::new(None)),
+		stop: Arc::new(AtomicBool::new(false)),
+	}
+}
+
+/// Let the servo move back and forth between two angles
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sweep {
+	/// Start angle in rad
+	pub from: f64,
+	/// End angle in rad
+	pub to: f64,
+	/// Angular velocity in rad/s
+	pub speed: f64,
+}
+
+/// A hobby servo which is driven by a pulse every 20ms
+#[derive(Clone)]
+pub struct Servo {
+	hal: Arc<dyn Backend>,
+	channel: hal::Servo,
+	min_pulse: i32,
+	max_pulse: i32,
+	range: f64,
+	pulse: Arc<AtomicI32>,
+	/// The sweep pattern and its angle in rad, the pulse width is too coarse to sweep slowly from it
+	sweep: Arc<Mutex<Option<(Sweep, f64)>>>,
+	stop: Arc<AtomicBool>,
+}
+
+impl Servo {
+	/// Set the pulse widths and the arc the servo can move over
+	///
+	/// # Arguments
+	///
+	/// * `min_pulse` - Pulse width in µs at the position -1.0
+	/// * `max_pulse` - Pulse width in µs at the position 1.0
+	/// * `range` - Angle in rad between the position -1.0 and 1.0
+	pub fn set_pulse_range(&mut self, min_pulse: i32, max_pulse: i32, range: f64) {
+		self.min_pulse = min_pulse;
+		self.max_pulse = max_pulse;
+		self.range = range;
+	}
+
+	/// Switch the power rail on and start sending pulses in a thread
+	pub fn start(&mut self) -> Result<(), Error> {
+		self.hal.init_servos()?;
+		self.hal.servo_power_rail(true)?;
+		self.stop.store(false, Ordering::Relaxed);
+
+		thread::spawn({
+			let servo = self.clone();
+			move || {
+				while !servo.stop.load(Ordering::Relaxed) {
+					servo.sweep_step(PULSE_INTERVAL.as_secs_f64());
+					if let Err(err) = servo.hal.servo_send_pulse(servo.channel, servo.pulse.load(Ordering::Relaxed)) {
+						println!("ERROR: {}", err);
+						break;
+					}
+					thread::sleep(PULSE_INTERVAL);
+				}
+			}
+		});
+		Ok(())
+	}
+
+	/// Stop sending pulses and switch the power rail off
+	///
+	/// The power rail is shared, so this also stops all other servos
+	pub fn stop(&mut self) -> Result<(), Error> {
+		self.stop.store(true, Ordering::Relaxed);
+		self.hal.servo_power_rail(false)
+	}
+
+	/// Set the pulse width the servo is driven with
+	///
+	/// # Arguments
+	///
+	/// * `width` - Pulse width in µs, limited to the configured range
+	pub fn set_pulse_width(&self, width: i32) {
+		self.pulse.store(width.clamp(self.min_pulse, self.max_pulse), Ordering::Relaxed);
+	}
+
+	/// Set the position of the servo
+	///
+	/// # Arguments
+	///
+	/// * `position` - Position from -1.0 up to 1.0, 0.0 is the center
+	pub fn set_position(&self, position: f64) {
+		let center = (self.min_pulse + self.max_pulse) as f64 / 2.0;
+		let half = (self.max_pulse - self.min_pulse) as f64 / 2.0;
+		self.set_pulse_width((center + position.clamp(-1.0, 1.0) * half).round() as i32);
+	}
+
+	/// Get the position of the servo from -1.0 up to 1.0
+	pub fn position(&self) -> f64 {
+		let center = (self.min_pulse + self.max_pulse) as f64 / 2.0;
+		let half = (self.max_pulse - self.min_pulse) as f64 / 2.0;
+		(self.pulse.load(Ordering::Relaxed) as f64 - center) / half
+	}
+
+	/// Turn the servo to an angle
+	///
+	/// # Arguments
+	///
+	/// * `angle` - Angle in rad, 0.0 is the center and positive is counterclockwise
+	pub fn set_angle(&self, angle: f64) {
+		self.set_position(angle / (self.range / 2.0));
+	}
+
+	/// Get the angle of the servo in rad
+	pub fn angle(&self) -> f64 {
+		self.position() * self.range / 2.0
+	}
+
+	/// Start or stop sweeping, while sweeping the position is moved in the pulse thread
+	///
+	/// # Arguments
+	///
+	/// * `sweep` - The sweep pattern or None to stop at the current position
+	pub fn sweep(&self, sweep: Option<Sweep>) {
+		if let Some(sweep) = sweep {
+			self.set_angle(sweep.from);
+		}
+		*self.sweep.lock().unwrap_or_else(|err| err.into_inner()) = sweep.map(|sweep| (sweep, sweep.from));
+	}
+
+	/// Move the sweeping servo for the given time, it turns around at the end of the arc
+	fn sweep_step(&self, dt: f64) {
+		let mut sweep = self.sweep.lock().unwrap_or_else(|err| err.into_inner());
+		if let Some((pattern, angle)) = sweep.as_mut() {
+			let direction = (pattern.to - pattern.from).signum();
+			*angle += direction * pattern.speed * dt;
+			if (*angle - pattern.to) * direction >= 0.0 {
+				*angle = pattern.to;
+				std::mem::swap(&mut pattern.from, &mut pattern.to);
+			}
+			self.set_angle(*angle);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::simulated::Simulated;
+
+	/// Sweep for the given time in steps of the pulse interval
+	fn sweep_for(servo: &Servo, seconds: f64) {
+		for _ in 0..(seconds / PULSE_INTERVAL.as_secs_f64()).round() as i32 {
+			servo.sweep_step(PULSE_INTERVAL.as_secs_f64());
+		}
+	}
+
+	#[test]
+	fn sweeps_slowly() {
+		let servo = new(Arc::new(Simulated::default()), hal::Servo::SERVO1);
+		// 0.01rad/s is a fraction of a µs per pulse
+		servo.sweep(Some(Sweep { from: 0.0, to: 0.1, speed: 0.01 }));
+		sweep_for(&servo, 5.0);
+		assert!((servo.angle() - 0.05).abs() < 0.002, "angle {}", servo.angle());
+		sweep_for(&servo, 5.0);
+		assert!((servo.angle() - 0.1).abs() < 0.002, "angle {}", servo.angle());
+
+		// It turns around at the end
+		sweep_for(&servo, 2.0);
+		assert!((servo.angle() - 0.08).abs() < 0.002, "angle {}", servo.angle());
+	}
+}
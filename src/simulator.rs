@@ -0,0 +1,307 @@
+use super::hal;
+use super::hal::{Backend, ImuData};
+use super::hal::simulated::{Simulated, MotorState};
+use super::units::Length;
+use super::wheel::Wheel as Wheel;
+use super::wheel::Orientation as Orientation;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest time span which is integrated in one go, longer steps are split up
+const MAX_STEP: Duration = Duration::from_millis(1);
+
+/// Gravity in m/s² as measured by the accelerometer
+const GRAVITY: f64 = 9.80665;
+
+/// Earth magnetic field in µT, the horizontal part points along the X axis of the world
+const MAGNETIC_FIELD: (f64, f64) = (21.0, -43.0);
+
+/// Model of a DC motor with gearbox, all values are on the wheel axle
+#[derive(Copy, Clone, Debug)]
+pub struct MotorModel {
+	/// Torque in Nm at a duty of 1.0 while the wheel is not turning
+	pub stall_torque: f64,
+	/// Angular velocity in rad/s at a duty of 1.0 without any load
+	pub no_load_speed: f64,
+	/// Inertia in kg*m² the motor has to accelerate (rotor, gearbox, wheel and its part of the robot)
+	pub inertia: f64,
+	/// Friction in Nm which has to be overcome before the wheel starts turning
+	pub static_friction: f64,
+	/// Friction in Nm*s/rad which grows with the angular velocity
+	pub viscous_friction: f64,
+	/// Battery voltage in V the torque and the speed are given at, the motor gets the duty of the measured voltage
+	pub voltage: f64,
+}
+impl Default for MotorModel {
+	/// A small 12V gear motor at 300rpm driving a wheel which carries half of a 1.5kg robot
+	fn default() -> Self {
+		Self {
+			stall_torque: 0.4,
+			no_load_speed: 31.4,
+			inertia: 0.0012,
+			static_friction: 0.01,
+			viscous_friction: 0.0005,
+			voltage: 7.4,
+		}
+	}
+}
+
+/// A simulated wheel with the motor driving it and the encoder attached to it
+struct SimulatedWheel {
+	motor: hal::Motor,
+	encoder: hal::Encoder,
+	radius: f64,
+	counts_per_rad: f64,
+	reversed: bool,
+	model: MotorModel,
+	velocity: f64,
+	angle: f64,
+}
+impl SimulatedWheel {
+	/// Integrate the wheel for the given time and return the distance the wheel drove on the ground
+	///
+	/// # Arguments
+	///
+	/// * `state` - The last command sent to the motor
+	/// * `voltage` - Voltage of the battery in V, None to drive the motor with the voltage of its model
+	/// * `dt` - Time in seconds to integrate
+	fn step(&mut self, state: MotorState, voltage: Option<f64>, dt: f64) -> f64 {
+		let model = &self.model;
+		let back_emf = model.stall_torque / model.no_load_speed;
+
+		// A braked motor is shorted, so only the back-EMF is slowing it down,
+		// a free spinning motor is disconnected and only slowed down by the friction
+		let (duty, back_emf) = match state {
+			MotorState::Running(duty) if self.reversed => (-duty, back_emf),
+			MotorState::Running(duty) => (duty, back_emf),
+			MotorState::Braked => (0.0, back_emf),
+			MotorState::FreeSpin => (0.0, 0.0),
+		};
+		let duty = duty * voltage.map_or(1.0, |voltage| voltage / model.voltage);
+		let drive = model.stall_torque * duty - back_emf * self.velocity - model.viscous_friction * self.velocity;
+
+		if self.velocity == 0.0 && drive.abs() <= model.static_friction {
+			return 0.0;
+		}
+
+		let friction = model.static_friction * if self.velocity != 0.0 { self.velocity.signum() } else { drive.signum() };
+		let velocity = self.velocity + (drive - friction) / model.inertia * dt;
+
+		// Friction can only stop the wheel, never turn it the other way round
+		self.velocity = if velocity.signum() != self.velocity.signum() && self.velocity != 0.0 { 0.0 } else { velocity };
+		self.angle += self.velocity * dt;
+		self.velocity * dt * self.radius
+	}
+
+	/// The encoder count for the current wheel angle
+	fn encoder_value(&self) -> i32 {
+		let counts = (self.angle * self.counts_per_rad).round() as i32;
+		match self.reversed {
+			true => -counts,
+			false => counts,
+		}
+	}
+}
+
+/// Physics simulation of a differential-drive robot
+///
+/// The motors are driven by the commands sent to the simulated backend, the resulting encoder
+/// counts are written back to it, so `DifferentialDrive` can drive the simulated robot.
+pub struct Simulator {
+	hal: Arc<Simulated>,
+	wheel_distance: f64,
+	slip: f64,
+	left: Vec<SimulatedWheel>,
+	right: Vec<SimulatedWheel>,
+	x: f64,
+	y: f64,
+	phi: f64,
+	velocity: f64,
+}
+
+/// Create a new simulated robot
+///
+/// # Arguments
+///
+/// * `wheel_distance` - Distance between the wheels (middle of the wheel)
+pub fn new(wheel_distance: Length) -> Simulator {
+	Simulator {
+		hal: Arc::new(Simulated::default()),
+		wheel_distance: wheel_distance.as_mm(),
+		slip: 1.0,
+		left: vec!(),
+		right: vec!(),
+		x: 0.0,
+		y: 0.0,
+		phi: 0.0,
+		velocity: 0.0,
+	}
+}
+
+impl Simulator {
+	/// The backend the robot has to use to drive the simulation
+	pub fn backend(&self) -> Arc<dyn Backend> {
+		self.hal.clone()
+	}
+
+	/// Add a simulated motorized wheel, the wheels of a side drive the robot with their average
+	///
+	/// # Arguments
+	///
+	/// * `wheel` - The Wheel as it is added to the robot
+	/// * `reversed` - If the motor and encoder is reversed, same as on the robot
+	/// * `model` - The model of the motor driving this wheel
+	pub fn add_wheel(&mut self, wheel: &Wheel, reversed: bool, model: MotorModel) {
+		let simulated = SimulatedWheel {
+			motor: wheel.motor,
+			encoder: wheel.encoder,
+			radius: wheel.radius.as_mm(),
+			counts_per_rad: wheel.encoder_resolution * wheel.gear_ratio / (2.0 * PI),
+			reversed,
+			model,
+			velocity: 0.0,
+			angle: 0.0,
+		};
+		match wheel.orientation {
+			Orientation::LEFT => self.left.push(simulated),
+			Orientation::RIGHT => self.right.push(simulated),
+			_ => {},
+		}
+	}
+
+	/// Let the wheels slide sideways while turning like on a skid-steer chassis
+	///
+	/// # Arguments
+	///
+	/// * `slip` - The robot turns as if its wheels were this many times further apart [>= 1.0]
+	pub fn set_slip(&mut self, slip: f64) {
+		self.slip = slip;
+	}
+
+	/// Set the voltage of the battery pack, the motors are driven and the ADC measures with it
+	///
+	/// # Arguments
+	///
+	/// * `voltage` - Voltage of the battery pack in V
+	pub fn set_battery_voltage(&self, voltage: f64) {
+		self.hal.set_voltages(voltage, 0.0);
+	}
+
+	/// Place the robot in the simulated world
+	///
+	/// # Arguments
+	///
+	/// * `x` - X-Position in mm
+	/// * `y` - Y-Position in mm
+	/// * `phi` - The robots alignment in rad
+	pub fn set_pose(&mut self, x: f64, y: f64, phi: f64) {
+		self.x = x;
+		self.y = y;
+		self.phi = phi;
+	}
+
+	/// The true pose of the simulated robot as (X in mm, Y in mm, Orientation in rad)
+	pub fn pose(&self) -> (f64, f64, f64) {
+		(self.x, self.y, self.phi)
+	}
+
+	/// The true velocity of the (left, right) side on the ground in mm/s, the average of its wheels
+	pub fn wheel_velocities(&self) -> (f64, f64) {
+		let velocity = |wheels: &[SimulatedWheel]| mean(&wheels.iter().map(|w| w.velocity * w.radius).collect::<Vec<f64>>());
+		(velocity(&self.left), velocity(&self.right))
+	}
+
+	/// Advance the simulation by the given time
+	///
+	/// # Arguments
+	///
+	/// * `duration` - Simulated time since the last step
+	pub fn step(&mut self, duration: Duration) {
+		let mut remaining = duration;
+		while !remaining.is_zero() {
+			let dt = remaining.min(MAX_STEP);
+			remaining -= dt;
+			self.integrate(dt.as_secs_f64());
+		}
+	}
+
+	/// Integrate the wheels and the pose over a time step
+	fn integrate(&mut self, dt: f64) {
+		let hal = &self.hal;
+		let voltage = hal.battery_voltage().ok();
+		let step = |wheels: &mut Vec<SimulatedWheel>| {
+			let distances: Vec<f64> = wheels.iter_mut().map(|wheel| {
+				let dist = wheel.step(hal.motor_state(wheel.motor), voltage, dt);
+				hal.set_encoder_value(wheel.encoder, wheel.encoder_value());
+				dist
+			}).collect();
+			mean(&distances)
+		};
+		let left = step(&mut self.left);
+		let right = step(&mut self.right);
+
+		// Move on the arc given by both sides
+		let distance = (left + right) / 2.0;
+		let delta_phi = (right - left) / (self.wheel_distance * self.slip);
+		if delta_phi.abs() < 1e-12 {
+			self.x += distance * self.phi.cos();
+			self.y += distance * self.phi.sin();
+		} else {
+			let radius = distance / delta_phi;
+			self.x += radius * ((self.phi + delta_phi).sin() - self.phi.sin());
+			self.y -= radius * ((self.phi + delta_phi).cos() - self.phi.cos());
+		}
+		self.phi = (self.phi + delta_phi + PI).rem_euclid(2.0 * PI) - PI;
+
+		// The IMU measures the motion of the robot body, converted from mm to m
+		let velocity = distance / dt;
+		let yaw_rate = delta_phi / dt;
+		self.hal.set_imu(ImuData {
+			accel: [(velocity - self.velocity) / dt / 1000.0, velocity * yaw_rate / 1000.0, GRAVITY],
+			gyro: [0.0, 0.0, yaw_rate.to_degrees()],
+			quaternion: [(self.phi / 2.0).cos(), 0.0, 0.0, (self.phi / 2.0).sin()],
+			mag: [MAGNETIC_FIELD.0 * self.phi.cos(), -MAGNETIC_FIELD.0 * self.phi.sin(), MAGNETIC_FIELD.1],
+		});
+		self.velocity = velocity;
+	}
+
+	/// Advance the simulation and the time of its backend together, so the robot runs as fast as it is stepped
+	///
+	/// # Arguments
+	///
+	/// * `duration` - Time to move on by
+	pub fn advance(&mut self, duration: Duration) {
+		self.hal.advance(duration);
+		self.step(duration);
+	}
+
+	/// Run the simulation in real time in its own thread until it should stop
+	///
+	/// # Arguments
+	///
+	/// * `stop` - Flag to stop the simulation
+	pub fn spawn(mut self, stop: Arc<AtomicBool>) -> thread::JoinHandle<Self> {
+		thread::spawn(move || {
+			let mut last_step = Instant::now();
+			while !stop.load(Ordering::Relaxed) {
+				thread::sleep(MAX_STEP);
+				let now = Instant::now();
+				self.step(now.duration_since(last_step));
+				last_step = now;
+			}
+			self
+		})
+	}
+}
+
+/// The mean of the values, zero without any
+fn mean(values: &[f64]) -> f64 {
+	match values.len() {
+		0 => 0.0,
+		count => values.iter().sum::<f64>() / count as f64,
+	}
+}
@@ -0,0 +1,204 @@
+use super::units::{AngularVelocity, Length, Velocity};
+
+use std::time::Duration;
+
+/// Time constant with which the velocity integrated from the accelerometer is pulled towards the wheels while they grip
+const VELOCITY_FILTER: Duration = Duration::from_millis(500);
+
+/// Thresholds of the slip detection and what is done while the wheels slip
+#[derive(Copy, Clone, Debug)]
+pub struct SlipDetection {
+	/// Largest difference of the velocity of the wheels and the one integrated from the accelerometer
+	pub max_velocity_error: Velocity,
+	/// Largest difference of the yaw rate of the wheels and the one of the gyro
+	pub max_yaw_rate_error: AngularVelocity,
+	/// How much the distance of the wheels is still trusted while they slip, the rest comes from the accelerometer [0.0 - 1.0]
+	pub odometry_weight: f64,
+	/// Factor the target speed of the wheels is cut to while they slip, None to not cut it
+	pub traction_control: Option<f64>,
+}
+
+/// A slip of the wheels, the motion of the wheels minus the one the IMU measured
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Slip {
+	pub velocity_error: Velocity,
+	pub yaw_rate_error: AngularVelocity,
+}
+
+/// Create a slip detector, the robot starts at rest
+///
+/// # Arguments
+///
+/// * `detection` - The thresholds and the reaction on a slip
+pub fn new(detection: SlipDetection) -> SlipDetector {
+	SlipDetector {
+		detection,
+		velocity: Velocity::ZERO,
+		slip: None,
+		events: 0,
+	}
+}
+
+/// Detects wheels which spin or slide by comparing their motion with the gyro and the accelerometer
+pub struct SlipDetector {
+	detection: SlipDetection,
+	/// Linear velocity of the robot integrated from the accelerometer
+	velocity: Velocity,
+	slip: Option<Slip>,
+	events: u32,
+}
+
+impl SlipDetector {
+	/// Compare the motion of the wheels with the one of the IMU, has to be called on every step of the odometry
+	///
+	/// # Arguments
+	///
+	/// * `velocity` - Linear velocity of the robot according to the wheels
+	/// * `yaw_rate` - Yaw rate of the robot according to the wheels
+	/// * `acceleration` - Forward acceleration in mm/s² measured by the accelerometer
+	/// * `gyro` - Yaw rate measured by the gyro
+	/// * `duration` - Duration since the last step
+	///
+	/// # Returns
+	///
+	/// The slip, None if the wheels grip
+	pub fn step(&mut self, velocity: Velocity, yaw_rate: AngularVelocity, acceleration: f64, gyro: AngularVelocity, duration: Duration) -> Option<Slip> {
+		self.velocity += Velocity::mm_per_s(acceleration * duration.as_secs_f64());
+
+		let slip = Slip {
+			velocity_error: velocity - self.velocity,
+			yaw_rate_error: yaw_rate - gyro,
+		};
+		let slipping = slip.velocity_error.abs() > self.detection.max_velocity_error
+			|| slip.yaw_rate_error.abs() > self.detection.max_yaw_rate_error;
+
+		// The integrated velocity drifts with the bias of the accelerometer, the wheels correct it while they grip
+		if !slipping {
+			let alpha = duration.as_secs_f64() / (VELOCITY_FILTER + duration).as_secs_f64();
+			self.velocity += (velocity - self.velocity) * alpha;
+		}
+		if slipping && self.slip.is_none() {
+			self.events += 1;
+		}
+		self.slip = slipping.then_some(slip);
+		self.slip
+	}
+
+	/// Correct the distances of the wheels during a slip: the heading follows the gyro only and the distance
+	/// is blended with the one integrated from the accelerometer. Without a slip they are returned unchanged.
+	///
+	/// # Arguments
+	///
+	/// * `left` - Distance the left side was driven since the last step
+	/// * `right` - Distance the right side was driven since the last step
+	/// * `wheel_distance` - Distance between the wheels, the effective one of a skid-steer chassis
+	/// * `gyro` - Yaw rate measured by the gyro
+	/// * `duration` - Duration since the last step
+	pub fn correct(&self, left: Length, right: Length, wheel_distance: Length, gyro: AngularVelocity, duration: Duration) -> (Length, Length) {
+		if self.slip.is_none() {
+			return (left, right);
+		}
+		let weight = self.detection.odometry_weight.clamp(0.0, 1.0);
+		let distance = (left + right) / 2.0 * weight + self.velocity * duration * (1.0 - weight);
+		let half_turn = (gyro * duration).arc(wheel_distance) / 2.0;
+		(distance - half_turn, distance + half_turn)
+	}
+
+	/// Factor for the target speed of the wheels, below 1.0 while they slip with a traction control
+	pub fn traction(&self) -> f64 {
+		match (self.slip, self.detection.traction_control) {
+			(Some(_), Some(factor)) => factor,
+			_ => 1.0,
+		}
+	}
+
+	/// The current slip, None if the wheels grip
+	pub fn slip(&self) -> Option<Slip> {
+		self.slip
+	}
+
+	/// Number of slips since the detector was created
+	pub fn events(&self) -> u32 {
+		self.events
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::units::Angle;
+
+	const STEP: Duration = Duration::from_millis(10);
+	const WHEEL_DISTANCE: Length = Length::mm(150.0);
+
+	fn detector() -> SlipDetector {
+		new(SlipDetection {
+			max_velocity_error: Velocity::mm_per_s(50.0),
+			max_yaw_rate_error: AngularVelocity::rad_per_s(0.3),
+			odometry_weight: 0.2,
+			traction_control: Some(0.5),
+		})
+	}
+
+	/// Accelerate straight ahead for a second with the wheels and the accelerometer agreeing
+	fn accelerate(detector: &mut SlipDetector) -> Velocity {
+		let mut velocity = Velocity::ZERO;
+		for _ in 0..100 {
+			velocity += Velocity::mm_per_s(500.0 * STEP.as_secs_f64());
+			assert_eq!(detector.step(velocity, AngularVelocity::rad_per_s(0.0), 500.0, AngularVelocity::rad_per_s(0.0), STEP), None);
+		}
+		velocity
+	}
+
+	#[test]
+	fn detects_spinning_wheels() {
+		let mut detector = detector();
+		let mut velocity = accelerate(&mut detector);
+		assert_eq!(detector.traction(), 1.0);
+
+		// The wheels speed up on their own, the robot does not
+		let mut slip = None;
+		for _ in 0..10 {
+			velocity += Velocity::mm_per_s(20.0);
+			slip = slip.or(detector.step(velocity, AngularVelocity::rad_per_s(0.0), 0.0, AngularVelocity::rad_per_s(0.0), STEP));
+		}
+		let slip = slip.expect("No slip detected");
+		assert!(slip.velocity_error > Velocity::mm_per_s(50.0), "{}", slip.velocity_error);
+		assert_eq!(detector.events(), 1);
+		assert_eq!(detector.traction(), 0.5);
+
+		// The distance comes mostly from the accelerometer, the robot still drives at about 500mm/s
+		let (left, right) = detector.correct(velocity * STEP, velocity * STEP, WHEEL_DISTANCE, AngularVelocity::rad_per_s(0.0), STEP);
+		assert_eq!(left, right);
+		let expected = velocity * STEP * 0.2 + Velocity::mm_per_s(500.0) * STEP * 0.8;
+		assert!((left - expected).abs() < Length::mm(0.1), "{} instead of {}", left, expected);
+
+		// Once the wheels grip again the slip ends
+		detector.step(Velocity::mm_per_s(500.0), AngularVelocity::rad_per_s(0.0), 0.0, AngularVelocity::rad_per_s(0.0), STEP);
+		assert_eq!(detector.slip(), None);
+		assert_eq!(detector.traction(), 1.0);
+		assert_eq!(detector.events(), 1);
+	}
+
+	#[test]
+	fn detects_sliding_turns() {
+		let mut detector = detector();
+		let velocity = accelerate(&mut detector);
+
+		// The wheels turn with 1 rad/s, the gyro measures only 0.2 rad/s
+		let (yaw_rate, gyro) = (AngularVelocity::rad_per_s(1.0), AngularVelocity::rad_per_s(0.2));
+		let slip = detector.step(velocity, yaw_rate, 0.0, gyro, STEP).expect("No slip detected");
+		assert!((slip.yaw_rate_error.as_rad_per_s() - 0.8).abs() < 1e-9);
+		assert!(slip.velocity_error.abs() < Velocity::mm_per_s(50.0));
+
+		// The heading follows the gyro only
+		let turn = (yaw_rate * STEP).arc(WHEEL_DISTANCE) / 2.0;
+		let (left, right) = detector.correct(velocity * STEP - turn, velocity * STEP + turn, WHEEL_DISTANCE, gyro, STEP);
+		let angle = Angle::from_arc(right - left, WHEEL_DISTANCE);
+		assert!((angle - gyro * STEP).abs() < Angle::rad(1e-9), "{} instead of {}", angle, gyro * STEP);
+
+		// Without a slip the distances are not touched
+		detector.step(velocity, gyro, 0.0, gyro, STEP);
+		assert_eq!(detector.correct(Length::mm(3.0), Length::mm(4.0), WHEEL_DISTANCE, gyro, STEP), (Length::mm(3.0), Length::mm(4.0)));
+	}
+}
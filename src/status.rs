@@ -0,0 +1,113 @@
+use super::hal::{Backend, Led};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// State of the robot shown on the LEDs
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+	#[default]
+	Idle,
+	Running,
+	GoalReached,
+	ObstacleClose,
+	Slipping,
+	Fault,
+	LowBattery,
+}
+
+/// Blink pattern of a LED, the LED is on for `on` at the start of every `period`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+	pub period: Duration,
+	pub on: Duration,
+}
+impl Pattern {
+	pub const OFF: Pattern = Pattern { period: Duration::from_secs(1), on: Duration::ZERO };
+	pub const ON: Pattern = Pattern { period: Duration::from_secs(1), on: Duration::from_secs(1) };
+	pub const SLOW: Pattern = Pattern { period: Duration::from_millis(1000), on: Duration::from_millis(100) };
+	pub const FAST: Pattern = Pattern { period: Duration::from_millis(200), on: Duration::from_millis(100) };
+
+	/// Check if the LED is on at the given time since the pattern started
+	fn is_on(&self, elapsed: Duration) -> bool {
+		if self.period.is_zero() {
+			return !self.on.is_zero();
+		}
+		elapsed.as_nanos() % self.period.as_nanos() < self.on.as_nanos()
+	}
+}
+
+impl Status {
+	/// The (green, red) pattern to show the status
+	pub fn patterns(&self) -> (Pattern, Pattern) {
+		match self {
+			Status::Idle => (Pattern::SLOW, Pattern::OFF),
+			Status::Running => (Pattern::ON, Pattern::OFF),
+			Status::GoalReached => (Pattern::FAST, Pattern::OFF),
+			Status::ObstacleClose => (Pattern::ON, Pattern::FAST),
+			Status::Slipping => (Pattern::ON, Pattern::SLOW),
+			Status::Fault => (Pattern::OFF, Pattern::ON),
+			Status::LowBattery => (Pattern::OFF, Pattern::SLOW),
+		}
+	}
+}
+
+/// Create the status indication on the green and red LED
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the LEDs are attached to
+pub fn new(hal: Arc<dyn Backend>) -> StatusLed {
+	StatusLed {
+		hal,
+		status: Status::Idle,
+		since: Instant::now(),
+		leds: None,
+	}
+}
+
+/// Shows the status of the robot on the green and red LED
+///
+/// `step` has to be called regularly, it only switches the LEDs if needed and never blocks.
+pub struct StatusLed {
+	hal: Arc<dyn Backend>,
+	status: Status,
+	since: Instant,
+	leds: Option<(bool, bool)>,
+}
+
+impl StatusLed {
+	/// The status which is shown at the moment
+	pub fn status(&self) -> Status {
+		self.status
+	}
+
+	/// Show a new status, the blink pattern starts over if it changed
+	pub fn set(&mut self, status: Status) {
+		if self.status != status {
+			self.status = status;
+			self.since = Instant::now();
+		}
+	}
+
+	/// Update the LEDs according to the blink pattern
+	pub fn step(&mut self) {
+		let elapsed = self.since.elapsed();
+		let (green, red) = self.status.patterns();
+		let leds = (green.is_on(elapsed), red.is_on(elapsed));
+
+		// A failing LED must not stop the robot, it is tried again on the next change
+		if self.leds != Some(leds) {
+			let _ = self.hal.set_led(Led::Green, leds.0);
+			let _ = self.hal.set_led(Led::Red, leds.1);
+			self.leds = Some(leds);
+		}
+	}
+
+	/// Switch both LEDs off
+	pub fn off(&mut self) {
+		let _ = self.hal.set_led(Led::Green, false);
+		let _ = self.hal.set_led(Led::Red, false);
+		self.leds = Some((false, false));
+	}
+}
@@ -0,0 +1,590 @@
+use super::distance::Sensor;
+use super::error::Error;
+use super::hal;
+use super::hal::Backend;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Address of the sensors after power up or a reset
+pub const DEFAULT_ADDRESS: u16 = 0x29;
+
+/// Time the sensor needs to boot after it was released from reset
+const BOOT_TIME: Duration = Duration::from_millis(2);
+
+/// Longest time to wait for the sensor to boot or to finish a calibration
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Interval to check for a new measurement
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// VL53L0X registers
+const L0X_SYSRANGE_START: u16 = 0x00;
+const L0X_SYSTEM_SEQUENCE_CONFIG: u16 = 0x01;
+const L0X_SYSTEM_INTERRUPT_CONFIG_GPIO: u16 = 0x0A;
+const L0X_SYSTEM_INTERRUPT_CLEAR: u16 = 0x0B;
+const L0X_RESULT_INTERRUPT_STATUS: u16 = 0x13;
+const L0X_RESULT_RANGE: u16 = 0x1E;
+const L0X_FINAL_RANGE_CONFIG_MIN_COUNT_RATE_RTN_LIMIT: u16 = 0x44;
+const L0X_MSRC_CONFIG_CONTROL: u16 = 0x60;
+const L0X_GPIO_HV_MUX_ACTIVE_HIGH: u16 = 0x84;
+const L0X_I2C_SLAVE_DEVICE_ADDRESS: u16 = 0x8A;
+const L0X_GLOBAL_CONFIG_SPAD_ENABLES_REF: u16 = 0xB0;
+const L0X_GLOBAL_CONFIG_REF_EN_START_SELECT: u16 = 0xB6;
+const L0X_IDENTIFICATION_MODEL_ID: u16 = 0xC0;
+
+// VL53L1X registers
+const L1X_I2C_SLAVE_DEVICE_ADDRESS: u16 = 0x0001;
+const L1X_VHV_CONFIG_TIMEOUT_MACROP_LOOP_BOUND: u16 = 0x0008;
+const L1X_VHV_CONFIG_INIT: u16 = 0x000B;
+const L1X_DEFAULT_CONFIGURATION_START: u16 = 0x002D;
+const L1X_GPIO_HV_MUX_CTRL: u16 = 0x0030;
+const L1X_GPIO_TIO_HV_STATUS: u16 = 0x0031;
+const L1X_SYSTEM_INTERRUPT_CLEAR: u16 = 0x0086;
+const L1X_SYSTEM_MODE_START: u16 = 0x0087;
+const L1X_RESULT_RANGE_STATUS: u16 = 0x0089;
+const L1X_RESULT_RANGE: u16 = 0x0096;
+const L1X_FIRMWARE_SYSTEM_STATUS: u16 = 0x00E5;
+const L1X_IDENTIFICATION_MODEL_ID: u16 = 0x010F;
+
+/// Tuning settings of the VL53L0X from the API of ST, pairs of register and value
+const L0X_TUNING: [(u8, u8); 80] = [
+	(0xFF, 0x01), (0x00, 0x00), (0xFF, 0x00), (0x09, 0x00), (0x10, 0x00), (0x11, 0x00), (0x24, 0x01), (0x25, 0xFF),
+	(0x75, 0x00), (0xFF, 0x01), (0x4E, 0x2C), (0x48, 0x00), (0x30, 0x20), (0xFF, 0x00), (0x30, 0x09), (0x54, 0x00),
+	(0x31, 0x04), (0x32, 0x03), (0x40, 0x83), (0x46, 0x25), (0x60, 0x00), (0x27, 0x00), (0x50, 0x06), (0x51, 0x00),
+	(0x52, 0x96), (0x56, 0x08), (0x57, 0x30), (0x61, 0x00), (0x62, 0x00), (0x64, 0x00), (0x65, 0x00), (0x66, 0xA0),
+	(0xFF, 0x01), (0x22, 0x32), (0x47, 0x14), (0x49, 0xFF), (0x4A, 0x00), (0xFF, 0x00), (0x7A, 0x0A), (0x7B, 0x00),
+	(0x78, 0x21), (0xFF, 0x01), (0x23, 0x34), (0x42, 0x00), (0x44, 0xFF), (0x45, 0x26), (0x46, 0x05), (0x40, 0x40),
+	(0x0E, 0x06), (0x20, 0x1A), (0x43, 0x40), (0xFF, 0x00), (0x34, 0x03), (0x35, 0x44), (0xFF, 0x01), (0x31, 0x04),
+	(0x4B, 0x09), (0x4C, 0x05), (0x4D, 0x04), (0xFF, 0x00), (0x44, 0x00), (0x45, 0x20), (0x47, 0x08), (0x48, 0x28),
+	(0x67, 0x00), (0x70, 0x04), (0x71, 0x01), (0x72, 0xFE), (0x76, 0x00), (0x77, 0x00), (0xFF, 0x01), (0x0D, 0x01),
+	(0xFF, 0x00), (0x80, 0x01), (0x01, 0xF8), (0xFF, 0x01), (0x8E, 0x01), (0x00, 0x01), (0xFF, 0x00), (0x80, 0x00),
+];
+
+/// Default configuration of the VL53L1X from the ultra lite driver of ST, written from register 0x2D up to 0x87
+const L1X_DEFAULT_CONFIGURATION: [u8; 91] = [
+	0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x02, 0x08, 0x00, 0x08, 0x10, 0x01, 0x01, 0x00, 0x00, 0x00,
+	0x00, 0xFF, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x0B, 0x00, 0x00, 0x02, 0x0A, 0x21,
+	0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0xC8, 0x00, 0x00, 0x38, 0xFF, 0x01, 0x00, 0x08, 0x00,
+	0x00, 0x01, 0xCC, 0x0F, 0x01, 0xF1, 0x0D, 0x01, 0x68, 0x00, 0x80, 0x08, 0xB8, 0x00, 0x00, 0x00,
+	0x00, 0x0F, 0x89, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x0F, 0x0D, 0x0E, 0x0E, 0x00,
+	0x00, 0x02, 0xC7, 0xFF, 0x9B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+];
+
+/// An I2C bus the sensors are attached to
+///
+/// Every transaction names the address of the device, so several sensors can share one bus
+pub trait Bus: Send {
+	/// Write the data to the device
+	fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error>;
+
+	/// Write the data to the device and read the answer into the buffer
+	fn write_read(&mut self, address: u16, data: &[u8], buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+#[cfg(target_os = "linux")]
+impl Bus for i2cdev::linux::LinuxI2CBus {
+	fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+		use i2cdev::core::{I2CMessage, I2CTransfer};
+		use i2cdev::linux::LinuxI2CMessage;
+
+		self.transfer(&mut [LinuxI2CMessage::write(data).with_address(address)])
+			.map(|_| ())
+			.map_err(|err| Error::Write(format!("Unable to write to I2C device 0x{:02X}: {}", address, err)))
+	}
+
+	fn write_read(&mut self, address: u16, data: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+		use i2cdev::core::{I2CMessage, I2CTransfer};
+		use i2cdev::linux::LinuxI2CMessage;
+
+		self.transfer(&mut [LinuxI2CMessage::write(data).with_address(address), LinuxI2CMessage::read(buffer).with_address(address)])
+			.map(|_| ())
+			.map_err(|err| Error::Read(format!("Unable to read from I2C device 0x{:02X}: {}", address, err)))
+	}
+}
+
+/// Supported time-of-flight sensors
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Model {
+	/// Up to 2m, 8 bit registers
+	VL53L0X,
+	/// Up to 4m, 16 bit registers
+	VL53L1X,
+}
+
+/// Register access of one sensor on the bus
+struct Device<'a> {
+	bus: MutexGuard<'a, Box<dyn Bus>>,
+	model: Model,
+	address: u16,
+}
+
+impl Device<'_> {
+	/// The register index as sent on the bus, the VL53L1X uses 16 bit indices
+	fn index(&self, register: u16) -> Vec<u8> {
+		match self.model {
+			Model::VL53L0X => vec!(register as u8),
+			Model::VL53L1X => register.to_be_bytes().to_vec(),
+		}
+	}
+
+	fn write(&mut self, register: u16, data: &[u8]) -> Result<(), Error> {
+		let mut message = self.index(register);
+		message.extend_from_slice(data);
+		self.bus.write(self.address, &message)
+	}
+
+	fn read(&mut self, register: u16, buffer: &mut [u8]) -> Result<(), Error> {
+		let index = self.index(register);
+		self.bus.write_read(self.address, &index, buffer)
+	}
+
+	fn write_u8(&mut self, register: u16, value: u8) -> Result<(), Error> {
+		self.write(register, &[value])
+	}
+
+	fn read_u8(&mut self, register: u16) -> Result<u8, Error> {
+		let mut buffer = [0; 1];
+		self.read(register, &mut buffer)?;
+		Ok(buffer[0])
+	}
+
+	fn read_u16(&mut self, register: u16) -> Result<u16, Error> {
+		let mut buffer = [0; 2];
+		self.read(register, &mut buffer)?;
+		Ok(u16::from_be_bytes(buffer))
+	}
+
+	/// Read a register until the condition is met
+	fn wait<F: Fn(u8) -> bool>(&mut self, register: u16, condition: F) -> Result<(), Error> {
+		let start = Instant::now();
+		while !condition(self.read_u8(register)?) {
+			if start.elapsed() > TIMEOUT {
+				return Err(Error::Timeout(format!("{:?} at 0x{:02X} did not answer in time", self.model, self.address)));
+			}
+			thread::sleep(Duration::from_millis(1));
+		}
+		Ok(())
+	}
+
+	/// Check if the device is the expected sensor
+	fn identify(&mut self) -> Result<(), Error> {
+		let matches = match self.model {
+			Model::VL53L0X => self.read_u8(L0X_IDENTIFICATION_MODEL_ID)? == 0xEE,
+			Model::VL53L1X => self.read_u16(L1X_IDENTIFICATION_MODEL_ID)? == 0xEACC,
+		};
+		match matches {
+			true => Ok(()),
+			false => Err(Error::Init(format!("No {:?} found at 0x{:02X}", self.model, self.address))),
+		}
+	}
+
+	/// Move the sensor to a new address, it keeps it until it is reset or powered off
+	fn set_address(&mut self, address: u16) -> Result<(), Error> {
+		match self.model {
+			Model::VL53L0X => self.write_u8(L0X_I2C_SLAVE_DEVICE_ADDRESS, address as u8 & 0x7F)?,
+			Model::VL53L1X => self.write_u8(L1X_I2C_SLAVE_DEVICE_ADDRESS, address as u8 & 0x7F)?,
+		}
+		self.address = address;
+		Ok(())
+	}
+
+	/// Configure the sensor and start the continuous ranging
+	fn init(&mut self) -> Result<(), Error> {
+		match self.model {
+			Model::VL53L0X => self.init_l0x(),
+			Model::VL53L1X => self.init_l1x(),
+		}
+	}
+
+	/// Initialize the VL53L0X as done by the API of ST, with the default timing budget of about 33ms
+	fn init_l0x(&mut self) -> Result<(), Error> {
+		// Standard I2C mode and read the stop variable which is needed to start the ranging
+		self.write_u8(0x88, 0x00)?;
+		self.write_u8(0x80, 0x01)?;
+		self.write_u8(0xFF, 0x01)?;
+		self.write_u8(0x00, 0x00)?;
+		let stop_variable = self.read_u8(0x91)?;
+		self.write_u8(0x00, 0x01)?;
+		self.write_u8(0xFF, 0x00)?;
+		self.write_u8(0x80, 0x00)?;
+
+		// Disable the MSRC and pre-range limit checks and limit the signal rate to 0.25 MCPS (9.7 fixed point)
+		let msrc = self.read_u8(L0X_MSRC_CONFIG_CONTROL)?;
+		self.write_u8(L0X_MSRC_CONFIG_CONTROL, msrc | 0x12)?;
+		self.write(L0X_FINAL_RANGE_CONFIG_MIN_COUNT_RATE_RTN_LIMIT, &32u16.to_be_bytes())?;
+		self.write_u8(L0X_SYSTEM_SEQUENCE_CONFIG, 0xFF)?;
+
+		self.init_l0x_spads()?;
+		for (register, value) in L0X_TUNING {
+			self.write_u8(register as u16, value)?;
+		}
+
+		// Interrupt on a new sample, active low
+		self.write_u8(L0X_SYSTEM_INTERRUPT_CONFIG_GPIO, 0x04)?;
+		let mux = self.read_u8(L0X_GPIO_HV_MUX_ACTIVE_HIGH)?;
+		self.write_u8(L0X_GPIO_HV_MUX_ACTIVE_HIGH, mux & !0x10)?;
+		self.write_u8(L0X_SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+
+		// Reference calibration of the VHV and the phase
+		self.write_u8(L0X_SYSTEM_SEQUENCE_CONFIG, 0x01)?;
+		self.calibrate_l0x(0x40)?;
+		self.write_u8(L0X_SYSTEM_SEQUENCE_CONFIG, 0x02)?;
+		self.calibrate_l0x(0x00)?;
+		self.write_u8(L0X_SYSTEM_SEQUENCE_CONFIG, 0xE8)?;
+
+		// Start the back-to-back ranging
+		self.write_u8(0x80, 0x01)?;
+		self.write_u8(0xFF, 0x01)?;
+		self.write_u8(0x00, 0x00)?;
+		self.write_u8(0x91, stop_variable)?;
+		self.write_u8(0x00, 0x01)?;
+		self.write_u8(0xFF, 0x00)?;
+		self.write_u8(0x80, 0x00)?;
+		self.write_u8(L0X_SYSRANGE_START, 0x02)
+	}
+
+	/// Enable the reference SPADs of the VL53L0X as stored in its NVM
+	fn init_l0x_spads(&mut self) -> Result<(), Error> {
+		self.write_u8(0x80, 0x01)?;
+		self.write_u8(0xFF, 0x01)?;
+		self.write_u8(0x00, 0x00)?;
+		self.write_u8(0xFF, 0x06)?;
+		let value = self.read_u8(0x83)?;
+		self.write_u8(0x83, value | 0x04)?;
+		self.write_u8(0xFF, 0x07)?;
+		self.write_u8(0x81, 0x01)?;
+		self.write_u8(0x80, 0x01)?;
+		self.write_u8(0x94, 0x6B)?;
+		self.write_u8(0x83, 0x00)?;
+		self.wait(0x83, |value| value != 0x00)?;
+		self.write_u8(0x83, 0x01)?;
+		let info = self.read_u8(0x92)?;
+		self.write_u8(0x81, 0x00)?;
+		self.write_u8(0xFF, 0x06)?;
+		let value = self.read_u8(0x83)?;
+		self.write_u8(0x83, value & !0x04)?;
+		self.write_u8(0xFF, 0x01)?;
+		self.write_u8(0x00, 0x01)?;
+		self.write_u8(0xFF, 0x00)?;
+		self.write_u8(0x80, 0x00)?;
+
+		let count = (info & 0x7F) as usize;
+		let aperture = info & 0x80 != 0;
+
+		let mut map = [0; 6];
+		self.read(L0X_GLOBAL_CONFIG_SPAD_ENABLES_REF, &mut map)?;
+		self.write_u8(0xFF, 0x01)?;
+		self.write_u8(0x4F, 0x00)?;
+		self.write_u8(0x4E, 0x2C)?;
+		self.write_u8(0xFF, 0x00)?;
+		self.write_u8(L0X_GLOBAL_CONFIG_REF_EN_START_SELECT, 0xB4)?;
+
+		// Aperture SPADs start at 12, enable as many as given in the NVM
+		let first = if aperture { 12 } else { 0 };
+		let mut enabled = 0;
+		for spad in 0..48 {
+			let bit = 1 << (spad % 8);
+			if spad < first || enabled == count {
+				map[spad / 8] &= !bit;
+			} else if map[spad / 8] & bit != 0 {
+				enabled += 1;
+			}
+		}
+		self.write(L0X_GLOBAL_CONFIG_SPAD_ENABLES_REF, &map)
+	}
+
+	/// Run a single reference calibration of the VL53L0X
+	fn calibrate_l0x(&mut self, vhv: u8) -> Result<(), Error> {
+		self.write_u8(L0X_SYSRANGE_START, 0x01 | vhv)?;
+		self.wait(L0X_RESULT_INTERRUPT_STATUS, |status| status & 0x07 != 0)?;
+		self.write_u8(L0X_SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+		self.write_u8(L0X_SYSRANGE_START, 0x00)
+	}
+
+	/// Initialize the VL53L1X as done by the ultra lite driver of ST
+	fn init_l1x(&mut self) -> Result<(), Error> {
+		self.wait(L1X_FIRMWARE_SYSTEM_STATUS, |status| status & 0x01 != 0)?;
+		self.write(L1X_DEFAULT_CONFIGURATION_START, &L1X_DEFAULT_CONFIGURATION)?;
+
+		// The first measurement calibrates the VHV, it is thrown away
+		self.write_u8(L1X_SYSTEM_MODE_START, 0x40)?;
+		let start = Instant::now();
+		while !self.ready()? {
+			if start.elapsed() > TIMEOUT {
+				return Err(Error::Timeout(format!("{:?} at 0x{:02X} did not answer in time", self.model, self.address)));
+			}
+			thread::sleep(Duration::from_millis(1));
+		}
+		self.write_u8(L1X_SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+		self.write_u8(L1X_SYSTEM_MODE_START, 0x00)?;
+		self.write_u8(L1X_VHV_CONFIG_TIMEOUT_MACROP_LOOP_BOUND, 0x09)?;
+		self.write_u8(L1X_VHV_CONFIG_INIT, 0x00)?;
+
+		// Start the continuous ranging
+		self.write_u8(L1X_SYSTEM_MODE_START, 0x40)
+	}
+
+	/// Check if a new measurement is available
+	fn ready(&mut self) -> Result<bool, Error> {
+		match self.model {
+			Model::VL53L0X => Ok(self.read_u8(L0X_RESULT_INTERRUPT_STATUS)? & 0x07 != 0),
+			Model::VL53L1X => {
+				// The interrupt pin is active low if bit 4 is set
+				let active_high = self.read_u8(L1X_GPIO_HV_MUX_CTRL)? & 0x10 == 0;
+				Ok((self.read_u8(L1X_GPIO_TIO_HV_STATUS)? & 0x01 != 0) == active_high)
+			},
+		}
+	}
+
+	/// Read a new measurement
+	///
+	/// # Returns
+	///
+	/// None if there is no new measurement, Some(None) if nothing was in range, otherwise the distance in mm
+	fn measure(&mut self) -> Result<Option<Option<u16>>, Error> {
+		if !self.ready()? {
+			return Ok(None);
+		}
+		let range = match self.model {
+			Model::VL53L0X => {
+				let range = self.read_u16(L0X_RESULT_RANGE)?;
+				self.write_u8(L0X_SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+				// 8190 and more mean nothing was in range
+				if range < 8190 { Some(range) } else { None }
+			},
+			Model::VL53L1X => {
+				let status = self.read_u8(L1X_RESULT_RANGE_STATUS)? & 0x1F;
+				let range = self.read_u16(L1X_RESULT_RANGE)?;
+				self.write_u8(L1X_SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+				// Only a completed range (status 9) is valid
+				if status == 9 { Some(range) } else { None }
+			},
+		};
+		Ok(Some(range))
+	}
+}
+
+/// Create a new time-of-flight sensor
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the XSHUT pin is attached to
+/// * `bus` - The I2C bus the sensor is attached to
+/// * `model` - Which sensor
+/// * `xshut` - GPIO-Chip and Pin of the XSHUT input, None if it is not connected
+/// * `address` - The address the sensor is moved to, DEFAULT_ADDRESS if it is the only sensor on the bus
+pub fn new(hal: Arc<dyn Backend>, bus: Box<dyn Bus>, model: Model, xshut: Option<(hal::GpioChip, i32)>, address: u16) -> TimeOfFlight {
+	TimeOfFlight {
+		hal,
+		bus: Arc::new(Mutex::new(bus)),
+		model,
+		xshut,
+		address,
+		stop: Arc::new(AtomicBool::new(false)),
+		distance: Arc::new(AtomicU64::new(f64::NAN.to_bits())),
+	}
+}
+
+/// Hold all sensors in reset, so they can be started one after the other and moved to their own address
+///
+/// Has to be called before the sensors sharing a bus are started
+pub fn reset(sensors: &[TimeOfFlight]) -> Result<(), Error> {
+	for sensor in sensors {
+		if let Some((chip, pin)) = sensor.xshut {
+			sensor.hal.gpio_init(chip, pin, hal::GpioHandle::OUTPUT)?;
+			sensor.hal.gpio_set_value(chip, pin, 0)?;
+		}
+	}
+	Ok(())
+}
+
+/// A VL53L0X or VL53L1X laser ranging sensor on an I2C bus
+#[derive(Clone)]
+pub struct TimeOfFlight {
+	hal: Arc<dyn Backend>,
+	bus: Arc<Mutex<Box<dyn Bus>>>,
+	model: Model,
+	xshut: Option<(hal::GpioChip, i32)>,
+	address: u16,
+	stop: Arc<AtomicBool>,
+	distance: Arc<AtomicU64>,
+}
+
+impl TimeOfFlight {
+	fn device(&self, address: u16) -> Device<'_> {
+		Device {
+			bus: self.bus.lock().unwrap_or_else(|err| err.into_inner()),
+			model: self.model,
+			address,
+		}
+	}
+
+	/// Release the sensor from reset, move it to its address, configure it and measure in a thread
+	pub fn start(&mut self) -> Result<(), Error> {
+		if let Some((chip, pin)) = self.xshut {
+			self.hal.gpio_init(chip, pin, hal::GpioHandle::OUTPUT)?;
+			self.hal.gpio_set_value(chip, pin, 1)?;
+			thread::sleep(BOOT_TIME);
+		}
+
+		let mut device = self.device(DEFAULT_ADDRESS);
+		device.identify()?;
+		if self.address != DEFAULT_ADDRESS {
+			device.set_address(self.address)?;
+		}
+		device.init()?;
+		drop(device);
+
+		self.stop.store(false, Ordering::Relaxed);
+		thread::spawn({
+			let sensor = self.clone();
+			move || {
+				while !sensor.stop.load(Ordering::Relaxed) {
+					match sensor.device(sensor.address).measure() {
+						Ok(Some(range)) => {
+							let distance = range.map_or(f64::NAN, |mm| mm as f64 / 10.0);
+							sensor.distance.store(distance.to_bits(), Ordering::Relaxed);
+						},
+						Ok(None) => {},
+						Err(err) => {
+							println!("ERROR: {}", err);
+							sensor.distance.store(f64::NAN.to_bits(), Ordering::Relaxed);
+						},
+					}
+					thread::sleep(POLL_INTERVAL);
+				}
+			}
+		});
+		Ok(())
+	}
+
+	/// Stop measuring and put the sensor into reset, so it is back at the default address
+	pub fn stop(&mut self) -> Result<(), Error> {
+		self.stop.store(true, Ordering::Relaxed);
+		match self.xshut {
+			Some((chip, pin)) => self.hal.gpio_set_value(chip, pin, 0),
+			None => Ok(()),
+		}
+	}
+
+	/// The last measured distance in cm, None if nothing is in range or the last measurement failed
+	pub fn distance(&self) -> Option<f64> {
+		let distance = f64::from_bits(self.distance.load(Ordering::Relaxed));
+		if distance.is_nan() { None } else { Some(distance) }
+	}
+}
+
+impl Sensor for TimeOfFlight {
+	fn start(&mut self) -> Result<(), Error> {
+		TimeOfFlight::start(self)
+	}
+
+	fn stop(&mut self) -> Result<(), Error> {
+		TimeOfFlight::stop(self)
+	}
+
+	fn distance(&self) -> Option<f64> {
+		TimeOfFlight::distance(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::simulated::Simulated;
+
+	use std::collections::VecDeque;
+
+	/// A transaction on the bus: address, written data and the answer if it was read
+	type Transaction = (u16, Vec<u8>, Option<Vec<u8>>);
+
+	/// Bus which expects the given transactions in order and replays their answers
+	struct Replay {
+		transactions: Arc<Mutex<VecDeque<Transaction>>>,
+	}
+
+	impl Bus for Replay {
+		fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+			let expected = self.transactions.lock().unwrap().pop_front();
+			assert_eq!(expected, Some((address, data.to_vec(), None)));
+			Ok(())
+		}
+
+		fn write_read(&mut self, address: u16, data: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+			let (expected_address, expected_data, answer) = self.transactions.lock().unwrap().pop_front().expect("Unexpected transaction");
+			assert_eq!((expected_address, expected_data), (address, data.to_vec()));
+			buffer.copy_from_slice(&answer.expect("Expected a write"));
+			Ok(())
+		}
+	}
+
+	/// Create a sensor on a replayed bus, the returned queue holds the transactions which are still expected
+	fn sensor(model: Model, transactions: Vec<Transaction>) -> (TimeOfFlight, Arc<Mutex<VecDeque<Transaction>>>) {
+		let transactions = Arc::new(Mutex::new(VecDeque::from(transactions)));
+		let bus = Box::new(Replay { transactions: transactions.clone() });
+		(new(Arc::new(Simulated::default()), bus, model, None, 0x30), transactions)
+	}
+
+	#[test]
+	fn moves_the_vl53l0x_and_measures() {
+		let (sensor, transactions) = sensor(Model::VL53L0X, vec!(
+			(0x29, vec!(0xC0), Some(vec!(0xEE))),
+			(0x29, vec!(0x8A, 0x30), None),
+			(0x30, vec!(0x13), Some(vec!(0x00))),
+			(0x30, vec!(0x13), Some(vec!(0x04))),
+			(0x30, vec!(0x1E), Some(vec!(0x01, 0x2C))),
+			(0x30, vec!(0x0B, 0x01), None),
+			(0x30, vec!(0x13), Some(vec!(0x04))),
+			(0x30, vec!(0x1E), Some(vec!(0x1F, 0xFE))),
+			(0x30, vec!(0x0B, 0x01), None),
+		));
+		let mut device = sensor.device(DEFAULT_ADDRESS);
+		device.identify().unwrap();
+		device.set_address(0x30).unwrap();
+		assert_eq!(device.measure().unwrap(), None);
+		assert_eq!(device.measure().unwrap(), Some(Some(300)));
+		assert_eq!(device.measure().unwrap(), Some(None));
+		assert!(transactions.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn measures_with_the_vl53l1x() {
+		let (sensor, transactions) = sensor(Model::VL53L1X, vec!(
+			(0x29, vec!(0x01, 0x0F), Some(vec!(0xEA, 0xCC))),
+			(0x29, vec!(0x00, 0x01, 0x30), None),
+			(0x30, vec!(0x00, 0x30), Some(vec!(0x11))),
+			(0x30, vec!(0x00, 0x31), Some(vec!(0x00))),
+			(0x30, vec!(0x00, 0x89), Some(vec!(0x09))),
+			(0x30, vec!(0x00, 0x96), Some(vec!(0x03, 0xE8))),
+			(0x30, vec!(0x00, 0x86, 0x01), None),
+		));
+		let mut device = sensor.device(DEFAULT_ADDRESS);
+		device.identify().unwrap();
+		device.set_address(0x30).unwrap();
+		assert_eq!(device.measure().unwrap(), Some(Some(1000)));
+		assert!(transactions.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn rejects_an_unknown_sensor() {
+		let (sensor, _) = sensor(Model::VL53L0X, vec!(
+			(0x29, vec!(0xC0), Some(vec!(0xEA))),
+		));
+		assert!(matches!(sensor.device(DEFAULT_ADDRESS).identify(), Err(Error::Init(_))));
+	}
+
+	#[test]
+	fn holds_the_sensors_in_reset() {
+		let hal = Arc::new(Simulated::default());
+		let sensors: Vec<TimeOfFlight> = [17, 18].iter()
+			.map(|pin| new(hal.clone(), Box::new(Replay { transactions: Arc::default() }), Model::VL53L0X, Some((hal::GpioChip::GPIO1, *pin)), 0x30 + *pin as u16))
+			.collect();
+		hal.set_gpio_value(hal::GpioChip::GPIO1, 17, 1);
+		reset(&sensors).unwrap();
+		assert_eq!(hal.gpio_value(hal::GpioChip::GPIO1, 17), 0);
+		assert_eq!(hal.gpio_value(hal::GpioChip::GPIO1, 18), 0);
+	}
+}
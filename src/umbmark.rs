@@ -0,0 +1,303 @@
+use super::config;
+use super::diff_drive::DifferentialDrive as DifferentialDrive;
+use super::error::Error;
+use super::units::{Angle, Duty, Length};
+
+use std::f64::consts::FRAC_PI_2;
+use std::io::BufRead;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Interval between two corrections while driving a square
+const INTERVAL: Duration = Duration::from_millis(10);
+
+/// Speed per rad of heading error to keep the robot on a straight leg
+const HEADING_GAIN: f64 = 0.5;
+
+/// A turn is done once the heading is within this angle
+const TURN_TOLERANCE: Angle = Angle::rad(0.005);
+
+/// Slowest speed while turning, so the wheels do not stop within the deadband of the motors
+const MIN_TURN_SPEED: f64 = 0.05;
+
+/// Effective wheel radii and wheel distance
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Calibration {
+	pub left_radius: Length,
+	pub right_radius: Length,
+	pub wheel_distance: Length,
+}
+
+impl Calibration {
+	/// Correct the radii and the wheel distance with the errors of squares driven clockwise and counterclockwise
+	///
+	/// Uses the correction of Borenstein's UMBmark: the wheel distance corrects the error of the turns,
+	/// the ratio of the radii the curving of the legs. The average radius is not changed.
+	///
+	/// # Arguments
+	///
+	/// * `nominal` - The radii and the wheel distance the squares were driven with
+	/// * `side` - Length of a side of the squares
+	/// * `clockwise` - Measured minus odometry end position (X, Y) of each clockwise square
+	/// * `counterclockwise` - Measured minus odometry end position (X, Y) of each counterclockwise square
+	pub fn fit(nominal: Calibration, side: Length, clockwise: &[(Length, Length)], counterclockwise: &[(Length, Length)]) -> Result<Self, Error> {
+		if clockwise.is_empty() || counterclockwise.is_empty() {
+			return Err(Error::Calibration(String::from("Squares in both directions are needed")));
+		}
+		let center = |errors: &[(Length, Length)]| errors.iter().map(|error| error.0.as_mm()).sum::<f64>() / errors.len() as f64;
+		let (side, wheel_distance) = (side.as_mm(), nominal.wheel_distance.as_mm());
+		let (x_cw, x_ccw) = (center(clockwise), center(counterclockwise));
+
+		// Error of each turn (type A) and the curving of each leg (type B) in rad
+		let alpha = (x_cw + x_ccw) / (-4.0 * side);
+		let beta = (x_cw - x_ccw) / (-4.0 * side);
+
+		// Ratio of the right to the left radius which drives the legs straight
+		let ratio = match beta.abs() <= f64::EPSILON {
+			true => 1.0,
+			false => {
+				let radius = (side / 2.0) / (beta / 2.0).sin();
+				(radius + wheel_distance / 2.0) / (radius - wheel_distance / 2.0)
+			},
+		};
+		if ratio <= 0.0 || alpha >= FRAC_PI_2 {
+			return Err(Error::Calibration(String::from("The errors are too large for a correction")));
+		}
+
+		Ok(Self {
+			left_radius: nominal.left_radius * 2.0 / (ratio + 1.0),
+			right_radius: nominal.right_radius * 2.0 / (1.0 / ratio + 1.0),
+			wheel_distance: nominal.wheel_distance * FRAC_PI_2 / (FRAC_PI_2 - alpha),
+		})
+	}
+
+	/// Write the calibration into the configuration of the robot, the other values in it are kept
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the configuration, it is created if it does not exist
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let mut config = config::load(&path).unwrap_or_default();
+		config.set("wheel.left.radius", self.left_radius.as_mm());
+		config.set("wheel.right.radius", self.right_radius.as_mm());
+		config.set("wheel.distance", self.wheel_distance.as_mm());
+		config.save(path)
+	}
+}
+
+/// Drive squares in both directions, ask for the measured end positions and fit the calibration
+///
+/// Before each square the robot has to be placed at the start facing along X. After it, the end position of
+/// the robot relative to the start has to be measured and entered as `X Y` in mm, Y is on the left of the start.
+///
+/// # Arguments
+///
+/// * `robot` - The robot to drive, it must not be running
+/// * `nominal` - The radii and the wheel distance the robot was created with
+/// * `side` - Length of a side of the squares
+/// * `runs` - Number of squares in each direction, UMBmark uses 5
+/// * `input` - Where the measured end positions are read from, e.g. stdin
+pub fn calibrate(robot: &mut DifferentialDrive, nominal: Calibration, side: Length, runs: usize, input: &mut dyn BufRead) -> Result<Calibration, Error> {
+	let mut errors = (vec!(), vec!());
+	for clockwise in [true, false] {
+		for run in 1..=runs {
+			println!("Square {} of {} {}: place the robot at the start and press Enter", run, runs, if clockwise { "clockwise" } else { "counterclockwise" });
+			read_line(input)?;
+
+			let end = square(robot, side, clockwise, Duty::new(0.2))?;
+
+			println!("Enter the measured end position relative to the start as 'X Y' in mm:");
+			let line = read_line(input)?;
+			let measured: Vec<f64> = line.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+			let error = match measured.as_slice() {
+				[x, y] => (Length::mm(*x) - end.0, Length::mm(*y) - end.1),
+				_ => return Err(Error::Calibration(format!("Invalid position: {}", line.trim()))),
+			};
+			match clockwise {
+				true => errors.0.push(error),
+				false => errors.1.push(error),
+			}
+		}
+	}
+	Calibration::fit(nominal, side, &errors.0, &errors.1)
+}
+
+/// Read a line of the operator
+fn read_line(input: &mut dyn BufRead) -> Result<String, Error> {
+	let mut line = String::new();
+	match input.read_line(&mut line) {
+		Ok(0) => Err(Error::Io(String::from("No more input"))),
+		Ok(_) => Ok(line),
+		Err(err) => Err(Error::Io(format!("Unable to read the input: {}", err))),
+	}
+}
+
+/// Drive a square by odometry, starting at (0, 0) facing along X, and turn in place at each corner
+///
+/// The odometry runs on the encoders alone, the gyro of an IMU would hide the errors of the wheels.
+///
+/// # Arguments
+///
+/// * `robot` - The robot to drive, it must not be running
+/// * `side` - Length of a side
+/// * `clockwise` - Turn right at the corners, otherwise left
+/// * `speed` - Speed of the wheels on the legs
+///
+/// # Returns
+///
+/// The end position (X, Y) as calculated by the odometry
+pub fn square(robot: &mut DifferentialDrive, side: Length, clockwise: bool, speed: Duty) -> Result<(Length, Length), Error> {
+	robot.set_pose(Length::ZERO, Length::ZERO, Angle::ZERO);
+	let imu = robot.remove_imu();
+	let turn = Angle::rad(if clockwise { -FRAC_PI_2 } else { FRAC_PI_2 });
+
+	let mut drive = || {
+		for corner in 0..4 {
+			let heading = turn * corner as f64;
+
+			// Drive the leg, steering towards the heading of the leg
+			let (x, y, _) = robot.pose();
+			loop {
+				robot.update_odometry()?;
+				let pose = robot.pose();
+				if (pose.0 - x).hypot(pose.1 - y) >= side {
+					break;
+				}
+				let correction = HEADING_GAIN * (heading - pose.2).normalized().as_rad();
+				robot.set_speed(Duty::new(speed.get() - correction), Duty::new(speed.get() + correction))?;
+				sleep(INTERVAL);
+			}
+
+			// Turn in place, slowing down towards the heading of the next leg
+			loop {
+				robot.update_odometry()?;
+				let error = (heading + turn - robot.pose().2).normalized();
+				if error.abs() <= TURN_TOLERANCE {
+					break;
+				}
+				let turn_speed = Duty::new((HEADING_GAIN * error.abs().as_rad()).clamp(MIN_TURN_SPEED, speed.get()).copysign(error.as_rad()));
+				robot.set_speed(-turn_speed, turn_speed)?;
+				sleep(INTERVAL);
+			}
+			robot.pause()?;
+		}
+		Ok(())
+	};
+	let result = drive();
+	let halted = robot.halt();
+	if let Some((imu, weight)) = imu {
+		robot.add_imu(imu, weight);
+	}
+	result.and(halted)?;
+
+	let (x, y, _) = robot.pose();
+	Ok((x, y))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::{diff_drive, hal, imu, simulator};
+	use super::super::wheel::Wheel;
+
+	use std::f64::consts::PI;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	/// Move a pose (X, Y, heading) in mm and rad along an arc
+	fn arc(pose: &mut (f64, f64, f64), distance: f64, angle: f64) {
+		match angle.abs() <= f64::EPSILON {
+			true => {
+				pose.0 += distance * pose.2.cos();
+				pose.1 += distance * pose.2.sin();
+			},
+			false => {
+				let radius = distance / angle;
+				pose.0 += radius * ((pose.2 + angle).sin() - pose.2.sin());
+				pose.1 -= radius * ((pose.2 + angle).cos() - pose.2.cos());
+			},
+		}
+		pose.2 += angle;
+	}
+
+	/// The true end position of a robot with the calibration `actual` which drives a perfect square by the odometry of `nominal`
+	fn square(actual: Calibration, nominal: Calibration, side: f64, clockwise: bool) -> (Length, Length) {
+		let mut pose = (0.0, 0.0, 0.0);
+		let (left, right) = (actual.left_radius.as_mm(), actual.right_radius.as_mm());
+		let wheel_distance = actual.wheel_distance.as_mm();
+		let mut drive = |left_angle: f64, right_angle: f64| {
+			arc(&mut pose, (left * left_angle + right * right_angle) / 2.0, (right * right_angle - left * left_angle) / wheel_distance);
+		};
+		for _ in 0..4 {
+			drive(side / nominal.left_radius.as_mm(), side / nominal.right_radius.as_mm());
+			// Turning in place by 90° according to the odometry
+			let turn = match clockwise {
+				true => -FRAC_PI_2,
+				false => FRAC_PI_2,
+			} * nominal.wheel_distance.as_mm() / 2.0;
+			drive(-turn / nominal.left_radius.as_mm(), turn / nominal.right_radius.as_mm());
+		}
+		(Length::mm(pose.0), Length::mm(pose.1))
+	}
+
+	#[test]
+	fn recovers_the_calibration() {
+		let nominal = Calibration { left_radius: Length::mm(30.0), right_radius: Length::mm(30.0), wheel_distance: Length::mm(150.0) };
+		// The right wheel is 0.2% larger than the left one with the nominal average, the wheels are 0.5mm further apart.
+		// The correction is linearized, so it is only that exact for small errors
+		let actual = Calibration { left_radius: Length::mm(30.0 * 2.0 / 2.002), right_radius: Length::mm(30.0 * 2.004 / 2.002), wheel_distance: Length::mm(150.5) };
+		let side = 1000.0;
+		let clockwise = vec!(square(actual, nominal, side, true); 5);
+		let counterclockwise = vec!(square(actual, nominal, side, false); 5);
+
+		let fitted = Calibration::fit(nominal, Length::mm(side), &clockwise, &counterclockwise).unwrap();
+		assert!((fitted.left_radius - actual.left_radius).abs() < Length::mm(0.01), "left radius {}", fitted.left_radius);
+		assert!((fitted.right_radius - actual.right_radius).abs() < Length::mm(0.01), "right radius {}", fitted.right_radius);
+		assert!((fitted.wheel_distance - actual.wheel_distance).abs() < Length::mm(0.1), "wheel distance {}", fitted.wheel_distance);
+	}
+
+	#[test]
+	fn drives_the_square_on_the_encoders() {
+		// The wheels are further apart than the robot assumes, so it turns less than its encoders tell
+		let mut sim = simulator::new(Length::mm(165.0));
+		let wheels = [
+			Wheel::left(Length::mm(40.0), hal::Encoder::ENCODER3, hal::Motor::MOTOR3, 3441.0 / 104.0, 32.0),
+			Wheel::right(Length::mm(40.0), hal::Encoder::ENCODER2, hal::Motor::MOTOR2, 3441.0 / 104.0, 32.0),
+		];
+		let hal = sim.backend();
+		hal.init_encoders().and_then(|_| hal.init_motors()).unwrap();
+		let mut robot = diff_drive::new(hal.clone(), Length::mm(150.0), Length::mm(163.0));
+		for wheel in wheels {
+			sim.add_wheel(&wheel, false, simulator::MotorModel::default());
+			robot.add_wheel(wheel, false);
+		}
+		let mut gyro = imu::new(hal.clone());
+		gyro.start().unwrap();
+		robot.add_imu(gyro, 0.98);
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let sim = sim.spawn(stop.clone());
+		let result = super::square(&mut robot, Length::mm(100.0), false, Duty::new(0.2));
+		stop.store(true, Ordering::Relaxed);
+		let sim = sim.join().unwrap();
+		result.unwrap();
+
+		// With the gyro the robot would have turned by 360°, the encoders lead it to miss by the error of the turns
+		let missed = Angle::rad(sim.pose().2 - 2.0 * PI).normalized();
+		let expected = Angle::rad(2.0 * PI * (150.0 / 165.0 - 1.0));
+		assert!((missed - expected).abs() < Angle::rad(0.05), "missed the start heading by {} instead of {}", missed, expected);
+		assert!(robot.remove_imu().is_some(), "The IMU is added again");
+	}
+
+	#[test]
+	fn keeps_an_exact_calibration() {
+		let nominal = Calibration { left_radius: Length::mm(30.0), right_radius: Length::mm(30.0), wheel_distance: Length::mm(150.0) };
+		let clockwise = [square(nominal, nominal, 1000.0, true)];
+		let counterclockwise = [square(nominal, nominal, 1000.0, false)];
+		let fitted = Calibration::fit(nominal, Length::mm(1000.0), &clockwise, &counterclockwise).unwrap();
+		assert!((fitted.wheel_distance - nominal.wheel_distance).abs() < Length::mm(1e-6));
+		assert!((fitted.left_radius - nominal.left_radius).abs() < Length::mm(1e-6));
+		assert!(Calibration::fit(nominal, Length::mm(1000.0), &clockwise, &[]).is_err());
+	}
+}
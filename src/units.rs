@@ -0,0 +1,240 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::time::Duration;
+
+/// Implement the arithmetic of a quantity which is stored as a single f64
+///
+/// Quantities of the same kind can be added, subtracted and divided into a ratio, scaling is only possible by plain numbers.
+macro_rules! quantity {
+	($name:ident, $unit:literal) => {
+		impl Add for $name {
+			type Output = Self;
+			fn add(self, other: Self) -> Self { Self(self.0 + other.0) }
+		}
+		impl AddAssign for $name {
+			fn add_assign(&mut self, other: Self) { self.0 += other.0; }
+		}
+		impl Sub for $name {
+			type Output = Self;
+			fn sub(self, other: Self) -> Self { Self(self.0 - other.0) }
+		}
+		impl SubAssign for $name {
+			fn sub_assign(&mut self, other: Self) { self.0 -= other.0; }
+		}
+		impl Neg for $name {
+			type Output = Self;
+			fn neg(self) -> Self { Self(-self.0) }
+		}
+		impl Mul<f64> for $name {
+			type Output = Self;
+			fn mul(self, factor: f64) -> Self { Self(self.0 * factor) }
+		}
+		impl Div<f64> for $name {
+			type Output = Self;
+			fn div(self, divisor: f64) -> Self { Self(self.0 / divisor) }
+		}
+		impl Div for $name {
+			type Output = f64;
+			fn div(self, other: Self) -> f64 { self.0 / other.0 }
+		}
+		impl Sum for $name {
+			fn sum<I: Iterator<Item = Self>>(iter: I) -> Self { Self(iter.map(|value| value.0).sum()) }
+		}
+		impl fmt::Display for $name {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				write!(f, "{}{}", self.0, $unit)
+			}
+		}
+		impl $name {
+			/// The absolute value
+			pub fn abs(self) -> Self { Self(self.0.abs()) }
+		}
+	};
+}
+
+/// A length, e.g. a distance or a radius
+#[derive(Default, Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Length(f64);
+quantity!(Length, "mm");
+
+impl Length {
+	pub const ZERO: Length = Length(0.0);
+
+	/// Create a length from mm
+	pub const fn mm(mm: f64) -> Self {
+		Self(mm)
+	}
+
+	/// The length in mm
+	pub fn as_mm(self) -> f64 {
+		self.0
+	}
+
+	/// Length of the line from the origin to (self, other)
+	pub fn hypot(self, other: Length) -> Length {
+		Self(self.0.hypot(other.0))
+	}
+}
+
+impl Div<Duration> for Length {
+	type Output = Velocity;
+	fn div(self, duration: Duration) -> Velocity { Velocity(self.0 / duration.as_secs_f64()) }
+}
+
+/// An angle, e.g. an orientation or how far a wheel turned
+#[derive(Default, Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Angle(f64);
+quantity!(Angle, "rad");
+
+impl Angle {
+	pub const ZERO: Angle = Angle(0.0);
+
+	/// Create an angle from rad
+	pub const fn rad(rad: f64) -> Self {
+		Self(rad)
+	}
+
+	/// Create an angle from degree
+	pub fn deg(deg: f64) -> Self {
+		Self(deg.to_radians())
+	}
+
+	/// Create an angle from a number of full turns
+	pub fn revolutions(revolutions: f64) -> Self {
+		Self(revolutions * 2.0 * PI)
+	}
+
+	/// The angle in rad
+	pub fn as_rad(self) -> f64 {
+		self.0
+	}
+
+	/// The angle in degree
+	pub fn as_deg(self) -> f64 {
+		self.0.to_degrees()
+	}
+
+	/// The same orientation from -PI up to PI
+	pub fn normalized(self) -> Self {
+		Self((self.0 + PI).rem_euclid(2.0 * PI) - PI)
+	}
+
+	/// Length of the arc with this angle on a circle with the given radius, e.g. the distance a wheel rolled
+	pub fn arc(self, radius: Length) -> Length {
+		Length(self.0 * radius.0)
+	}
+
+	/// The angle of an arc with the given length on a circle with the given radius
+	pub fn from_arc(arc: Length, radius: Length) -> Self {
+		Self(arc.0 / radius.0)
+	}
+
+	/// Sine of the angle
+	pub fn sin(self) -> f64 {
+		self.0.sin()
+	}
+
+	/// Cosine of the angle
+	pub fn cos(self) -> f64 {
+		self.0.cos()
+	}
+
+	/// The angle of the line from the origin to (x, y)
+	pub fn atan2(y: Length, x: Length) -> Self {
+		Self(y.0.atan2(x.0))
+	}
+}
+
+impl Div<Duration> for Angle {
+	type Output = AngularVelocity;
+	fn div(self, duration: Duration) -> AngularVelocity { AngularVelocity(self.0 / duration.as_secs_f64()) }
+}
+
+/// A velocity, e.g. of a wheel on the ground
+#[derive(Default, Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Velocity(f64);
+quantity!(Velocity, "mm/s");
+
+impl Velocity {
+	pub const ZERO: Velocity = Velocity(0.0);
+
+	/// Create a velocity from mm/s
+	pub const fn mm_per_s(mm_per_s: f64) -> Self {
+		Self(mm_per_s)
+	}
+
+	/// The velocity in mm/s
+	pub fn as_mm_per_s(self) -> f64 {
+		self.0
+	}
+}
+
+impl Mul<Duration> for Velocity {
+	type Output = Length;
+	fn mul(self, duration: Duration) -> Length { Length(self.0 * duration.as_secs_f64()) }
+}
+
+/// An angular velocity, e.g. the yaw rate of the robot
+#[derive(Default, Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct AngularVelocity(f64);
+quantity!(AngularVelocity, "rad/s");
+
+impl AngularVelocity {
+	pub const ZERO: AngularVelocity = AngularVelocity(0.0);
+
+	/// Create an angular velocity from rad/s
+	pub const fn rad_per_s(rad_per_s: f64) -> Self {
+		Self(rad_per_s)
+	}
+
+	/// The angular velocity in rad/s
+	pub fn as_rad_per_s(self) -> f64 {
+		self.0
+	}
+}
+
+impl Mul<Duration> for AngularVelocity {
+	type Output = Angle;
+	fn mul(self, duration: Duration) -> Angle { Angle(self.0 * duration.as_secs_f64()) }
+}
+
+/// Duty of a motor from -1.0 (full backward) up to 1.0 (full forward)
+#[derive(Default, Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Duty(f64);
+
+impl Duty {
+	pub const ZERO: Duty = Duty(0.0);
+
+	/// Create a duty, it is limited to -1.0 up to 1.0
+	pub fn new(duty: f64) -> Self {
+		Self(duty.clamp(-1.0, 1.0))
+	}
+
+	/// The duty from -1.0 up to 1.0
+	pub fn get(self) -> f64 {
+		self.0
+	}
+
+	/// The duty without its direction
+	pub fn abs(self) -> Self {
+		Self(self.0.abs())
+	}
+}
+
+impl Neg for Duty {
+	type Output = Self;
+	fn neg(self) -> Self { Self(-self.0) }
+}
+
+impl Mul<f64> for Duty {
+	type Output = Self;
+	fn mul(self, factor: f64) -> Self { Self::new(self.0 * factor) }
+}
+
+impl fmt::Display for Duty {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
@@ -0,0 +1,152 @@
+use super::hal;
+use super::hal::Backend;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Create a watchdog which brakes all motors if it is not fed in time
+///
+/// # Arguments
+///
+/// * `hal` - The hardware backend the motors are attached to
+/// * `deadline` - Longest time between two feeds
+pub fn new(hal: Arc<dyn Backend>, deadline: Duration) -> Watchdog {
+	let now = hal.now();
+	Watchdog {
+		hal,
+		deadline,
+		last_feed: Arc::new(Mutex::new(now)),
+		tripped: Arc::new(AtomicBool::new(false)),
+		stop: Arc::new(AtomicBool::new(true)),
+	}
+}
+
+/// Brakes every motor from its own thread when the control loop stalls
+///
+/// Once it tripped, it stays tripped until it is reset explicitly.
+pub struct Watchdog {
+	hal: Arc<dyn Backend>,
+	deadline: Duration,
+	last_feed: Arc<Mutex<Instant>>,
+	tripped: Arc<AtomicBool>,
+	stop: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+	/// Start watching, it has to be fed from now on
+	pub fn start(&mut self) {
+		self.feed();
+		self.stop.store(true, Ordering::Relaxed);
+		self.stop = Arc::new(AtomicBool::new(false));
+
+		thread::spawn({
+			let hal = self.hal.clone();
+			let deadline = self.deadline;
+			let last_feed = self.last_feed.clone();
+			let tripped = self.tripped.clone();
+			let stop = self.stop.clone();
+			let interval = (deadline / 10).max(Duration::from_millis(1));
+			move || {
+				while !stop.load(Ordering::Relaxed) {
+					let elapsed = hal.now().duration_since(*last_feed.lock().unwrap_or_else(|err| err.into_inner()));
+					if elapsed > deadline && !tripped.swap(true, Ordering::Relaxed) {
+						println!("ERROR: Control loop stalled for {:?}, braking all motors", elapsed);
+						for motor in [hal::Motor::MOTOR1, hal::Motor::MOTOR2, hal::Motor::MOTOR3, hal::Motor::MOTOR4] {
+							let _ = hal.brake_motor(motor);
+						}
+					}
+					thread::sleep(interval);
+				}
+			}
+		});
+	}
+
+	/// Stop watching
+	pub fn stop(&mut self) {
+		self.stop.store(true, Ordering::Relaxed);
+	}
+
+	/// Tell the watchdog the control loop is still alive
+	pub fn feed(&self) {
+		*self.last_feed.lock().unwrap_or_else(|err| err.into_inner()) = self.hal.now();
+	}
+
+	/// Check if the deadline was missed
+	pub fn tripped(&self) -> bool {
+		self.tripped.load(Ordering::Relaxed)
+	}
+
+	/// Clear a missed deadline
+	pub fn reset(&mut self) {
+		self.feed();
+		self.tripped.store(false, Ordering::Relaxed);
+	}
+
+	/// Longest time between two feeds
+	pub fn deadline(&self) -> Duration {
+		self.deadline
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::hal::simulated::{MotorState, Simulated};
+
+	const MOTORS: [hal::Motor; 4] = [hal::Motor::MOTOR1, hal::Motor::MOTOR2, hal::Motor::MOTOR3, hal::Motor::MOTOR4];
+
+	/// Time the thread of the watchdog gets to check, in real time
+	const SETTLE: Duration = Duration::from_millis(50);
+
+	/// Let the motors run and give the watchdog time to check the time of the backend
+	fn run(hal: &Simulated, elapsed: Duration) {
+		for motor in MOTORS {
+			hal.run_motor(motor, 0.5).unwrap();
+		}
+		hal.advance(elapsed);
+		thread::sleep(SETTLE);
+	}
+
+	/// Check if all motors are braked or all are still running
+	fn braked(hal: &Simulated) -> bool {
+		let braked = MOTORS.iter().filter(|motor| hal.motor_state(**motor) == MotorState::Braked).count();
+		assert!(braked == 0 || braked == MOTORS.len(), "{} motors braked", braked);
+		braked == MOTORS.len()
+	}
+
+	#[test]
+	fn brakes_without_feeding_and_rearms_after_a_reset() {
+		let hal = Arc::new(Simulated::default());
+		hal.init_motors().unwrap();
+		// The time of the backend only moves on by hand from here on
+		hal.advance(Duration::ZERO);
+		let mut watchdog = new(hal.clone(), Duration::from_millis(100));
+		watchdog.start();
+
+		for _ in 0..5 {
+			run(&hal, Duration::from_millis(80));
+			watchdog.feed();
+		}
+		assert!(!watchdog.tripped());
+		assert!(!braked(&hal));
+
+		run(&hal, Duration::from_millis(150));
+		assert!(watchdog.tripped());
+		assert!(braked(&hal));
+
+		// Fed again after the reset it keeps the motors running, until the feeding stops again
+		watchdog.reset();
+		for _ in 0..5 {
+			run(&hal, Duration::from_millis(80));
+			watchdog.feed();
+		}
+		assert!(!watchdog.tripped());
+		assert!(!braked(&hal));
+		run(&hal, Duration::from_millis(150));
+		assert!(watchdog.tripped());
+		assert!(braked(&hal));
+		watchdog.stop();
+	}
+}
@@ -1,6 +1,8 @@
 use super::hal;
+use super::units::Length;
 
 // To differentiate between LEFT and RIGHT
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Default)]
 pub(crate) enum Orientation {
 	#[default]
@@ -13,11 +15,11 @@ pub(crate) enum Orientation {
 #[derive(Default)]
 pub struct Wheel {
 	pub(crate) orientation: Orientation,
-	pub(crate) radius: f32,
+	pub(crate) radius: Length,
 	pub(crate) encoder: hal::Encoder,
 	pub(crate) motor: hal::Motor,
-	pub(crate) gear_ratio: f32,
-	pub(crate) encoder_resolution: f32,
+	pub(crate) gear_ratio: f64,
+	pub(crate) encoder_resolution: f64,
 }
 impl Wheel {
 	/// Creates a LEFT-Sided Wheel and returns it
@@ -28,8 +30,8 @@ impl Wheel {
 	/// * `encoder` - Which Encoder-PIN
 	/// * `motor` - Which Motor-PIN
 	/// * `gear_ratio` - Ratio of the attached gearbox
-	/// * `encoder_resolution` - Resolution of the encoder
-	pub fn left(radius: f32, encoder: hal::Encoder, motor: hal::Motor, gear_ratio: f32, encoder_resolution: f32) -> Self {
+	/// * `encoder_resolution` - Counts of the encoder per revolution of the motor
+	pub fn left(radius: Length, encoder: hal::Encoder, motor: hal::Motor, gear_ratio: f64, encoder_resolution: f64) -> Self {
 		Self {
 			orientation: Orientation::LEFT,
 			radius,
@@ -48,8 +50,8 @@ impl Wheel {
 	/// * `encoder` - Which Encoder-PIN
 	/// * `motor` - Which Motor-PIN
 	/// * `gear_ratio` - Ratio of the attached gearbox
-	/// * `encoder_resolution` - Resolution of the encoder
-	pub fn right(radius: f32, encoder: hal::Encoder, motor: hal::Motor, gear_ratio: f32, encoder_resolution: f32) -> Self {
+	/// * `encoder_resolution` - Counts of the encoder per revolution of the motor
+	pub fn right(radius: Length, encoder: hal::Encoder, motor: hal::Motor, gear_ratio: f64, encoder_resolution: f64) -> Self {
 		Self {
 			orientation: Orientation::RIGHT,
 			radius,